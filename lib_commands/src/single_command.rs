@@ -1,4 +1,4 @@
-use std::{process::{Command, Stdio}, collections::HashMap, env};
+use std::{process::{Command, Stdio}, collections::{HashMap, VecDeque}, env, io::{BufRead, BufReader}, thread};
 use thiserror::Error;
 
 /// Represents a shell command to execute
@@ -33,6 +33,15 @@ pub enum SingleCommandError{
     /// That is to say, when the program runs but in the middle of execution fails
     #[error("Command failed during execution, with error {0}")]
     RuntimeFailure(String),
+
+    /// Error that is returned by `run_keeping_stderr` when the program fails in runtime
+    /// Keeps the exit code, that is None if the program was killed by a signal, and the last
+    /// lines that the program wrote to stderr
+    #[error("Command failed during execution, with exit code {}", .code.map_or("unknown".to_string(), |code| code.to_string()))]
+    FailureWithStderr{
+        code: Option<i32>,
+        stderr_tail: Vec<String>,
+    },
 }
 
 impl SingleCommand{
@@ -84,6 +93,62 @@ impl SingleCommand{
         return Ok(());
     }
 
+    /// Runs the command as `run` does, but keeping the last `tail_lines` lines that it writes to
+    /// stderr, that are still shown. If the command fails, they are returned in the error
+    pub fn run_keeping_stderr(&self, tail_lines: usize) -> Result<(), SingleCommandError>{
+
+        let mut builder = self.get_builder_command();
+        builder.stderr(Stdio::piped());
+
+        let mut handler = match builder.spawn(){
+            Err(err) => return Err(SingleCommandError::ProgramDoesNotExist(format!("{:?}", err))),
+            Ok(child) => child,
+        };
+
+        // Stderr is read while the command runs, so it does not block on a full pipe
+        let stderr = handler.stderr.take();
+        let reader = thread::spawn(move || {
+            let mut tail = VecDeque::new();
+            for line in stderr.into_iter().flat_map(|stderr| BufReader::new(stderr).lines()).flatten() {
+                eprintln!("{}", line);
+                tail.push_back(line);
+                if tail.len() > tail_lines {
+                    tail.pop_front();
+                }
+            }
+            return tail;
+        });
+
+        let exit_code = handler.wait();
+        let stderr_tail: Vec<String> = reader.join().unwrap_or_default().into_iter().collect();
+
+        return match exit_code {
+            Err(err) => Err(SingleCommandError::RuntimeFailure(format!("{:?}", err))),
+            Ok(exit_code) if exit_code.success() => Ok(()),
+            Ok(exit_code) => Err(SingleCommandError::FailureWithStderr{code: exit_code.code(), stderr_tail}),
+        };
+    }
+
+    /// Runs the command capturing its output, instead of showing it
+    /// Returns what the command wrote to stdout
+    pub fn output(&self) -> Result<String, SingleCommandError>{
+
+        // Get the builder of the command, without asking the user for input
+        let mut builder = self.get_builder_command();
+        builder.stdin(Stdio::null());
+
+        let output = match builder.output(){
+            Err(err) => return Err(SingleCommandError::ProgramDoesNotExist(format!("{:?}", err))),
+            Ok(output) => output,
+        };
+
+        return match output.status.code(){
+            None => Err(SingleCommandError::RuntimeFailure("Process terminated by signal".to_string())),
+            Some(0) => Ok(String::from_utf8_lossy(&output.stdout).to_string()),
+            Some(code) => Err(SingleCommandError::RuntimeFailure(format!("Exit code was not zero, was {}", code))),
+        };
+    }
+
     /// Creates the `Command` struct, that we can use for spawning, getting the output, ...
     fn get_builder_command(&self) -> Command {
 
@@ -143,7 +208,7 @@ mod single_command_test{
         match result {
             Ok(value) => return Err(format!("Expected error, obtained {:?}", value)),
             Err(SingleCommandError::SudoAtTheStart) => return Err("This command should faild because the program doesn't exist, not because it has sudo at the start".to_string()),
-            Err(SingleCommandError::RuntimeFailure(_)) => return Err("This command should faild because the program doesn't exist, not because it has some runtime failure".to_string()),
+            Err(SingleCommandError::RuntimeFailure(_)) | Err(SingleCommandError::FailureWithStderr{..}) => return Err("This command should faild because the program doesn't exist, not because it has some runtime failure".to_string()),
             Err(SingleCommandError::ProgramDoesNotExist(_)) => return Ok(()),
         }
     }
@@ -164,6 +229,36 @@ mod single_command_test{
         }
     }
 
+    #[test]
+    pub fn test_output_is_captured() -> Result<(), String>{
+        let command = SingleCommand::new(
+            "echo captured output".to_string(), false, false
+        ).expect("This command doesn't have sudo at the start");
+
+        match command.output(){
+            Ok(output) if output == "captured output\n" => return Ok(()),
+            other => return Err(format!("Expected the output of echo, obtained {:?}", other)),
+        }
+    }
+
+    #[test]
+    pub fn test_stderr_of_failed_commands_is_kept() -> Result<(), String>{
+        // ls writes why it fails to stderr
+        let command = SingleCommand::new("ls thisfiledoesnotexist".to_string(), false, false)
+            .map_err(|err| format!("Could not build the command: {}", err))?;
+
+        match command.run_keeping_stderr(5) {
+            Err(SingleCommandError::FailureWithStderr{code, stderr_tail}) => {
+                assert_ne!(code, Some(0));
+                assert_eq!(stderr_tail.len(), 1);
+                assert!(stderr_tail[0].contains("thisfiledoesnotexist"));
+            },
+            other => return Err(format!("Expected a failure with its stderr, got {:?}", other)),
+        }
+
+        return Ok(());
+    }
+
     #[test]
     pub fn test_failing_command_in_runtime() -> Result<(), String>{
         // Build and run a failing command
@@ -179,6 +274,7 @@ mod single_command_test{
             Ok(_) => return Err(format!("Installation of thispackagedoesnotexist run succesfully")),
             Err(SingleCommandError::SudoAtTheStart) => return Err("This command should fail in runtime, not because it has sudo".to_string()),
             Err(SingleCommandError::ProgramDoesNotExist(_)) => return Err("This command should fail in runtime, not because the program does not exist".to_string()),
+            Err(SingleCommandError::FailureWithStderr{..}) => return Err("This command should fail in runtime without keeping its stderr".to_string()),
             Err(SingleCommandError::RuntimeFailure(_)) => return Ok(()),
         }
     }
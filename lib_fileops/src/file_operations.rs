@@ -1,120 +1,414 @@
-use std::{fs, path::Path};
+use std::{fs, path::{Component, Path, PathBuf}, env, ffi::OsString, process, collections::HashSet};
 use anyhow::Context;
-use thiserror::Error;
-use folder_compare::FolderCompare;
+use glob::{MatchOptions, Pattern};
 
 /// Module to implement basic file operations such as copy files, copy dirs,
 /// create dirs, ...
 
-use lib_commands::SingleCommand;
+use crate::index::IndexStore;
+use crate::journal::Journal;
 
-/// Gets a path and adds a last "/" if it is not present
-/// This is needed for the rsync command
+/// Env var that can be used to override where punto stores its state
+const STATE_DIR_ENV_VAR: &str = "PUNTO_STATE_DIR";
+
+/// Returns the dir where punto stores its state (journals, indexes, ...)
 ///
-/// For example:
-/// some/path -> some/path/
-/// other/path/ -> do nothing
-fn add_last_slash_to_path(path: &str) -> String{
-    let last_char = path.chars().last().unwrap();
+/// In order of preference, this is:
+///     1. `$PUNTO_STATE_DIR`
+///     2. `$XDG_STATE_HOME/punto`
+///     3. `$HOME/.local/state/punto`
+pub fn punto_state_dir() -> anyhow::Result<PathBuf> {
+    if let Some(dir) = env::var_os(STATE_DIR_ENV_VAR) {
+        return Ok(PathBuf::from(dir));
+    }
 
-    if last_char == '/' {
-        return path.to_string();
+    if let Some(dir) = env::var_os("XDG_STATE_HOME") {
+        return Ok(PathBuf::from(dir).join("punto"));
     }
 
+    let home = env::var_os("HOME").context("Neither $XDG_STATE_HOME nor $HOME are set")?;
+    return Ok(PathBuf::from(home).join(".local/state/punto"));
+}
+
+/// Checks if `rel_path` (relative to the root of a dir sync) matches one of
+/// the `ignore_paths` patterns
+///
+/// Patterns follow the rsync `--exclude` rules:
+///     - Patterns without a "/" nor "**" are matched against the name of the
+///       file or dir at any depth. For example, "*.swp" or "__pycache__"
+///     - Patterns starting with "/" are anchored to the root of the sync, and
+///       matched against the whole relative path. For example, "/src/first.rs"
+///     - Other patterns with a "/" or "**" are matched against the end of the
+///       relative path. For example, "src/first.rs" also matches "other/src/first.rs"
+///     - Patterns ending in "/" only match dirs
+///     - "*" does not match a "/", while "**" matches any number of dirs, so it
+///       has to be a whole component, as in "**/cache" or "build/**"
+///     - Glob wildcards ("?", "[...]") are supported
+///
+/// Ignoring a dir also ignores everything inside it
+fn is_ignored(rel_path: &Path, is_dir: bool, ignore_paths: &[String]) -> bool {
+    let options = MatchOptions {
+        case_sensitive: true,
+        require_literal_separator: true,
+        require_literal_leading_dot: false,
+    };
+    let components: Vec<Component> = rel_path.components().collect();
 
-    let mut transformted_path = path.to_string();
-    transformted_path.push('/');
+    for ignore_path in ignore_paths {
 
-    return transformted_path;
+        // Patterns like "some_dir/" only apply to dirs
+        let dir_only = ignore_path.ends_with('/');
+        if dir_only && !is_dir {
+            continue;
+        }
+
+        let pattern = ignore_path.trim_end_matches('/');
+        let anchored = pattern.starts_with('/') || pattern.starts_with("./");
+        let pattern = pattern.strip_prefix("./").unwrap_or(pattern).trim_start_matches('/');
+        let whole_path = anchored || pattern.contains('/') || pattern.contains("**");
+
+        // Paths that the pattern is matched against: only the name, the whole
+        // path, or every tail of the path
+        let candidates: Vec<PathBuf> = if !whole_path {
+            components.last().map(|name| vec![PathBuf::from(name.as_os_str())]).unwrap_or_default()
+        } else if anchored {
+            vec![rel_path.to_path_buf()]
+        } else {
+            (0..components.len()).map(|skip| components[skip..].iter().collect()).collect()
+        };
+
+        let matches = match Pattern::new(pattern) {
+            Ok(glob) => candidates.iter().any(|candidate| glob.matches_path_with(candidate, options)),
+
+            // Not a valid glob, so use it as a literal path
+            Err(_) => candidates.iter().any(|candidate| candidate == Path::new(pattern)),
+        };
+
+        if matches {
+            return true;
+        }
+    }
+
+    return false;
+}
+
+/// Checks if the file (or symlink) in `to` has to be updated to match `from`
+fn entry_differs(from: &Path, to: &Path) -> anyhow::Result<bool> {
+    let to_metadata = match fs::symlink_metadata(to) {
+        Ok(metadata) => metadata,
+        Err(_) => return Ok(true),
+    };
+    let from_metadata = fs::symlink_metadata(from)
+        .with_context(|| format!("Could not get metadata of {}", from.display()))?;
+
+    if from_metadata.file_type().is_symlink() || to_metadata.file_type().is_symlink() {
+        return Ok(fs::read_link(from).ok() != fs::read_link(to).ok());
+    }
+
+    if from_metadata.len() != to_metadata.len() {
+        return Ok(true);
+    }
+
+    let from_contents = fs::read(from).with_context(|| format!("Could not read {}", from.display()))?;
+    let to_contents = fs::read(to).with_context(|| format!("Could not read {}", to.display()))?;
+    return Ok(from_contents != to_contents);
+}
+
+/// Builds the path of the temporary file used to atomically write `path`
+/// The temporary file lives in the same dir as `path`, so the final rename
+/// does not cross filesystems
+fn temporary_path_for(path: &Path) -> anyhow::Result<PathBuf> {
+    let file_name = path.file_name()
+        .with_context(|| format!("Path {} has no file name", path.display()))?;
+
+    // Built as an `OsString`, so file names that are not valid UTF-8 are kept
+    let mut temporary_name = OsString::from(".");
+    temporary_name.push(file_name);
+    temporary_name.push(format!(".punto-tmp-{}", process::id()));
+
+    return Ok(path.with_file_name(temporary_name));
+}
+
+/// Writes `contents` to `to` atomically: the data is written to a temporary
+/// file in the same dir that is then renamed over `to`
+/// So `to` either has its old contents or the new ones, never a truncated file
+/// If `to` already exists, its permissions are kept
+pub fn atomic_write(to: &Path, contents: &[u8]) -> anyhow::Result<()> {
+    let temporary = temporary_path_for(to)?;
+    let permissions = fs::metadata(to).ok().map(|metadata| metadata.permissions());
+
+    let result = fs::write(&temporary, contents)
+        .with_context(|| format!("Could not write temporary file {}", temporary.display()))
+        .and_then(|_| match permissions {
+            Some(permissions) => fs::set_permissions(&temporary, permissions)
+                .with_context(|| format!("Could not set the permissions of {}", temporary.display())),
+            None => Ok(()),
+        })
+        .and_then(|_| fs::rename(&temporary, to)
+            .with_context(|| format!("Could not move temporary file {} to {}", temporary.display(), to.display()))
+        );
+
+    // Do not leave the temporary file behind if something failed
+    if result.is_err() {
+        let _ = fs::remove_file(&temporary);
+    }
+
+    return result;
+}
+
+/// Copies `from` to `to` atomically, using a temporary file plus a rename
+/// Symlinks are copied as symlinks, not followed
+/// Permissions of regular files are preserved
+pub fn atomic_copy(from: &Path, to: &Path) -> anyhow::Result<()> {
+    let temporary = temporary_path_for(to)?;
+
+    let metadata = fs::symlink_metadata(from)
+        .with_context(|| format!("Could not get metadata of {}", from.display()))?;
+
+    let result = if metadata.file_type().is_symlink() {
+        copy_symlink(from, &temporary)
+    } else {
+        fs::copy(from, &temporary)
+            .map(|_| ())
+            .with_context(|| format!("Failed to copy file from {} to {}", from.display(), temporary.display()))
+    }
+    .and_then(|_| fs::rename(&temporary, to)
+        .with_context(|| format!("Could not move temporary file {} to {}", temporary.display(), to.display()))
+    );
+
+    // Do not leave the temporary file behind if something failed
+    if result.is_err() {
+        let _ = fs::remove_file(&temporary);
+    }
+
+    return result;
+}
+
+/// Creates in `to` a symlink pointing to the same target as the symlink `from`
+fn copy_symlink(from: &Path, to: &Path) -> anyhow::Result<()> {
+    let target = fs::read_link(from)
+        .with_context(|| format!("Could not read symlink {}", from.display()))?;
+
+    #[cfg(unix)]
+    return std::os::unix::fs::symlink(&target, to)
+        .with_context(|| format!("Could not create symlink {} -> {}", to.display(), target.display()));
+
+    #[cfg(not(unix))]
+    anyhow::bail!("Copying symlinks is only supported on unix systems, could not copy {}", target.display());
+}
+
+/// Creates `dir` and all its missing parents, recording every created dir in
+/// the `journal`, so they can be removed when rolling back
+pub(crate) fn create_dir_all_recorded(dir: &Path, journal: &mut Journal) -> anyhow::Result<()> {
+
+    // Collect the dirs that do not exist yet, from the deepest one to the
+    // shallowest one
+    let mut missing_dirs = vec![];
+    let mut current = Some(dir);
+    while let Some(curr_dir) = current {
+        if curr_dir.as_os_str().is_empty() || curr_dir.exists() {
+            break;
+        }
+
+        missing_dirs.push(curr_dir.to_path_buf());
+        current = curr_dir.parent();
+    }
+
+    // Create them from the shallowest to the deepest one
+    for missing_dir in missing_dirs.iter().rev() {
+        journal.record_dir_creation(missing_dir)?;
+        fs::create_dir(missing_dir)
+            .with_context(|| format!("Could not create dir {}", missing_dir.display()))?;
+    }
+
+    return Ok(());
+}
+
+/// Returns the entries of `dir` sorted by name, so walks are deterministic
+fn sorted_dir_entries(dir: &Path) -> anyhow::Result<Vec<fs::DirEntry>> {
+    let mut entries = fs::read_dir(dir)
+        .with_context(|| format!("Could not read dir {}", dir.display()))?
+        .collect::<Result<Vec<fs::DirEntry>, std::io::Error>>()
+        .with_context(|| format!("Could not read the entries of dir {}", dir.display()))?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    return Ok(entries);
+}
+
+/// Walks the tree rooted at `root`, returning every file, symlink and dir in
+/// it (parents before their children) with its path relative to `root`
+/// Symlinks are not followed
+///
+/// Entries matching `ignore_paths` (see `is_ignored`) are skipped, and so is
+/// `skip_dir` (relative to `root`) if given
+pub(crate) fn walk_tree(root: &Path, ignore_paths: &[String], skip_dir: Option<&Path>) -> anyhow::Result<Vec<(PathBuf, fs::Metadata)>> {
+    let mut entries = vec![];
+    walk_tree_recursive(root, Path::new(""), ignore_paths, skip_dir, &mut entries)?;
+    return Ok(entries);
+}
+
+/// Recursive step of `walk_tree`
+fn walk_tree_recursive(
+    root: &Path,
+    rel_path: &Path,
+    ignore_paths: &[String],
+    skip_dir: Option<&Path>,
+    entries: &mut Vec<(PathBuf, fs::Metadata)>,
+) -> anyhow::Result<()> {
+    for entry in sorted_dir_entries(&root.join(rel_path))? {
+        let rel_entry = rel_path.join(entry.file_name());
+        let metadata = fs::symlink_metadata(entry.path())
+            .with_context(|| format!("Could not get metadata of {}", entry.path().display()))?;
+
+        if is_ignored(&rel_entry, metadata.is_dir(), ignore_paths) || Some(rel_entry.as_path()) == skip_dir {
+            continue;
+        }
+
+        let is_dir = metadata.is_dir();
+        entries.push((rel_entry.clone(), metadata));
+
+        if is_dir {
+            walk_tree_recursive(root, &rel_entry, ignore_paths, skip_dir, entries)?;
+        }
+    }
+
+    return Ok(());
 }
 
 /// Syncs two paths
-/// `ignore_paths` can be both file and dir paths
+/// `ignore_paths` can be both file and dir paths, see `is_ignored` for the
+/// supported patterns
 /// `ignore_paths` must be relative paths based on `from` path
 ///
 /// If `remove_files` is true, files and dirs that are not present in `from` path but are present
 /// in `to` path will be removed
 ///
-// TODO -- we're using rsync to do this, move that to native rust code
-pub fn sync_dir(from: &str, to: &str, ignore_paths: &Vec<String>, remove_files: bool) -> anyhow::Result<()>{
-
-    // For using rsync, last char in the paths must be /
-    // So make some checks and do the conversion if they fail
-    // i.e. some/path -> some/path/
-    let from = add_last_slash_to_path(&from);
-    let to = add_last_slash_to_path(&to);
+/// Every file is written atomically, and every change made in `to` is
+/// recorded in `journal`
+///
+/// Both trees are scanned using their indexes from `index_store`, so only the
+/// files that changed since the last sync are hashed
+///
+/// Returns the paths, inside `to`, of all the files synced from `from`
+pub fn sync_dir(
+    from: &Path,
+    to: &Path,
+    ignore_paths: &[String],
+    remove_files: bool,
+    journal: &mut Journal,
+    index_store: &IndexStore,
+) -> anyhow::Result<Vec<PathBuf>>{
+    if !from.is_dir() {
+        anyhow::bail!("{} is not a dir", from.display());
+    }
 
-    // Build a bash command based on rsync to perform the operation
-    // This has to be done in four steps due to ignore_files and remove_files nature
+    create_dir_all_recorded(to, journal)?;
 
-    // Step 1: create the base of the command string
-    let mut command_content = format!("rsync -zaP ");
+    // `to` might be inside `from`, and we do not want to copy it into itself
+    let from_canonical = from.canonicalize()
+        .with_context(|| format!("Could not canonicalize {}", from.display()))?;
+    let to_canonical = to.canonicalize()
+        .with_context(|| format!("Could not canonicalize {}", to.display()))?;
+    let skip_dir = to_canonical.strip_prefix(&from_canonical).ok();
 
-    // Step 2: add this flag, so rsync creates the necessary dirs if they don't
-    // exist
-    command_content.push_str("--mkpath ");
+    // Scan both trees, hashing only what changed
+    let mut from_index = index_store.open(from)?;
+    let mut to_index = index_store.open(to)?;
+    let from_scan = from_index.scan(ignore_paths, skip_dir)?;
+    let to_scan = to_index.scan(ignore_paths, None)?;
 
-    // Step 3: check if we want to remove files
-    if remove_files == true{
-        command_content.push_str("--delete ");
+    for dir in &from_scan.dirs {
+        create_dir_all_recorded(&to.join(dir), journal)?;
     }
 
-    // Step 4: add the ignored files
-    if ignore_paths.is_empty() == false{
-        for excluded_file in ignore_paths{
-            command_content.push_str(&format!("--exclude {excluded_file} "));
+    let mut synced = vec![];
+    for (rel_path, hash) in &from_scan.files {
+        let source = from.join(rel_path);
+        let destination = to.join(rel_path);
+        synced.push(destination.clone());
+
+        // Same contents in both places
+        if to_scan.files.get(rel_path) == Some(hash) {
+            continue;
         }
+
+        if destination.is_dir() && !destination.is_symlink() {
+            anyhow::bail!("Cannot replace dir {} with file {}", destination.display(), source.display());
+        }
+
+        journal.record_file_write(&destination)?;
+        atomic_copy(&source, &destination)?;
+        to_index.record(rel_path, hash)?;
     }
 
-    // Step 5: specify source and destination
-    command_content.push_str(&format!("{from} {to}"));
+    // Remove the entries in `to` that are not present in `from`
+    if remove_files {
+        for rel_path in to_scan.files.keys().filter(|rel_path| !from_scan.files.contains_key(*rel_path)) {
+            let path = to.join(rel_path);
+            journal.record_file_removal(&path)?;
+            fs::remove_file(&path).with_context(|| format!("Could not remove file {}", path.display()))?;
+            to_index.remove(rel_path);
+        }
 
-    let quiet = false;
-    let sudo = false;
-    let command = SingleCommand::new(
-        command_content, quiet, sudo,
-    ).context("Could not create the command to use rsync")?;
+        // Children before their parents, and only if they are empty, because
+        // they might contain ignored files
+        for rel_path in to_scan.dirs.iter().rev().filter(|rel_path| !from_scan.dirs.contains(rel_path)) {
+            let path = to.join(rel_path);
+            let is_empty = fs::read_dir(&path)
+                .with_context(|| format!("Could not read dir {}", path.display()))?
+                .next()
+                .is_none();
+
+            if is_empty {
+                journal.record_dir_removal(&path)?;
+                fs::remove_dir(&path).with_context(|| format!("Could not remove dir {}", path.display()))?;
+            }
+        }
+    }
 
-    // Run the command
-    command.run().context("Rsync command failed at runtime")?;
-    return Ok(());
+    from_index.persist()?;
+    to_index.persist()?;
+    return Ok(synced);
 }
 
 /// Copies one file to another location
 /// Creates the `to` folder if it does not exist
-pub fn sync_file(from: &str, to: &str) -> anyhow::Result<()> {
+/// The file is written atomically, and the changes are recorded in `journal`
+pub fn sync_file(from: &Path, to: &Path, journal: &mut Journal) -> anyhow::Result<()> {
 
     // Get the path to the parent dir of `to` file
-    let parent_dir = Path::new(to).parent()
-        .with_context(|| format!("Could not get the path of the parent dir of dest. file {}", to))?
-        .to_str().with_context(|| format!("Could not get the string of the parent dir of dest file {}", to))?;
+    let parent_dir = to.parent()
+        .with_context(|| format!("Could not get the path of the parent dir of dest. file {}", to.display()))?;
 
     // Create the dir for the new file
-    fs::create_dir_all(parent_dir)
-        .with_context(|| format!("Could not create dir {} to store new file", parent_dir))?;
+    create_dir_all_recorded(parent_dir, journal)
+        .with_context(|| format!("Could not create dir {} to store new file", parent_dir.display()))?;
 
-    // Copy the file to the new dir
-    fs::copy(from, to).context(format!("Failed to copy file from {} to {}", from, to))?;
+    // Copy the file to the new dir, only if it changed
+    if entry_differs(from, to)? {
+        journal.record_file_write(to)?;
+        atomic_copy(from, to).context(format!("Failed to copy file from {} to {}", from.display(), to.display()))?;
+    }
 
     return Ok(());
 }
 
-/// Joins two paths given in strings
+/// Joins two paths, sanitizing the second one with `sanitize_relative_path`
 ///
 /// # Examples
 /// ```
+/// use std::path::Path;
 /// use lib_fileops::join_two_paths;
-/// let joined = join_two_paths("first_part", "second_part");
-/// let expected = "first_part/second_part";
+/// let joined = join_two_paths(Path::new("first_part"), Path::new("second_part"));
+/// let expected = Path::new("first_part/second_part");
 /// assert_eq!(expected, joined, "Join two paths func did not work properly");
 /// ```
-pub fn join_two_paths(first: &str, second: &str) -> String{
+pub fn join_two_paths(first: &Path, second: &Path) -> PathBuf{
     let second_sanitized = sanitize_relative_path(second);
-    let joined_path = std::path::Path::new(first).join(second_sanitized).to_str().unwrap().to_string();
-    return joined_path;
+    return first.join(second_sanitized);
 }
 
-/// Relative paths that are stored in a string in the form of "./something" are
+/// Relative paths that are stored in the form of "./something" are
 /// dangerous. Some functions fail when passing relative paths like that
 /// So here we sanitize that relative paths
 ///
@@ -124,64 +418,84 @@ pub fn join_two_paths(first: &str, second: &str) -> String{
 /// # Examples
 ///
 /// ```
+/// use std::path::Path;
 /// use lib_fileops::sanitize_relative_path;
-/// let computed = sanitize_relative_path("./some/rel/path");
-/// let expected = "some/rel/path";
+/// let computed = sanitize_relative_path(Path::new("./some/rel/path"));
+/// let expected = Path::new("some/rel/path");
 /// assert_eq!(expected, computed, "Relative path sanitizer did not work well");
 ///
-/// let computed = sanitize_relative_path("/some/rel/path");
-/// let expected = "some/rel/path";
+/// let computed = sanitize_relative_path(Path::new("/some/rel/path"));
+/// let expected = Path::new("some/rel/path");
 /// assert_eq!(expected, computed, "Relative path sanitizer did not work well");
 /// ```
-pub fn sanitize_relative_path(rel_path: &str) -> String {
-
-    if &rel_path[0..1] == "/" {
-        let sanitized: &str = &rel_path[1..rel_path.len()];
-        return sanitized.to_string();
-    }
-
-    if &rel_path[0..2] == "./" {
-        let sanitized: &str = &rel_path[2..rel_path.len()];
-        return sanitized.to_string();
+pub fn sanitize_relative_path(rel_path: &Path) -> PathBuf {
+    return rel_path.components()
+        .filter(|component| !matches!(component, Component::Prefix(_) | Component::RootDir | Component::CurDir))
+        .collect();
+}
 
+/// Lexically normalizes a path that is going to be joined to a base path:
+///     - Leading "/" and "./" are removed, as in `sanitize_relative_path`
+///     - "." components are removed
+///     - ".." components remove the previous component
+///
+/// The filesystem is not accessed, so symlinks are not resolved
+/// A path that escapes its base keeps its leading ".." components, see
+/// `escapes_base`
+///
+/// # Examples
+///
+/// ```
+/// use std::path::Path;
+/// use lib_fileops::normalize_relative_path;
+/// assert_eq!(normalize_relative_path(Path::new("./some/../rel/./path")), Path::new("rel/path"));
+/// assert_eq!(normalize_relative_path(Path::new("some/../../etc")), Path::new("../etc"));
+/// ```
+pub fn normalize_relative_path(rel_path: &Path) -> PathBuf {
+    let mut components: Vec<Component> = vec![];
+
+    for component in rel_path.components() {
+        match component {
+            Component::Prefix(_) | Component::RootDir | Component::CurDir => continue,
+            Component::ParentDir => match components.last() {
+                Some(Component::Normal(_)) => {
+                    components.pop();
+                },
+                _ => components.push(Component::ParentDir),
+            },
+            normal => components.push(normal),
+        }
     }
 
-    return rel_path.to_string();
+    return components.iter().collect();
 }
 
-/// `folder_compare::Error` does not implement `Error` trait, which is needed
-/// for using anyhow. So this enum takes a `folder_compare::Error` and implements
-/// the traits we need
-#[derive(Error, Debug)]
-enum DiffError{
-    #[error("Error while computing the diff between two dirs, reason: {inner_error:?}")]
-    DiffError {
-        inner_error: folder_compare::Error,
-    }
+/// Checks if a path normalized with `normalize_relative_path` points outside
+/// of the base path it is going to be joined to
+pub fn escapes_base(normalized_path: &Path) -> bool {
+    return normalized_path.components().next() == Some(Component::ParentDir);
 }
 
 /// Given two folders, defined by paths `first_path` and `second_path`, returns
 /// the list of files that are present in the second dir but not present in the
 /// first dir
-pub fn get_dir_diff(first_path: &str, second_path: &str) -> anyhow::Result<Vec<String>> {
-
-    let excluded = vec![];
-    let new_files = FolderCompare::new(
-        Path::new(second_path),
-        Path::new(first_path),
-        &excluded
-    )
-    // Use our custom error type so we can use anyhow
-    .map_err(|inner| DiffError::DiffError{inner_error: inner})
-    .context(format!("An error ocurred while diffing {first_path} and {second_path}"))?
-    .new_files;
-
-    // We want the strings out of the `PathBuf` objects
-    let new_files: Vec<String> = new_files.iter().map(|pathbuf|
-        pathbuf.to_str().context("Could not convert pathbuf {pathbuf:?} to string")
-    )
-    .collect::<anyhow::Result<Vec<&str>>>()? // some paths could faild to be converted to `&str`
-    .iter().map(|path| path.to_string()).collect(); // `&str -> String`
+///
+/// Only the paths are compared, so no file is read or hashed
+pub fn get_dir_diff(first_path: &Path, second_path: &Path) -> anyhow::Result<Vec<PathBuf>> {
+
+    let first_files: HashSet<PathBuf> = walk_tree(first_path, &[], None)
+        .context(format!("An error ocurred while walking {}", first_path.display()))?
+        .into_iter()
+        .filter(|(_, metadata)| !metadata.is_dir())
+        .map(|(rel_path, _)| rel_path)
+        .collect();
+
+    let new_files = walk_tree(second_path, &[], None)
+        .context(format!("An error ocurred while walking {}", second_path.display()))?
+        .into_iter()
+        .filter(|(rel_path, metadata)| !metadata.is_dir() && !first_files.contains(rel_path))
+        .map(|(rel_path, _)| second_path.join(rel_path))
+        .collect();
 
     return Ok(new_files);
 }
@@ -189,10 +503,14 @@ pub fn get_dir_diff(first_path: &str, second_path: &str) -> anyhow::Result<Vec<S
 #[cfg(test)]
 mod tests {
     use std::fs;
-    use std::path::Path;
+    use std::path::{Path, PathBuf};
 
+    use crate::index::IndexStore;
+    use crate::journal::Journal;
     use super::{
-        add_last_slash_to_path,
+        escapes_base,
+        is_ignored,
+        normalize_relative_path,
         join_two_paths,
         sync_dir,
         sync_file,
@@ -203,55 +521,102 @@ mod tests {
     #[test]
     fn test_join_two_paths_basic() {
 
-        let computed = join_two_paths("testing", "this");
-        let expected = "testing/this";
+        let computed = join_two_paths(Path::new("testing"), Path::new("this"));
+        let expected = Path::new("testing/this");
         assert_eq!(expected, computed);
     }
 
     #[test]
     fn test_join_two_paths_trailing_slashes(){
 
-        let computed = join_two_paths("testing/", "this");
-        let expected = "testing/this";
+        let computed = join_two_paths(Path::new("testing/"), Path::new("this"));
+        let expected = Path::new("testing/this");
         assert_eq!(expected, computed);
 
-        let computed = join_two_paths("testing", "this/");
-        let expected = "testing/this/";
+        let computed = join_two_paths(Path::new("testing"), Path::new("this/"));
+        let expected = Path::new("testing/this/");
         assert_eq!(expected, computed);
 
-        let computed = join_two_paths("testing/", "this/");
-        let expected = "testing/this/";
+        let computed = join_two_paths(Path::new("testing/"), Path::new("this/"));
+        let expected = Path::new("testing/this/");
         assert_eq!(expected, computed);
     }
 
     #[test]
     fn test_join_two_paths_with_relative_paths(){
-        let computed = join_two_paths("some/path/", "./relative/path");
-        let expected = "some/path/relative/path";
+        let computed = join_two_paths(Path::new("some/path/"), Path::new("./relative/path"));
+        let expected = Path::new("some/path/relative/path");
         assert_eq!(expected, computed, "Relative paths are not joined properly");
     }
 
     #[test]
-    fn test_add_last_slash_to_path(){
-        let original_path = "some/path";
-        let transformted_path = add_last_slash_to_path(original_path);
-        assert_eq!(transformted_path, "some/path/", "add_last_slash_to_path did not added last slash");
+    fn test_is_ignored_patterns() {
+        let ignore_paths = vec![
+            "*.swp".to_string(),
+            "src/first.rs".to_string(),
+            "/test/first_test.rs".to_string(),
+            "__pycache__/".to_string(),
+            "**/cache".to_string(),
+            "build/**".to_string(),
+            "/docs/*.md".to_string(),
+        ];
+
+        // Patterns without slash match the name at any depth
+        assert!(is_ignored(Path::new("deep/dir/file.swp"), false, &ignore_paths));
+        assert!(is_ignored(Path::new("some/__pycache__"), true, &ignore_paths));
+
+        // Unanchored patterns with slash match the end of the path
+        assert!(is_ignored(Path::new("src/first.rs"), false, &ignore_paths));
+        assert!(is_ignored(Path::new("other/src/first.rs"), false, &ignore_paths));
+        assert!(!is_ignored(Path::new("mysrc/first.rs"), false, &ignore_paths));
+
+        // Anchored patterns only match from the root of the sync
+        assert!(is_ignored(Path::new("test/first_test.rs"), false, &ignore_paths));
+        assert!(!is_ignored(Path::new("other/test/first_test.rs"), false, &ignore_paths));
+
+        // Trailing slash only matches dirs
+        assert!(!is_ignored(Path::new("__pycache__"), false, &ignore_paths));
+
+        // "**" matches any number of dirs, "*" does not match a slash
+        assert!(is_ignored(Path::new("cache"), true, &ignore_paths));
+        assert!(is_ignored(Path::new("a/b/cache"), true, &ignore_paths));
+        assert!(is_ignored(Path::new("build/out/main.o"), false, &ignore_paths));
+        assert!(is_ignored(Path::new("docs/index.md"), false, &ignore_paths));
+        assert!(!is_ignored(Path::new("docs/api/index.md"), false, &ignore_paths));
+
+        assert!(!is_ignored(Path::new("src/second.rs"), false, &ignore_paths));
+    }
 
+    #[test]
+    fn test_sanitizer_does_not_panic_on_short_paths() {
+        assert_eq!(sanitize_relative_path(Path::new("")), Path::new(""));
+        assert_eq!(sanitize_relative_path(Path::new("a")), Path::new("a"));
+        assert_eq!(sanitize_relative_path(Path::new("/")), Path::new(""));
+    }
 
-        let original_path = "some/path/";
-        let transformted_path = add_last_slash_to_path(original_path);
-        assert_eq!(transformted_path, "some/path/", "add_last_slash_to_path changed a path that was correct at first");
+    #[test]
+    fn test_normalize_relative_path() {
+        assert_eq!(normalize_relative_path(Path::new("/etc/hosts")), Path::new("etc/hosts"));
+        assert_eq!(normalize_relative_path(Path::new("a/./b//c/")), Path::new("a/b/c"));
+        assert_eq!(normalize_relative_path(Path::new("a/b/../../c")), Path::new("c"));
+        assert_eq!(normalize_relative_path(Path::new("a/../..")), Path::new(".."));
+        assert_eq!(normalize_relative_path(Path::new("../../etc")), Path::new("../../etc"));
+
+        assert!(escapes_base(&normalize_relative_path(Path::new("../../etc"))));
+        assert!(escapes_base(&normalize_relative_path(Path::new("a/../.."))));
+        assert!(!escapes_base(&normalize_relative_path(Path::new("a/../b"))));
+        assert!(!escapes_base(&normalize_relative_path(Path::new("..config"))));
     }
 
     #[test]
     fn test_sanitizer_works() {
 
-        let computed = sanitize_relative_path("./some/rel/path");
-        let expected = "some/rel/path";
+        let computed = sanitize_relative_path(Path::new("./some/rel/path"));
+        let expected = Path::new("some/rel/path");
         assert_eq!(expected, computed, "Relative path sanitizer did not work well");
 
-        let computed = sanitize_relative_path("/some/rel/path");
-        let expected = "some/rel/path";
+        let computed = sanitize_relative_path(Path::new("/some/rel/path"));
+        let expected = Path::new("some/rel/path");
         assert_eq!(expected, computed, "Relative path sanitizer did not work well");
     }
 
@@ -294,7 +659,7 @@ mod tests {
         let to = Path::new(base_path).join("pruebas");
         let ignore_files = vec![];
         let remove_files = false;
-        sync_dir(from, to.to_str().unwrap(), &ignore_files, remove_files).expect("Copy operation failed to run");
+        sync_dir(Path::new(from), &to, &ignore_files, remove_files, &mut Journal::disabled(), &IndexStore::disabled()).expect("Copy operation failed to run");
 
         // Make some checks about the dirs
         assert!(Path::new(base_path).join("pruebas/").exists(), "New dir hierarchy was not created properly");
@@ -327,12 +692,11 @@ mod tests {
         // Copy now to another path
         let from = base_path;
 
-        let binding = Path::new(base_path).join("pruebas");
-        let to = binding.to_str().unwrap();
+        let to = Path::new(base_path).join("pruebas");
 
         let ignore_files = vec!["src/first.rs".to_string(), "src/second.rs".to_string()];
         let remove_files = false;
-        sync_dir(from, to, &ignore_files, remove_files).expect("Copy operation failed to run");
+        sync_dir(Path::new(from), &to, &ignore_files, remove_files, &mut Journal::disabled(), &IndexStore::disabled()).expect("Copy operation failed to run");
 
         // Make some checks about the dirs
         assert!(Path::new(base_path).join("pruebas/").exists(), "New dir hierarchy was not created properly");
@@ -352,6 +716,72 @@ mod tests {
         remove_basic_file_structure(base_path);
     }
 
+    #[test]
+    fn test_sync_dir_with_index_picks_up_changes(){
+
+        let base_path = "test_sync_dir_with_index_picks_up_changes";
+
+        // Start creating a basic file structure
+        // If a test fails, this structure might be already created, so delete if first
+        remove_basic_file_structure(base_path);
+        create_basic_file_structure(base_path)
+            .expect("Could not create basic file structure for the test");
+
+        let from = Path::new(base_path).join("src");
+        let to = Path::new(base_path).join("pruebas");
+        let index_store = IndexStore::new(&Path::new(base_path).join("index"));
+
+        // First sync builds the indexes
+        let synced = sync_dir(&from, &to, &[], false, &mut Journal::disabled(), &index_store)
+            .expect("Copy operation failed to run");
+        assert_eq!(synced.len(), 3, "All files should be reported as synced");
+
+        // Second sync, with the indexes, still notices the changed file
+        fs::write(from.join("second.rs"), "fn main() {}").unwrap();
+        sync_dir(&from, &to, &[], false, &mut Journal::disabled(), &index_store)
+            .expect("Copy operation failed to run");
+        assert_eq!(fs::read_to_string(to.join("second.rs")).unwrap(), "fn main() {}", "Changed file was not synced");
+
+        // Now, remove the file hierarchy created
+        remove_basic_file_structure(base_path);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_sync_dir_with_non_utf8_names(){
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let base_path = Path::new("test_sync_dir_with_non_utf8_names");
+        let _ = fs::remove_dir_all(base_path);
+
+        // Names that are not valid UTF-8, for a file and for a dir
+        let file_name = OsStr::from_bytes(b"caf\xe9.txt");
+        let dir_name = OsStr::from_bytes(b"d\xffir");
+        let from = base_path.join("repo");
+        fs::create_dir_all(from.join(dir_name)).unwrap();
+        fs::write(from.join(file_name), "contents").unwrap();
+        fs::write(from.join(dir_name).join(file_name), "nested").unwrap();
+
+        // Persisted indexes and journal must be able to store these names
+        let to = base_path.join("system");
+        let index_store = IndexStore::new(&base_path.join("index"));
+        let mut journal = Journal::start(&base_path.join("journal")).unwrap();
+        let synced = sync_dir(&from, &to, &[], true, &mut journal, &index_store)
+            .expect("Sync with non UTF-8 names failed");
+
+        assert_eq!(synced.len(), 2);
+        assert_eq!(fs::read_to_string(to.join(file_name)).unwrap(), "contents");
+        assert_eq!(fs::read_to_string(to.join(dir_name).join(file_name)).unwrap(), "nested");
+
+        // The persisted journal can be loaded back and rolled back
+        let journal = Journal::load(&base_path.join("journal")).unwrap().expect("Journal was not persisted");
+        journal.rollback().expect("Rollback failed");
+        assert!(!to.join(file_name).exists(), "Created file was not removed");
+
+        let _ = fs::remove_dir_all(base_path);
+    }
+
     #[test]
     fn test_sync_file_base_case(){
 
@@ -366,7 +796,7 @@ mod tests {
         // Sync just a single file
         let from = Path::new(base_path).join("src").join("first.rs");
         let to = Path::new(base_path).join("pruebas/code").join("first.rs");
-        sync_file(from.to_str().unwrap(), to.to_str().unwrap()).expect("Copy operation failed to run");
+        sync_file(&from, &to, &mut Journal::disabled()).expect("Copy operation failed to run");
 
         // Check that the dir for the file was created
         assert!(Path::new(base_path).join("pruebas/code").exists(), "Dir for the new file was not created");
@@ -399,14 +829,14 @@ mod tests {
 
         // Compute one diff and check the result
         // A single new file should be detected
-        let new_files = get_dir_diff(base_path, other_path).unwrap();
-        let expected_new_files = vec![new_file_path.to_str().unwrap().to_string()];
+        let new_files = get_dir_diff(Path::new(base_path), Path::new(other_path)).unwrap();
+        let expected_new_files = vec![new_file_path];
         assert_eq!(new_files, expected_new_files, "Diff dir did not found a new file");
 
         // Compute the other diff and check the result
         // This time no new files should be detected
-        let new_files = get_dir_diff(other_path, base_path).unwrap();
-        let expected_new_files: Vec<String> = vec![];
+        let new_files = get_dir_diff(Path::new(other_path), Path::new(base_path)).unwrap();
+        let expected_new_files: Vec<PathBuf> = vec![];
         assert_eq!(new_files, expected_new_files, "Diff dir found new files when no one should be found");
     }
 }
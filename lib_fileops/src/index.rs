@@ -0,0 +1,310 @@
+//! Module to implement a persistent index of the files of a tree
+//!
+//! Hashing big trees (for example, `~/.config`) on every sync is slow. So for
+//! every synced tree we store the size, modification time and hash of its
+//! files. In the next scan, files whose size and modification time did not
+//! change reuse the stored hash, and only the changed ones are hashed (in
+//! parallel). That way, syncs are proportional to what changed
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::file_operations::{atomic_write, walk_tree};
+
+/// What we know about a single file of the tree
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct IndexEntry {
+    size: u64,
+    modified_secs: u64,
+    modified_nanos: u32,
+
+    /// When the file was hashed, in seconds since the epoch
+    hashed_at: u64,
+
+    hash: String,
+}
+
+impl IndexEntry {
+    fn new(metadata: &fs::Metadata, hash: String) -> Self {
+        let (modified_secs, modified_nanos) = modification_time(metadata);
+        return Self { size: metadata.len(), modified_secs, modified_nanos, hashed_at: now_secs(), hash };
+    }
+
+    /// Checks if the stored hash can be trusted for a file with `metadata`
+    ///
+    /// A file modified in the same second it was hashed could have been
+    /// modified again without changing its modification time, so in that case
+    /// the stored hash is not trusted
+    fn is_fresh(&self, metadata: &fs::Metadata) -> bool {
+        let (modified_secs, modified_nanos) = modification_time(metadata);
+        return self.size == metadata.len()
+            && self.modified_secs == modified_secs
+            && self.modified_nanos == modified_nanos
+            && self.modified_secs < self.hashed_at;
+    }
+}
+
+/// How an `IndexEntry` is persisted
+/// Entries are stored as a list, since the paths of the files can not be the
+/// keys of a JSON map when they are not valid UTF-8
+#[derive(Serialize, Deserialize)]
+struct PersistedEntry {
+    #[serde(with = "crate::serde_path")]
+    path: PathBuf,
+
+    entry: IndexEntry,
+}
+
+/// Files and dirs of a tree, with paths relative to its root
+#[derive(Debug, Default)]
+pub struct TreeScan {
+    /// Every file with its hash
+    pub files: BTreeMap<PathBuf, String>,
+
+    /// Every dir, parents before their children
+    pub dirs: Vec<PathBuf>,
+}
+
+/// Index of the files of the tree rooted at `root`
+#[derive(Debug)]
+pub struct TreeIndex {
+    /// File where the index is persisted
+    /// If it is None, the index lives only in memory
+    location: Option<PathBuf>,
+
+    root: PathBuf,
+
+    entries: BTreeMap<PathBuf, IndexEntry>,
+}
+
+impl TreeIndex {
+
+    /// Walks the tree, hashing the files that changed since the last scan
+    /// `ignore_paths` follow the rules of `sync_dir`, and `skip_dir` (relative
+    /// to the root) is not walked
+    /// If the root does not exist, the scan is empty
+    pub fn scan(&mut self, ignore_paths: &[String], skip_dir: Option<&Path>) -> anyhow::Result<TreeScan> {
+        let mut scan = TreeScan::default();
+        if !self.root.exists() {
+            self.entries.clear();
+            return Ok(scan);
+        }
+
+        let mut to_hash = vec![];
+        let mut seen = BTreeMap::new();
+        for (rel_path, metadata) in walk_tree(&self.root, ignore_paths, skip_dir)? {
+            if metadata.is_dir() {
+                scan.dirs.push(rel_path);
+                continue;
+            }
+
+            // Symlinks are compared by their target
+            if metadata.file_type().is_symlink() {
+                let target = fs::read_link(self.root.join(&rel_path))
+                    .with_context(|| format!("Could not read symlink {}", self.root.join(&rel_path).display()))?;
+                // Debug formatting keeps the bytes of targets that are not UTF-8
+                scan.files.insert(rel_path, format!("symlink:{:?}", target));
+                continue;
+            }
+
+            match self.entries.get(&rel_path) {
+                Some(entry) if entry.is_fresh(&metadata) => {
+                    scan.files.insert(rel_path.clone(), entry.hash.clone());
+                    seen.insert(rel_path, entry.clone());
+                },
+                _ => to_hash.push((rel_path, metadata)),
+            }
+        }
+
+        // Only the changed files are hashed, in parallel
+        let root = &self.root;
+        let hashed = to_hash
+            .into_par_iter()
+            .map(|(rel_path, metadata)| {
+                let hash = hash_file(&root.join(&rel_path))?;
+                return Ok((rel_path, IndexEntry::new(&metadata, hash)));
+            })
+            .collect::<anyhow::Result<Vec<(PathBuf, IndexEntry)>>>()?;
+
+        for (rel_path, entry) in hashed {
+            scan.files.insert(rel_path.clone(), entry.hash.clone());
+            seen.insert(rel_path, entry);
+        }
+
+        // Files that are gone are dropped from the index
+        self.entries = seen;
+        return Ok(scan);
+    }
+
+    /// Records that the file in `rel_path` was just written with contents
+    /// whose hash is `hash`, so it does not need to be hashed again
+    pub fn record(&mut self, rel_path: &Path, hash: &str) -> anyhow::Result<()> {
+        let path = self.root.join(rel_path);
+        let metadata = fs::symlink_metadata(&path)
+            .with_context(|| format!("Could not get metadata of {}", path.display()))?;
+
+        if !metadata.file_type().is_symlink() {
+            self.entries.insert(rel_path.to_path_buf(), IndexEntry::new(&metadata, hash.to_string()));
+        }
+
+        return Ok(());
+    }
+
+    /// Drops the file in `rel_path` from the index
+    pub fn remove(&mut self, rel_path: &Path) {
+        self.entries.remove(rel_path);
+    }
+
+    /// Writes the index to disk
+    pub fn persist(&self) -> anyhow::Result<()> {
+        let location = match &self.location {
+            Some(location) => location,
+            None => return Ok(()),
+        };
+
+        if let Some(parent) = location.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Could not create dir {}", parent.display()))?;
+        }
+
+        let persisted: Vec<PersistedEntry> = self.entries.iter()
+            .map(|(path, entry)| PersistedEntry { path: path.clone(), entry: entry.clone() })
+            .collect();
+        let data = serde_json::to_string(&persisted).context("Could not serialize the index")?;
+        return atomic_write(location, data.as_bytes());
+    }
+}
+
+/// Place where the indexes of all the synced trees are stored
+#[derive(Debug)]
+pub struct IndexStore {
+    /// Dir where the indexes are persisted
+    /// If it is None, indexes live only in memory
+    location: Option<PathBuf>,
+}
+
+impl IndexStore {
+
+    /// Creates a store that persists the indexes in `location`
+    pub fn new(location: &Path) -> Self {
+        return Self { location: Some(location.to_path_buf()) };
+    }
+
+    /// Creates a store whose indexes are not persisted, so every scan hashes
+    /// all the files
+    pub fn disabled() -> Self {
+        return Self { location: None };
+    }
+
+    /// Opens the index of the tree rooted at `root`
+    /// Each tree gets its own file, named after the absolute path of the root
+    pub fn open(&self, root: &Path) -> anyhow::Result<TreeIndex> {
+        let location = match &self.location {
+            Some(location) => location,
+            None => return Ok(TreeIndex { location: None, root: root.to_path_buf(), entries: BTreeMap::new() }),
+        };
+
+        let absolute_root = match root.canonicalize() {
+            Ok(canonical) => canonical,
+            Err(_) => std::env::current_dir().context("Could not get the current dir")?.join(root),
+        };
+        let index_file = location.join(format!("{}.json", absolute_root.to_string_lossy().replace('/', "%")));
+
+        // An unreadable index only means that every file is hashed again
+        let entries = match fs::read_to_string(&index_file) {
+            Ok(data) => serde_json::from_str::<Vec<PersistedEntry>>(&data)
+                .unwrap_or_default()
+                .into_iter()
+                .map(|persisted| (persisted.path, persisted.entry))
+                .collect(),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(err) => return Err(err).with_context(|| format!("Could not read index {}", index_file.display())),
+        };
+
+        return Ok(TreeIndex { location: Some(index_file), root: root.to_path_buf(), entries });
+    }
+}
+
+/// Hashes the contents of the file in `path`
+fn hash_file(path: &Path) -> anyhow::Result<String> {
+    let mut file = fs::File::open(path).with_context(|| format!("Could not open {}", path.display()))?;
+    let mut hasher = blake3::Hasher::new();
+    io::copy(&mut file, &mut hasher).with_context(|| format!("Could not read {}", path.display()))?;
+
+    return Ok(hasher.finalize().to_hex().to_string());
+}
+
+/// Modification time of a file, as seconds and nanoseconds since the epoch
+fn modification_time(metadata: &fs::Metadata) -> (u64, u32) {
+    let since_epoch = metadata.modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .unwrap_or_default();
+
+    return (since_epoch.as_secs(), since_epoch.subsec_nanos());
+}
+
+/// Current time, in seconds since the epoch
+fn now_secs() -> u64 {
+    return SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_secs())
+        .unwrap_or_default();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    use super::IndexStore;
+
+    #[test]
+    fn test_index_reuses_hashes_of_unchanged_files() {
+        let base_path = Path::new("test_index_reuses_hashes_of_unchanged_files");
+        let _ = fs::remove_dir_all(base_path);
+        fs::create_dir_all(base_path.join("tree/nested")).unwrap();
+        fs::write(base_path.join("tree/first.txt"), "first").unwrap();
+        fs::write(base_path.join("tree/nested/second.txt"), "second").unwrap();
+
+        let store = IndexStore::new(&base_path.join("index"));
+        let mut index = store.open(&base_path.join("tree")).unwrap();
+        let scan = index.scan(&[], None).unwrap();
+        index.persist().unwrap();
+
+        assert_eq!(scan.dirs, vec![PathBuf::from("nested")]);
+        assert_eq!(scan.files.len(), 2);
+
+        // Pretend that the files were hashed long ago, and tamper with the
+        // stored hash of a file whose size and mtime did not change
+        let mut index = store.open(&base_path.join("tree")).unwrap();
+        for entry in index.entries.values_mut() {
+            entry.hashed_at = u64::MAX;
+        }
+        index.entries.get_mut(Path::new("first.txt")).unwrap().hash = "stored hash".to_string();
+
+        // The stored hash is reused, instead of hashing the file again
+        let scan = index.scan(&[], None).unwrap();
+        assert_eq!(scan.files[Path::new("first.txt")], "stored hash");
+
+        // Changed files are hashed again
+        fs::write(base_path.join("tree/nested/second.txt"), "changed contents").unwrap();
+        let rescan = index.scan(&[], None).unwrap();
+        assert_ne!(scan.files[Path::new("nested/second.txt")], rescan.files[Path::new("nested/second.txt")]);
+
+        // Removed files are dropped
+        fs::remove_file(base_path.join("tree/first.txt")).unwrap();
+        let rescan = index.scan(&[], None).unwrap();
+        assert!(!rescan.files.contains_key(Path::new("first.txt")));
+        assert!(!index.entries.contains_key(Path::new("first.txt")));
+
+        fs::remove_dir_all(base_path).unwrap();
+    }
+}
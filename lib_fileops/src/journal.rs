@@ -0,0 +1,381 @@
+//! Module to implement an operation journal
+//!
+//! Before changing anything in the filesystem, sync operations record in the
+//! journal what they are about to do (and back up the files they are about to
+//! overwrite or remove). The journal is persisted after every record, so even
+//! if punto crashes in the middle of a sync, the filesystem can be rolled back
+//! to the exact state it had before the sync started
+
+use std::{fs, path::{Path, PathBuf}};
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::file_operations::{atomic_copy, atomic_write};
+
+/// Name of the file, inside the journal dir, that stores the entries
+const ENTRIES_FILE: &str = "journal.json";
+
+/// Name of the dir, inside the journal dir, that stores the backups
+const BACKUPS_DIR: &str = "backups";
+
+/// Name of the file, inside the journal dir, that marks that the operation
+/// finished without errors
+const FINISHED_FILE: &str = "finished";
+
+/// A single change made to the filesystem
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum JournalEntry {
+    /// A file that did not exist was created
+    FileCreated {
+        #[serde(with = "crate::serde_path")]
+        path: PathBuf,
+    },
+
+    /// An existing file was overwritten. Its old contents are in `backup`
+    FileOverwritten {
+        #[serde(with = "crate::serde_path")]
+        path: PathBuf,
+        backup: PathBuf,
+    },
+
+    /// An existing file was removed. Its old contents are in `backup`
+    FileRemoved {
+        #[serde(with = "crate::serde_path")]
+        path: PathBuf,
+        backup: PathBuf,
+    },
+
+    /// A dir that did not exist was created
+    DirCreated {
+        #[serde(with = "crate::serde_path")]
+        path: PathBuf,
+    },
+
+    /// An empty dir was removed
+    DirRemoved {
+        #[serde(with = "crate::serde_path")]
+        path: PathBuf,
+    },
+
+    /// The permissions of an existing file were changed. Its old ones are in `mode`
+    ModeChanged {
+        #[serde(with = "crate::serde_path")]
+        path: PathBuf,
+        mode: u32,
+    },
+}
+
+/// Gives the permissions `mode` to the file in `path`
+#[cfg(unix)]
+fn restore_mode(path: &Path, mode: u32) -> anyhow::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    return fs::set_permissions(path, fs::Permissions::from_mode(mode))
+        .with_context(|| format!("Could not restore the mode of {}", path.display()));
+}
+
+/// Modes are only changed on unix systems, so there is nothing to restore
+#[cfg(not(unix))]
+fn restore_mode(_path: &Path, _mode: u32) -> anyhow::Result<()> {
+    return Ok(());
+}
+
+/// Ordered list of the changes made to the filesystem by an operation
+#[derive(Debug)]
+pub struct Journal {
+    /// Dir where the journal is persisted
+    /// If it is None, the journal is disabled and records nothing
+    location: Option<PathBuf>,
+
+    /// The changes, in the order they were made
+    entries: Vec<JournalEntry>,
+}
+
+impl Journal {
+
+    /// Creates a journal that records nothing, for operations that do not
+    /// need to be rolled back
+    pub fn disabled() -> Self {
+        return Self { location: None, entries: vec![] };
+    }
+
+    /// Starts a new empty journal persisted in `location`
+    /// A previous journal stored in that location is discarded if its
+    /// operation finished. Otherwise, it is the only way to restore the state
+    /// before that operation, so this fails until it is rolled back or discarded
+    pub fn start(location: &Path) -> anyhow::Result<Self> {
+        if Self::is_unfinished(location)? {
+            anyhow::bail!(
+                "There is a journal of an operation that did not finish at {}, roll it back or discard it first",
+                location.display(),
+            );
+        }
+
+        if location.exists() {
+            fs::remove_dir_all(location)
+                .with_context(|| format!("Could not discard previous journal at {}", location.display()))?;
+        }
+
+        fs::create_dir_all(location.join(BACKUPS_DIR))
+            .with_context(|| format!("Could not create journal dir {}", location.display()))?;
+
+        let journal = Self { location: Some(location.to_path_buf()), entries: vec![] };
+        journal.persist()?;
+        return Ok(journal);
+    }
+
+    /// Loads the journal persisted in `location`
+    /// Returns None if there is no journal in that location
+    pub fn load(location: &Path) -> anyhow::Result<Option<Self>> {
+        let entries_file = location.join(ENTRIES_FILE);
+        if !entries_file.exists() {
+            return Ok(None);
+        }
+
+        let data = fs::read_to_string(&entries_file)
+            .with_context(|| format!("Could not read journal {}", entries_file.display()))?;
+        let entries: Vec<JournalEntry> = serde_json::from_str(&data)
+            .with_context(|| format!("Journal {} is corrupted", entries_file.display()))?;
+
+        return Ok(Some(Self { location: Some(location.to_path_buf()), entries }));
+    }
+
+    /// Checks if the journal persisted in `location` belongs to an operation
+    /// that changed something and did not finish
+    pub fn is_unfinished(location: &Path) -> anyhow::Result<bool> {
+        let changed_something = match Self::load(location)? {
+            Some(journal) => !journal.is_empty(),
+            None => false,
+        };
+
+        return Ok(changed_something && !location.join(FINISHED_FILE).exists());
+    }
+
+    pub fn entries(&self) -> &Vec<JournalEntry> {
+        return &self.entries;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.entries.is_empty();
+    }
+
+    /// Records that the file in `path` is about to be written
+    /// If the file already exists, it is backed up first
+    pub fn record_file_write(&mut self, path: &Path) -> anyhow::Result<()> {
+        if self.location.is_none() {
+            return Ok(());
+        }
+
+        let entry = match fs::symlink_metadata(path) {
+            Ok(_) => JournalEntry::FileOverwritten { path: path.to_path_buf(), backup: self.backup(path)? },
+            Err(_) => JournalEntry::FileCreated { path: path.to_path_buf() },
+        };
+
+        return self.push(entry);
+    }
+
+    /// Records that the file in `path` is about to be removed, backing it up
+    pub fn record_file_removal(&mut self, path: &Path) -> anyhow::Result<()> {
+        if self.location.is_none() {
+            return Ok(());
+        }
+
+        let backup = self.backup(path)?;
+        return self.push(JournalEntry::FileRemoved { path: path.to_path_buf(), backup });
+    }
+
+    /// Records that the dir in `path` is about to be created
+    pub fn record_dir_creation(&mut self, path: &Path) -> anyhow::Result<()> {
+        return self.push(JournalEntry::DirCreated { path: path.to_path_buf() });
+    }
+
+    /// Records that the empty dir in `path` is about to be removed
+    pub fn record_dir_removal(&mut self, path: &Path) -> anyhow::Result<()> {
+        return self.push(JournalEntry::DirRemoved { path: path.to_path_buf() });
+    }
+
+    /// Records that the permissions of the file in `path`, which currently
+    /// are `old_mode`, are about to be changed
+    pub fn record_mode_change(&mut self, path: &Path, old_mode: u32) -> anyhow::Result<()> {
+        return self.push(JournalEntry::ModeChanged { path: path.to_path_buf(), mode: old_mode });
+    }
+
+    /// Marks the operation as finished, so the next `start` can replace this journal
+    pub fn finish(&self) -> anyhow::Result<()> {
+        let location = match &self.location {
+            Some(location) => location,
+            None => return Ok(()),
+        };
+
+        let marker = location.join(FINISHED_FILE);
+        return fs::write(&marker, "").with_context(|| format!("Could not write {}", marker.display()));
+    }
+
+    /// Undoes all the recorded changes, from the last one to the first one
+    pub fn rollback(&self) -> anyhow::Result<()> {
+        for entry in self.entries.iter().rev() {
+            match entry {
+                JournalEntry::FileCreated { path } => {
+                    if fs::symlink_metadata(path).is_ok() {
+                        fs::remove_file(path)
+                            .with_context(|| format!("Could not remove created file {}", path.display()))?;
+                    }
+                },
+
+                JournalEntry::FileOverwritten { path, backup } | JournalEntry::FileRemoved { path, backup } => {
+                    if let Some(parent) = path.parent() {
+                        fs::create_dir_all(parent)
+                            .with_context(|| format!("Could not create dir {}", parent.display()))?;
+                    }
+
+                    atomic_copy(backup, path)
+                        .with_context(|| format!("Could not restore {} from its backup", path.display()))?;
+                },
+
+                // Dirs that are not empty contain files that were not created
+                // by us, so they are left in place
+                JournalEntry::DirCreated { path } => {
+                    let _ = fs::remove_dir(path);
+                },
+
+                JournalEntry::DirRemoved { path } => {
+                    fs::create_dir_all(path)
+                        .with_context(|| format!("Could not restore dir {}", path.display()))?;
+                },
+
+                // Files created later in the operation were already removed
+                JournalEntry::ModeChanged { path, mode } => {
+                    if fs::symlink_metadata(path).is_ok() {
+                        restore_mode(path, *mode)?;
+                    }
+                },
+            }
+        }
+
+        return Ok(());
+    }
+
+    /// Removes the persisted journal and its backups
+    pub fn discard(self) -> anyhow::Result<()> {
+        if let Some(location) = &self.location {
+            fs::remove_dir_all(location)
+                .with_context(|| format!("Could not remove journal at {}", location.display()))?;
+        }
+
+        return Ok(());
+    }
+
+    /// Adds a new entry and persists the journal
+    fn push(&mut self, entry: JournalEntry) -> anyhow::Result<()> {
+        if self.location.is_none() {
+            return Ok(());
+        }
+
+        self.entries.push(entry);
+        return self.persist();
+    }
+
+    /// Copies the file in `path` to the backups dir, returning the path of
+    /// the copy
+    fn backup(&self, path: &Path) -> anyhow::Result<PathBuf> {
+        let location = self.location.as_ref().context("Disabled journals do not make backups")?;
+
+        let backup = location.join(BACKUPS_DIR).join(self.entries.len().to_string());
+        atomic_copy(path, &backup)
+            .with_context(|| format!("Could not back up {}", path.display()))?;
+
+        return Ok(backup);
+    }
+
+    /// Writes the entries to the journal dir
+    fn persist(&self) -> anyhow::Result<()> {
+        let location = match &self.location {
+            Some(location) => location,
+            None => return Ok(()),
+        };
+
+        let data = serde_json::to_string_pretty(&self.entries).context("Could not serialize the journal")?;
+        return atomic_write(&location.join(ENTRIES_FILE), data.as_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use super::{Journal, JournalEntry};
+    use crate::file_operations::sync_dir;
+    use crate::index::IndexStore;
+
+    #[test]
+    fn test_rollback_restores_previous_state() {
+        let base_path = Path::new("test_rollback_restores_previous_state");
+        let _ = fs::remove_dir_all(base_path);
+
+        // A repo dir with two files, and a system dir with an old version of one of them
+        fs::create_dir_all(base_path.join("repo")).unwrap();
+        fs::create_dir_all(base_path.join("system")).unwrap();
+        fs::write(base_path.join("repo/first.txt"), "new contents").unwrap();
+        fs::write(base_path.join("repo/second.txt"), "second").unwrap();
+        fs::write(base_path.join("system/first.txt"), "old contents").unwrap();
+        fs::write(base_path.join("system/only_in_system.txt"), "system").unwrap();
+
+        let mut journal = Journal::start(&base_path.join("journal")).unwrap();
+        let remove_files = true;
+        sync_dir(
+            &base_path.join("repo"),
+            &base_path.join("system"),
+            &vec![],
+            remove_files,
+            &mut journal,
+            &IndexStore::disabled(),
+        ).expect("Sync failed");
+
+        assert_eq!(fs::read_to_string(base_path.join("system/first.txt")).unwrap(), "new contents");
+        assert!(!base_path.join("system/only_in_system.txt").exists(), "File only in system was not removed");
+
+        // The journal can be loaded back from disk and rolled back
+        let journal = Journal::load(&base_path.join("journal")).unwrap().expect("Journal was not persisted");
+        assert!(journal.entries().contains(&JournalEntry::FileCreated { path: base_path.join("system/second.txt") }));
+        journal.rollback().expect("Rollback failed");
+
+        assert_eq!(fs::read_to_string(base_path.join("system/first.txt")).unwrap(), "old contents");
+        assert_eq!(fs::read_to_string(base_path.join("system/only_in_system.txt")).unwrap(), "system");
+        assert!(!base_path.join("system/second.txt").exists(), "Created file was not removed");
+
+        journal.discard().unwrap();
+        assert!(!base_path.join("journal").exists(), "Journal was not discarded");
+
+        fs::remove_dir_all(base_path).unwrap();
+    }
+
+    #[test]
+    fn test_unfinished_journal_is_not_replaced() {
+        let base_path = Path::new("test_unfinished_journal_is_not_replaced");
+        let _ = fs::remove_dir_all(base_path);
+        fs::create_dir_all(base_path).unwrap();
+        let location = base_path.join("journal");
+
+        // An operation that failed after changing something
+        let mut journal = Journal::start(&location).unwrap();
+        journal.record_dir_creation(&base_path.join("created")).unwrap();
+        assert!(Journal::is_unfinished(&location).unwrap());
+        assert!(Journal::start(&location).is_err(), "Unfinished journal was replaced");
+
+        // Once it finishes, the next operation replaces it
+        journal.finish().unwrap();
+        let journal = Journal::start(&location).unwrap();
+        assert!(journal.is_empty());
+
+        fs::remove_dir_all(base_path).unwrap();
+    }
+
+    #[test]
+    fn test_disabled_journal_records_nothing() {
+        let mut journal = Journal::disabled();
+        journal.record_file_write(Path::new("does/not/matter")).unwrap();
+        journal.record_dir_creation(Path::new("does/not/matter")).unwrap();
+        assert!(journal.is_empty());
+    }
+}
@@ -1,2 +1,11 @@
 pub mod file_operations;
+pub mod index;
+pub mod journal;
+pub mod merge;
+pub mod region;
+pub mod serde_path;
 pub use file_operations::*;
+pub use index::*;
+pub use journal::*;
+pub use merge::*;
+pub use region::*;
@@ -0,0 +1,416 @@
+//! Module to implement key-level merging of structured config files
+//!
+//! Some applications (VS Code, Firefox, ...) rewrite their whole settings file,
+//! so copying the repo version over it loses the keys managed by the
+//! application. Instead, only the keys present in the repo file are merged
+//! into the system file, and only those keys are written back to the repo
+//!
+//! Both files are parsed into a common tree (`serde_json::Value`), so the
+//! merge does not depend on the format. The system file is written back in
+//! its own format, keeping the order of its keys, but not its comments
+//!
+//! Json files can have comments and trailing commas (as VS Code settings
+//! do), which are dropped when the file is written back. Values of ini files
+//! that are booleans or numbers are read as such, so they match the values of
+//! other formats
+
+use std::fs;
+use std::path::Path;
+
+use anyhow::Context;
+use ini::Ini;
+use serde_json::{Map, Value};
+
+use crate::file_operations::{atomic_write, create_dir_all_recorded};
+use crate::journal::Journal;
+
+/// Formats of the config files that can be merged
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MergeFormat {
+    Json,
+    Toml,
+    Yaml,
+    Ini,
+}
+
+impl MergeFormat {
+    /// Detects the format of the file in `path` by its extension
+    pub fn from_path(path: &Path) -> anyhow::Result<Self> {
+        let extension = path.extension()
+            .and_then(|extension| extension.to_str())
+            .with_context(|| format!("Could not get the extension of {}", path.display()))?;
+
+        let format = match extension {
+            "json" => Self::Json,
+            "toml" => Self::Toml,
+            "yaml" | "yml" => Self::Yaml,
+            "ini" | "cfg" => Self::Ini,
+            other => anyhow::bail!("Extension '{}' of {} is not a supported format for merging", other, path.display()),
+        };
+
+        return Ok(format);
+    }
+
+    /// Parses `contents` into a tree
+    pub fn parse(&self, contents: &str) -> anyhow::Result<Value> {
+        let value = match self {
+            Self::Json => serde_json::from_str(&strip_json_comments(contents))?,
+            Self::Toml => toml::from_str(contents)?,
+            Self::Yaml => serde_yaml::from_str(contents)?,
+            Self::Ini => {
+                let ini = Ini::load_from_str(contents)?;
+                let mut root = Map::new();
+                for (section, properties) in ini.iter() {
+                    let properties: Map<String, Value> = properties.iter()
+                        .map(|(key, value)| (key.to_string(), ini_scalar(value)))
+                        .collect();
+
+                    match section {
+                        // Keys without section live at the top level
+                        None => root.extend(properties),
+                        Some(section) => {
+                            root.insert(section.to_string(), Value::Object(properties));
+                        },
+                    }
+                }
+                Value::Object(root)
+            },
+        };
+
+        return Ok(value);
+    }
+
+    /// Serializes `value` back into this format
+    pub fn serialize(&self, value: &Value) -> anyhow::Result<String> {
+        let contents = match self {
+            Self::Json => serde_json::to_string_pretty(value)? + "\n",
+            Self::Toml => toml::to_string_pretty(value)?,
+            Self::Yaml => serde_yaml::to_string(value)?,
+            Self::Ini => {
+                let root = value.as_object().context("The top level of an ini file must be a map")?;
+                let mut ini = Ini::new();
+                for (key, value) in root {
+                    match value {
+                        Value::Object(properties) => {
+                            for (property, value) in properties {
+                                ini.with_section(Some(key.as_str())).set(property.as_str(), ini_value(value));
+                            }
+                        },
+                        other => {
+                            ini.with_section(None::<String>).set(key.as_str(), ini_value(other));
+                        },
+                    }
+                }
+
+                let mut buffer = vec![];
+                ini.write_to(&mut buffer)?;
+                String::from_utf8(buffer)?
+            },
+        };
+
+        return Ok(contents);
+    }
+}
+
+/// Reads a value of an ini file as a boolean or a number when it is written
+/// as one, and as a string otherwise
+/// Values whose text would change when written back (as "01" or "1.50") are
+/// kept as strings
+fn ini_scalar(value: &str) -> Value {
+    let scalar = match value {
+        "true" => Value::Bool(true),
+        "false" => Value::Bool(false),
+        _ => match (value.parse::<i64>(), value.parse::<f64>()) {
+            (Ok(integer), _) => Value::from(integer),
+            (_, Ok(float)) => Value::from(float),
+            _ => return Value::String(value.to_string()),
+        },
+    };
+
+    if ini_value(&scalar) != value {
+        return Value::String(value.to_string());
+    }
+    return scalar;
+}
+
+/// Ini files only have string values
+fn ini_value(value: &Value) -> String {
+    return match value {
+        Value::String(string) => string.clone(),
+        other => other.to_string(),
+    };
+}
+
+/// Removes the `//` and `/* */` comments, and the trailing commas, of the
+/// json in `contents`, so json with comments (as VS Code settings) can be
+/// parsed
+/// Line breaks are kept, so parse errors point to the right line
+///
+/// # Examples
+/// ```
+/// use lib_fileops::strip_json_comments;
+/// let stripped = strip_json_comments("{\n  // Comment\n  \"url\": \"http://a\", /* other */\n}");
+/// assert_eq!(stripped, "{\n  \n  \"url\": \"http://a\" \n}");
+/// ```
+pub fn strip_json_comments(contents: &str) -> String {
+    let chars: Vec<char> = contents.chars().collect();
+    let mut stripped = String::with_capacity(contents.len());
+    let mut in_string = false;
+    let mut index = 0;
+
+    while index < chars.len() {
+        let current = chars[index];
+        let next = chars.get(index + 1).copied();
+
+        if in_string {
+            stripped.push(current);
+            match current {
+                '\\' => {
+                    if let Some(escaped) = next {
+                        stripped.push(escaped);
+                        index += 1;
+                    }
+                },
+                '"' => in_string = false,
+                _ => {},
+            }
+            index += 1;
+            continue;
+        }
+
+        match (current, next) {
+            ('"', _) => {
+                in_string = true;
+                stripped.push(current);
+                index += 1;
+            },
+            ('/', Some('/')) => {
+                while index < chars.len() && chars[index] != '\n' {
+                    index += 1;
+                }
+            },
+            ('/', Some('*')) => {
+                index += 2;
+                while index < chars.len() && !(chars[index] == '*' && chars.get(index + 1) == Some(&'/')) {
+                    if chars[index] == '\n' {
+                        stripped.push('\n');
+                    }
+                    index += 1;
+                }
+                index += 2;
+            },
+            (',', _) if matches!(next_significant(&chars, index + 1), Some('}' | ']')) => {
+                index += 1;
+            },
+            _ => {
+                stripped.push(current);
+                index += 1;
+            },
+        }
+    }
+
+    return stripped;
+}
+
+/// Next char from `start` on that is not whitespace nor part of a comment
+fn next_significant(chars: &[char], start: usize) -> Option<char> {
+    let mut index = start;
+    while index < chars.len() {
+        match (chars[index], chars.get(index + 1)) {
+            (current, _) if current.is_whitespace() => index += 1,
+            ('/', Some('/')) => {
+                while index < chars.len() && chars[index] != '\n' {
+                    index += 1;
+                }
+            },
+            ('/', Some('*')) => {
+                index += 2;
+                while index < chars.len() && !(chars[index] == '*' && chars.get(index + 1) == Some(&'/')) {
+                    index += 1;
+                }
+                index += 2;
+            },
+            (current, _) => return Some(current),
+        }
+    }
+
+    return None;
+}
+
+/// Merges the keys of `repo` into `system`, recursing into nested maps
+/// Values that are not maps (including lists) are replaced as a whole
+///
+/// # Examples
+/// ```
+/// use lib_fileops::deep_merge;
+/// use serde_json::json;
+/// let mut system = json!({"editor": {"fontSize": 12, "theme": "light"}, "telemetry": false});
+/// deep_merge(&mut system, &json!({"editor": {"theme": "dark"}}));
+/// assert_eq!(system, json!({"editor": {"fontSize": 12, "theme": "dark"}, "telemetry": false}));
+/// ```
+pub fn deep_merge(system: &mut Value, repo: &Value) {
+    match (system, repo) {
+        (Value::Object(system), Value::Object(repo)) => {
+            for (key, repo_value) in repo {
+                match system.get_mut(key) {
+                    Some(system_value) => deep_merge(system_value, repo_value),
+                    None => {
+                        system.insert(key.clone(), repo_value.clone());
+                    },
+                }
+            }
+        },
+        (system, repo) => *system = repo.clone(),
+    }
+}
+
+/// Takes from `system` only the keys that are present in `repo`, recursing
+/// into nested maps
+/// Managed keys that are no longer in `system` are dropped
+///
+/// # Examples
+/// ```
+/// use lib_fileops::managed_keys;
+/// use serde_json::json;
+/// let system = json!({"editor": {"fontSize": 12, "theme": "dark"}, "telemetry": false});
+/// let managed = managed_keys(&system, &json!({"editor": {"theme": "light"}}));
+/// assert_eq!(managed, json!({"editor": {"theme": "dark"}}));
+/// ```
+pub fn managed_keys(system: &Value, repo: &Value) -> Value {
+    let (system, repo) = match (system, repo) {
+        (Value::Object(system), Value::Object(repo)) => (system, repo),
+        (system, _) => return system.clone(),
+    };
+
+    let managed: Map<String, Value> = repo.iter()
+        .filter_map(|(key, repo_value)| system.get(key)
+            .map(|system_value| (key.clone(), managed_keys(system_value, repo_value)))
+        )
+        .collect();
+
+    return Value::Object(managed);
+}
+
+/// Reads and parses the config file in `path`
+/// If the file does not exist, it is taken as an empty map
+fn read_tree(path: &Path, format: MergeFormat) -> anyhow::Result<Value> {
+    if !path.exists() {
+        return Ok(Value::Object(Map::new()));
+    }
+
+    let contents = fs::read_to_string(path).with_context(|| format!("Could not read {}", path.display()))?;
+    return format.parse(&contents).with_context(|| format!("Could not parse {} as {:?}", path.display(), format));
+}
+
+/// Writes `contents` to `path` if they changed, creating the parent dirs
+/// The file is written atomically, and the changes are recorded in `journal`
+fn write_if_changed(path: &Path, contents: &str, journal: &mut Journal) -> anyhow::Result<()> {
+    if fs::read_to_string(path).ok().as_deref() == Some(contents) {
+        return Ok(());
+    }
+
+    if let Some(parent) = path.parent() {
+        create_dir_all_recorded(parent, journal)?;
+    }
+
+    journal.record_file_write(path)?;
+    return atomic_write(path, contents.as_bytes());
+}
+
+/// Merges the keys of the repo config file `from` into the system config file
+/// `to`, keeping every other key of `to`
+/// Formats are detected by extension, so both files can use different formats
+pub fn sync_merging_keys(from: &Path, to: &Path, journal: &mut Journal) -> anyhow::Result<()> {
+    let (from_format, to_format) = (MergeFormat::from_path(from)?, MergeFormat::from_path(to)?);
+
+    let repo = read_tree(from, from_format)?;
+    let mut system = read_tree(to, to_format)?;
+    deep_merge(&mut system, &repo);
+
+    let contents = to_format.serialize(&system)
+        .with_context(|| format!("Could not serialize the merged contents of {}", to.display()))?;
+    return write_if_changed(to, &contents, journal);
+}
+
+/// Writes back to the repo config file `to` the values that the keys it
+/// manages have in the system config file `from`
+/// Keys of `from` that are not in `to` are not written
+pub fn sync_managed_keys(from: &Path, to: &Path, journal: &mut Journal) -> anyhow::Result<()> {
+    let (from_format, to_format) = (MergeFormat::from_path(from)?, MergeFormat::from_path(to)?);
+
+    let system = read_tree(from, from_format)?;
+    let repo = read_tree(to, to_format)?;
+    let managed = managed_keys(&system, &repo);
+
+    let contents = to_format.serialize(&managed)
+        .with_context(|| format!("Could not serialize the managed keys of {}", from.display()))?;
+    return write_if_changed(to, &contents, journal);
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{deep_merge, managed_keys, MergeFormat};
+
+    #[test]
+    fn test_merge_keeps_unmanaged_keys() {
+        let mut system = json!({"a": 1, "nested": {"b": 2, "c": 3}, "list": [1, 2]});
+        let repo = json!({"nested": {"c": 30}, "list": [3], "new": true});
+        deep_merge(&mut system, &repo);
+
+        assert_eq!(system, json!({"a": 1, "nested": {"b": 2, "c": 30}, "list": [3], "new": true}));
+    }
+
+    #[test]
+    fn test_managed_keys_only_takes_repo_keys() {
+        let system = json!({"a": 1, "nested": {"b": 2, "c": 3}});
+        let repo = json!({"nested": {"c": 0}, "gone": 1});
+
+        assert_eq!(managed_keys(&system, &repo), json!({"nested": {"c": 3}}));
+    }
+
+    #[test]
+    fn test_formats_round_trip() {
+        let value = json!({"top": "level", "section": {"key": "value", "other": "thing"}});
+
+        for format in [MergeFormat::Json, MergeFormat::Toml, MergeFormat::Yaml, MergeFormat::Ini] {
+            let serialized = format.serialize(&value).unwrap();
+            let parsed = format.parse(&serialized).unwrap();
+            assert_eq!(parsed, value, "{:?} did not round trip", format);
+        }
+    }
+
+    #[test]
+    fn test_json_with_comments_is_parsed() {
+        let settings = r#"{
+    // Editor
+    "editor.fontSize": 14,
+    /* Links keep their "//" */
+    "http.proxy": "http://proxy:8080", // trailing comment
+    "files.exclude": {
+        "**/.git": true,
+    },
+}
+"#;
+        let parsed = MergeFormat::Json.parse(settings).unwrap();
+        assert_eq!(parsed, json!({"editor.fontSize": 14, "http.proxy": "http://proxy:8080", "files.exclude": {"**/.git": true}}));
+    }
+
+    #[test]
+    fn test_ini_scalars_round_trip() {
+        let value = json!({"section": {"enabled": true, "count": 1, "ratio": 0.5, "padded": "01", "name": "value"}});
+
+        let serialized = MergeFormat::Ini.serialize(&value).unwrap();
+        assert_eq!(MergeFormat::Ini.parse(&serialized).unwrap(), value);
+    }
+
+    #[test]
+    fn test_merge_keeps_key_order() {
+        let format = MergeFormat::Json;
+        let mut system = format.parse(r#"{"zeta": 1, "alpha": 2}"#).unwrap();
+        deep_merge(&mut system, &json!({"alpha": 3}));
+
+        assert_eq!(format.serialize(&system).unwrap(), "{\n  \"zeta\": 1,\n  \"alpha\": 3\n}\n");
+    }
+}
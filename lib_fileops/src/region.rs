@@ -0,0 +1,248 @@
+//! Module to implement managed regions inside files that punto does not own
+//! wholesale (for example, `/etc/hosts` or a `~/.bashrc` that installers
+//! also write to)
+//!
+//! A region is the text between a `# BEGIN punto <name>` line and a
+//! `# END punto <name>` line. Punto only touches the text of the region, and
+//! leaves the rest of the file untouched
+
+use std::fs;
+use std::path::Path;
+
+use anyhow::Context;
+
+use crate::file_operations::{atomic_write, create_dir_all_recorded};
+use crate::journal::Journal;
+
+/// Line that marks the start of the region `name`
+fn begin_marker(name: &str) -> String {
+    return format!("# BEGIN punto {}", name);
+}
+
+/// Line that marks the end of the region `name`
+fn end_marker(name: &str) -> String {
+    return format!("# END punto {}", name);
+}
+
+/// Byte offsets of the region of a file
+struct RegionSpan {
+    /// Start of the begin marker line
+    begin: usize,
+
+    /// Start of the first line after the begin marker
+    body_start: usize,
+
+    /// Start of the end marker line
+    body_end: usize,
+
+    /// End of the text of the end marker line, before its line ending
+    end_marker_end: usize,
+
+    /// Start of the first line after the end marker
+    end: usize,
+}
+
+/// Line ending used by `contents`, so new lines match the rest of the file
+fn line_ending(contents: &str) -> &'static str {
+    if contents.contains("\r\n") {
+        return "\r\n";
+    }
+    return "\n";
+}
+
+/// Finds the markers of region `name` in `contents`
+/// Returns None if the file has no such region
+fn find_region(contents: &str, name: &str) -> anyhow::Result<Option<RegionSpan>> {
+    let begin = begin_marker(name);
+    let end = end_marker(name);
+
+    // Every line with the offset where it starts, line endings included
+    let mut offset = 0;
+    let mut lines = contents.split_inclusive('\n').map(|line| {
+        let start = offset;
+        offset += line.len();
+        return (start, line);
+    });
+
+    let (begin_start, begin_line) = match lines.find(|(_, line)| line.trim_end() == begin) {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+
+    let (end_start, end_line) = lines
+        .find(|(_, line)| line.trim_end() == end)
+        .with_context(|| format!("Found '{}' but not its '{}'", begin, end))?;
+
+    return Ok(Some(RegionSpan {
+        begin: begin_start,
+        body_start: begin_start + begin_line.len(),
+        body_end: end_start,
+        end_marker_end: end_start + end_line.trim_end_matches(['\r', '\n']).len(),
+        end: end_start + end_line.len(),
+    }));
+}
+
+/// Puts `region` as the contents of the region `name` of `contents`
+/// If `contents` has no such region, it is appended at the end
+/// Only the region is rewritten, so the rest of `contents` keeps its bytes,
+/// line endings included
+///
+/// # Examples
+/// ```
+/// use lib_fileops::insert_region;
+/// let contents = "127.0.0.1 localhost\n";
+/// let inserted = insert_region(contents, "hosts", "0.0.0.0 ads.com\n").unwrap();
+/// assert_eq!(inserted, "127.0.0.1 localhost\n# BEGIN punto hosts\n0.0.0.0 ads.com\n# END punto hosts\n");
+/// ```
+pub fn insert_region(contents: &str, name: &str, region: &str) -> anyhow::Result<String> {
+    let newline = line_ending(contents);
+
+    let mut new_region = begin_marker(name) + newline;
+    for line in region.lines() {
+        new_region.push_str(line);
+        new_region.push_str(newline);
+    }
+    new_region.push_str(&end_marker(name));
+
+    let new_contents = match find_region(contents, name)? {
+        Some(span) => format!("{}{}{}", &contents[..span.begin], new_region, &contents[span.end_marker_end..]),
+
+        // The last line of the file might have no line ending
+        None if contents.is_empty() || contents.ends_with('\n') => format!("{}{}{}", contents, new_region, newline),
+        None => format!("{}{}{}{}", contents, newline, new_region, newline),
+    };
+
+    return Ok(new_contents);
+}
+
+/// Gets the contents of the region `name` of `contents`, without the markers
+/// Returns None if `contents` has no such region
+pub fn extract_region(contents: &str, name: &str) -> anyhow::Result<Option<String>> {
+    return Ok(find_region(contents, name)?.map(|span| contents[span.body_start..span.body_end].to_string()));
+}
+
+/// Removes the region `name`, markers included, from `contents`
+/// Returns None if `contents` has no such region
+fn without_region(contents: &str, name: &str) -> anyhow::Result<Option<String>> {
+    return Ok(find_region(contents, name)?.map(|span| format!("{}{}", &contents[..span.begin], &contents[span.end..])));
+}
+
+/// Writes the whole contents of the file `from` as the region `name` of the
+/// file `to`, leaving the rest of `to` untouched
+/// `to` (and its parent dirs) are created if they do not exist
+/// The file is written atomically, and the changes are recorded in `journal`
+pub fn sync_into_region(from: &Path, to: &Path, name: &str, journal: &mut Journal) -> anyhow::Result<()> {
+    let region = fs::read_to_string(from).with_context(|| format!("Could not read {}", from.display()))?;
+
+    let old_contents = match to.exists() {
+        true => fs::read_to_string(to).with_context(|| format!("Could not read {}", to.display()))?,
+        false => String::new(),
+    };
+
+    let new_contents = insert_region(&old_contents, name, &region)
+        .with_context(|| format!("Could not insert region {} in {}", name, to.display()))?;
+    if to.exists() && new_contents == old_contents {
+        return Ok(());
+    }
+
+    if let Some(parent) = to.parent() {
+        create_dir_all_recorded(parent, journal)?;
+    }
+
+    journal.record_file_write(to)?;
+    return atomic_write(to, new_contents.as_bytes());
+}
+
+/// Writes the region `name` of the file `from` as the whole contents of the
+/// file `to`
+/// Fails if `from` has no such region
+/// The file is written atomically, and the changes are recorded in `journal`
+pub fn sync_from_region(from: &Path, to: &Path, name: &str, journal: &mut Journal) -> anyhow::Result<()> {
+    let contents = fs::read_to_string(from).with_context(|| format!("Could not read {}", from.display()))?;
+    let region = extract_region(&contents, name)?
+        .with_context(|| format!("File {} has no region {}", from.display(), name))?;
+
+    if to.exists() && fs::read_to_string(to).ok().as_deref() == Some(region.as_str()) {
+        return Ok(());
+    }
+
+    if let Some(parent) = to.parent() {
+        create_dir_all_recorded(parent, journal)?;
+    }
+
+    journal.record_file_write(to)?;
+    return atomic_write(to, region.as_bytes());
+}
+
+/// Removes the region `name`, markers included, from the file in `path`
+/// Returns false if the file has no such region
+pub fn remove_region(path: &Path, name: &str) -> anyhow::Result<bool> {
+    if !path.exists() {
+        return Ok(false);
+    }
+
+    let contents = fs::read_to_string(path).with_context(|| format!("Could not read {}", path.display()))?;
+    let remaining = match without_region(&contents, name)? {
+        Some(remaining) => remaining,
+        None => return Ok(false),
+    };
+
+    atomic_write(path, remaining.as_bytes())?;
+    return Ok(true);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{extract_region, insert_region, without_region};
+
+    #[test]
+    fn test_insert_region_replaces_only_the_region() {
+        let contents = "before\n# BEGIN punto hosts\nold line\n# END punto hosts\nafter\n";
+        let inserted = insert_region(contents, "hosts", "new line\nother new line\n").unwrap();
+
+        let expected = "before\n# BEGIN punto hosts\nnew line\nother new line\n# END punto hosts\nafter\n";
+        assert_eq!(inserted, expected, "Text outside of the region was modified");
+    }
+
+    #[test]
+    fn test_regions_with_other_names_are_not_touched() {
+        let contents = "# BEGIN punto other\nother line\n# END punto other\n";
+        let inserted = insert_region(contents, "hosts", "line\n").unwrap();
+
+        assert_eq!(extract_region(&inserted, "other").unwrap().unwrap(), "other line\n");
+        assert_eq!(extract_region(&inserted, "hosts").unwrap().unwrap(), "line\n");
+    }
+
+    #[test]
+    fn test_extract_and_remove_region() {
+        let contents = "before\n# BEGIN punto hosts\nline\n# END punto hosts\nafter\n";
+
+        assert_eq!(extract_region(contents, "hosts").unwrap(), Some("line\n".to_string()));
+        assert_eq!(extract_region(contents, "missing").unwrap(), None);
+        assert_eq!(without_region(contents, "hosts").unwrap(), Some("before\nafter\n".to_string()));
+    }
+
+    #[test]
+    fn test_text_outside_the_region_keeps_its_bytes() {
+        let before = "127.0.0.1 localhost\r\n";
+        let after = "\r\n::1 localhost";
+        let contents = format!("{}# BEGIN punto hosts\r\nold line\r\n# END punto hosts{}", before, after);
+
+        // CRLF line endings and the missing final newline are kept
+        let inserted = insert_region(&contents, "hosts", "new line\n").unwrap();
+        assert_eq!(inserted, format!("{}# BEGIN punto hosts\r\nnew line\r\n# END punto hosts{}", before, after));
+        assert_eq!(extract_region(&inserted, "hosts").unwrap().unwrap(), "new line\r\n");
+        assert_eq!(without_region(&inserted, "hosts").unwrap().unwrap(), format!("{}::1 localhost", before));
+
+        // Appended regions do not change the bytes that were already there
+        let appended = insert_region("127.0.0.1 localhost\r\n::1 localhost", "hosts", "line\n").unwrap();
+        assert_eq!(appended, "127.0.0.1 localhost\r\n::1 localhost\r\n# BEGIN punto hosts\r\nline\r\n# END punto hosts\r\n");
+    }
+
+    #[test]
+    fn test_unterminated_region_is_an_error() {
+        let contents = "# BEGIN punto hosts\nline\n";
+        assert!(extract_region(contents, "hosts").is_err(), "Region without end marker was accepted");
+        assert!(insert_region(contents, "hosts", "line\n").is_err(), "Region without end marker was accepted");
+    }
+}
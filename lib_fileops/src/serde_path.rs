@@ -0,0 +1,115 @@
+//! Module to (de)serialize paths that might not be valid UTF-8
+//!
+//! serde refuses to serialize a `PathBuf` that is not valid UTF-8, and punto
+//! stores the paths of synced files in its state (journals, indexes, ...). So
+//! paths are stored as strings when they are valid UTF-8, and as raw bytes
+//! otherwise
+//!
+//! Use it with `#[serde(with = "lib_fileops::serde_path")]`, or with
+//! `#[serde(with = "lib_fileops::serde_path::vec")]` for `Vec<PathBuf>`
+
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// How a path is stored
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum StoredPath {
+    Utf8(String),
+    Bytes(Vec<u8>),
+}
+
+impl From<&Path> for StoredPath {
+    fn from(path: &Path) -> Self {
+        if let Some(utf8) = path.to_str() {
+            return StoredPath::Utf8(utf8.to_string());
+        }
+
+        #[cfg(unix)]
+        return StoredPath::Bytes(std::os::unix::ffi::OsStrExt::as_bytes(path.as_os_str()).to_vec());
+
+        // Other platforms can not build a path from raw bytes
+        #[cfg(not(unix))]
+        return StoredPath::Utf8(path.to_string_lossy().to_string());
+    }
+}
+
+impl From<StoredPath> for PathBuf {
+    fn from(stored: StoredPath) -> Self {
+        return match stored {
+            StoredPath::Utf8(utf8) => PathBuf::from(utf8),
+
+            #[cfg(unix)]
+            StoredPath::Bytes(bytes) => PathBuf::from(<std::ffi::OsString as std::os::unix::ffi::OsStringExt>::from_vec(bytes)),
+
+            #[cfg(not(unix))]
+            StoredPath::Bytes(bytes) => PathBuf::from(String::from_utf8_lossy(&bytes).to_string()),
+        };
+    }
+}
+
+pub fn serialize<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+    return StoredPath::from(path).serialize(serializer);
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PathBuf, D::Error> {
+    return Ok(StoredPath::deserialize(deserializer)?.into());
+}
+
+/// Same as the parent module, for lists of paths
+pub mod vec {
+    use std::path::PathBuf;
+
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use super::StoredPath;
+
+    pub fn serialize<S: Serializer>(paths: &[PathBuf], serializer: S) -> Result<S::Ok, S::Error> {
+        let stored: Vec<StoredPath> = paths.iter().map(|path| StoredPath::from(path.as_path())).collect();
+        return stored.serialize(serializer);
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<PathBuf>, D::Error> {
+        let stored = Vec::<StoredPath>::deserialize(deserializer)?;
+        return Ok(stored.into_iter().map(PathBuf::from).collect());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct WithPaths {
+        #[serde(with = "super")]
+        path: PathBuf,
+
+        #[serde(with = "super::vec")]
+        paths: Vec<PathBuf>,
+    }
+
+    #[test]
+    fn test_utf8_paths_are_stored_as_strings() {
+        let value = WithPaths { path: PathBuf::from("some/path"), paths: vec![PathBuf::from("other")] };
+        let json = serde_json::to_string(&value).unwrap();
+
+        assert_eq!(json, r#"{"path":"some/path","paths":["other"]}"#);
+        assert_eq!(serde_json::from_str::<WithPaths>(&json).unwrap(), value);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_non_utf8_paths_round_trip() {
+        use std::ffi::OsString;
+        use std::os::unix::ffi::OsStringExt;
+
+        let non_utf8 = PathBuf::from(OsString::from_vec(vec![b'f', 0xff, b'o']));
+        let value = WithPaths { path: non_utf8.clone(), paths: vec![non_utf8] };
+        let json = serde_json::to_string(&value).unwrap();
+
+        assert_eq!(serde_json::from_str::<WithPaths>(&json).unwrap(), value);
+    }
+}
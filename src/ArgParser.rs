@@ -1,14 +1,21 @@
 /// Parses the cli arguments given by the user
 
+use std::path::Path;
+
 use crate::DirSync;
 use crate::Commands;
 use crate::Installer;
+use crate::Converter;
+use crate::Migrations;
+use crate::ConfigLoader::ConfigFormat;
+use crate::Errors::PuntoError;
 use clap::{App, Arg, ArgMatches};
 
 /// Parses the args and launchs commands depending on user input
-pub fn parse_args_and_launch_commands() {
+/// Stops at the first command that fails
+pub fn parse_args_and_launch_commands() -> Result<(), PuntoError> {
     let matches = generate_matches();
-    call_handlers(matches);
+    return call_handlers(matches);
 }
 
 /// Generates the matches structure, defining inputs by hand
@@ -24,8 +31,8 @@ fn generate_matches() -> ArgMatches<'static>{
             Arg::with_name("shell command")
                 .short("-s")
                 .long("--shell")
-                .value_name("yaml_file")
-                .help("Launchs shell commands from yaml file")
+                .value_name("config_file")
+                .help("Launchs shell commands from config file")
                 .takes_value(true),
         )
 
@@ -34,8 +41,8 @@ fn generate_matches() -> ArgMatches<'static>{
             Arg::with_name("install command")
                 .short("-i")
                 .long("--install")
-                .value_name("yaml_file")
-                .help("Installs packages from yaml file")
+                .value_name("config_file")
+                .help("Installs packages from config file")
         )
 
         // Download dotfiles from repo to system
@@ -43,7 +50,7 @@ fn generate_matches() -> ArgMatches<'static>{
             Arg::with_name("download command")
                 .short("-d")
                 .long("--download")
-                .value_name("yaml_file")
+                .value_name("config_file")
                 .help("Syncs files and dirs from repo to your system ")
                 .takes_value(true),
         )
@@ -53,7 +60,7 @@ fn generate_matches() -> ArgMatches<'static>{
             Arg::with_name("upload command")
                 .short("-u")
                 .long("--upload")
-                .value_name("yaml_file")
+                .value_name("config_file")
                 .help("Syncs files and dirs from your system to repo")
                 .takes_value(true),
         )
@@ -64,16 +71,171 @@ fn generate_matches() -> ArgMatches<'static>{
             .long("--section")
             .value_name("section")
             .help(
-                "Specify the package section to install (by default all sections of the file are installed) \nCan only be used when using --install"
+                "Specify a package section to install (by default all sections of the file are installed). Can be given many times, and can be a glob as 'desktop-*' \nCan only be used when using --install or --capture-packages"
             )
             .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+        )
+
+        // Sections to leave out of the install
+        .arg(
+            Arg::with_name("skip install section")
+            .long("--skip-section")
+            .value_name("section")
+            .help("Leaves a package section out of the install. Can be given many times, and can be a glob \nCan only be used when using --install")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .requires("install command")
+        )
+
+        // List the sections instead of installing them
+        .arg(
+            Arg::with_name("list install sections")
+            .long("--list-sections")
+            .help("Lists the sections in the order they are installed, without installing anything \nCan only be used when using --install")
+            .takes_value(false)
+            .requires("install command")
+            .conflicts_with_all(&["install status", "install prune"])
+        )
+
+        // Show installed packages instead of installing them
+        .arg(
+            Arg::with_name("install status")
+            .long("--status")
+            .help("Shows which packages are installed, missing or unknown, without installing anything \nCan only be used when using --install")
+            .takes_value(false)
+            .requires("install command")
+        )
+
+        // Profiles that installer sections can require
+        .arg(
+            Arg::with_name("install profile")
+            .long("--profile")
+            .value_name("profile")
+            .help("Activates a profile, for the sections whose `when` requires it. Can be given many times \nCan only be used when using --install")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .requires("install command")
+        )
+
+        // Check the installed versions against the lock file
+        .arg(
+            Arg::with_name("install locked")
+            .long("--locked")
+            .help("Fails if an installed version differs from packages.lock, without changing it \nCan only be used when using --install")
+            .takes_value(false)
+            .requires("install command")
+        )
+
+        // Record the installed versions in the lock file
+        .arg(
+            Arg::with_name("install update lock")
+            .long("--update-lock")
+            .help("Writes the installed versions to packages.lock, replacing the locked ones \nCan only be used when using --install")
+            .takes_value(false)
+            .requires("install command")
+            .conflicts_with("install locked")
+        )
+
+        // Install again the packages that failed
+        .arg(
+            Arg::with_name("install retry failed")
+            .long("--retry-failed")
+            .help("Installs only the packages that failed to install the last time \nCan only be used when using --install")
+            .takes_value(false)
+            .requires("install command")
+            .conflicts_with("install update lock")
+        )
+
+        // Remove packages that are not in the installer file
+        .arg(
+            Arg::with_name("install prune")
+            .long("--prune")
+            .help("Removes, after asking, the installed packages that no section declares, instead of installing anything \nCan only be used when using --install")
+            .takes_value(false)
             .requires("install command")
+            .conflicts_with_all(&["install status", "specify install section", "skip install section"])
+        )
+
+        // Write the installed packages as an installer section
+        .arg(
+            Arg::with_name("capture packages")
+            .long("--capture-packages")
+            .value_name("backend")
+            .help("Prints an installer section with the packages explicitly installed with a backend. The section is named as the backend, unless --section is given")
+            .takes_value(true)
+        )
+
+        // Installer file where captured packages are merged
+        .arg(
+            Arg::with_name("capture into")
+            .long("--into")
+            .value_name("config_file")
+            .help("Adds the captured packages that are not declared yet to the installer file, keeping its comments \nCan only be used when using --capture-packages")
+            .takes_value(true)
+            .requires("capture packages")
+        )
+
+        // Format of the config file, when its extension does not tell it
+        .arg(
+            Arg::with_name("config format")
+                .long("--format")
+                .value_name("format")
+                .help("Format of the config file (yaml, toml or json). By default, it is picked by the file extension")
+                .possible_values(&ConfigFormat::NAMES)
+                .takes_value(true),
+        )
+
+        // Undo the last download
+        .arg(
+            Arg::with_name("rollback command")
+                .long("--rollback")
+                .help("Restores your system to the state it had before the last download")
+                .takes_value(false),
+        )
+
+        // Forget the last download, keeping the system as it is
+        .arg(
+            Arg::with_name("discard journal command")
+                .long("--discard-journal")
+                .help("Forgets the journal of the last download, so it can no longer be rolled back")
+                .takes_value(false),
+        )
+
+        // Remove the files deployed by a block
+        .arg(
+            Arg::with_name("undeploy command")
+                .long("--undeploy")
+                .value_name("block")
+                .help("Removes the files deployed by a dir block, restoring the ones that existed before punto")
+                .takes_value(true),
+        )
+
+        // Write a config file in other format
+        .arg(
+            Arg::with_name("convert command")
+                .long("--convert")
+                .value_names(&["input_file", "output_file"])
+                .help("Writes a config file in the format given by the extension of output_file (yaml, toml or json)")
+                .number_of_values(2),
+        )
+
+        // Rewrite a config file for the current config version
+        .arg(
+            Arg::with_name("migrate command")
+                .long("--migrate")
+                .value_name("config_file")
+                .help("Rewrites a config file written for an old config version, keeping the old file in config_file.bak")
+                .takes_value(true),
         )
 
         .arg(
             Arg::with_name("check dir sync problems")
             .long("--check")
-            .value_name("yaml_file")
+            .value_name("config_file")
             .help(
                 "Checks for dir sync problems. Searches for files deleted in a repo (or system) dir that are still present in their system (or repo) dir"
             )
@@ -86,28 +248,98 @@ fn generate_matches() -> ArgMatches<'static>{
 }
 
 /// Calls the functions given the cli parameters
-fn call_handlers(matches: ArgMatches) {
+fn call_handlers(matches: ArgMatches) -> Result<(), PuntoError> {
     for arg in matches.args.iter() {
         let arg_name = arg.0;
         if matches.is_present(arg_name) {
-            let yaml_file = matches.value_of(arg_name).unwrap();
+
+            // Flags that do not take a file
+            if arg_name == &"rollback command" {
+                DirSync::handle_rollback()?;
+                continue;
+            }
+            if arg_name == &"discard journal command" {
+                DirSync::handle_discard_journal()?;
+                continue;
+            }
+
+            // --section only selects sections to install or capture
+            if arg_name == &"specify install section" && !matches.is_present("install command") && !matches.is_present("capture packages") {
+                println!("--section can only be used with --install or --capture-packages");
+                continue;
+            }
+
+            // Options that modify other commands
+            let modifiers = [
+                "specify install section", "skip install section", "list install sections", "install status", "install prune",
+                "install profile", "install locked", "install update lock", "install retry failed", "capture into", "config format",
+            ];
+            if modifiers.contains(arg_name) {
+                continue;
+            }
+
+            // Commands that take a backend
+            if arg_name == &"capture packages" {
+                let section = matches.value_of("specify install section");
+                let into = matches.value_of_os("capture into").map(Path::new);
+                let format = matches.value_of("config format").and_then(ConfigFormat::from_name);
+                Installer::handle_capture(matches.value_of(arg_name).unwrap(), section, into, format)?;
+                continue;
+            }
+
+            // Config file paths do not need to be valid UTF-8
+            let config_file = Path::new(matches.value_of_os(arg_name).unwrap());
+            let format = matches.value_of("config format").and_then(ConfigFormat::from_name);
+
+            // Commands that take two files
+            if arg_name == &"convert command" {
+                let output_file = Path::new(matches.values_of_os(arg_name).unwrap().nth(1).unwrap());
+                Converter::handle_convert(config_file, output_file, format)?;
+                continue;
+            }
 
             match arg_name {
-                &"shell command" => Commands::handle_shell_command(yaml_file),
+                &"shell command" => Commands::handle_shell_command(config_file, format),
                 &"install command" => {
 
-                    // Check if we passed --section parameter
-                    let section = matches.value_of("specify install section");
+                    // Sections picked with --section, --skip-section and --profile
+                    let values = |name: &str| -> Vec<&str> { matches.values_of(name).map(Iterator::collect).unwrap_or_default() };
+                    let selection = Installer::SectionSelection {
+                        sections: values("specify install section"),
+                        skip_sections: values("skip install section"),
+                        profiles: values("install profile"),
+                    };
 
-                    // We launch the installer using this parameter (which can be None)
-                    Installer::handle_install_command(yaml_file, section);
+                    if matches.is_present("install status") {
+                        Installer::handle_status(config_file, format, &selection)
+                    } else if matches.is_present("list install sections") {
+                        Installer::handle_list_sections(config_file, format, &selection)
+                    } else if matches.is_present("install prune") {
+                        Installer::handle_prune(config_file, format)
+                    } else {
+                        let lock_mode = if matches.is_present("install locked") {
+                            Installer::LockMode::Locked
+                        } else if matches.is_present("install update lock") {
+                            Installer::LockMode::Update
+                        } else {
+                            Installer::LockMode::Record
+                        };
+                        Installer::handle_install_command(config_file, format, &selection, lock_mode, matches.is_present("install retry failed"))
+                    }
                 },
 
-                &"download command" => DirSync::handle_download(yaml_file),
-                &"upload command" => DirSync::handle_upload(yaml_file),
-                &"check dir sync problems" => DirSync::handle_check(yaml_file),
-                _ => println!("Command not recognized"),
-            }
+                &"download command" => DirSync::handle_download(config_file, format),
+                &"upload command" => DirSync::handle_upload(config_file, format),
+                &"undeploy command" => DirSync::handle_undeploy(&matches.value_of_lossy(arg_name).unwrap()),
+                &"check dir sync problems" => DirSync::handle_check(config_file, format),
+                &"migrate command" => Migrations::handle_migrate(config_file, format),
+                _ => {
+                    println!("Command not recognized");
+                    Ok(())
+                },
+            }?;
         }
     }
+
+    return Ok(());
 }
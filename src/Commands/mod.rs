@@ -2,7 +2,11 @@ mod parsers;
 
 use lib_commands::{SingleCommand, SingleCommandError};
 
-use crate::Commands::parsers::{YamlCommandsParser, ParseCommandsFile};
+use std::path::Path;
+
+use crate::Commands::parsers::{CommandsParser, ParseCommandsFile};
+use crate::ConfigLoader::ConfigFormat;
+use crate::Errors::{self, PuntoError};
 
 /// Represent a group of commands to execute in sequence
 /// If one command fails, the rest of the commands won't be executed
@@ -47,14 +51,33 @@ impl CommandBlock {
 
 
 /// Handler to --shell cli argument
-/// Reads the shell yaml config file and executes commands described in the yaml file
-// TODO -- handle exceptions
-pub fn handle_shell_command(yaml_file: &str) {
-    println!("Running shell commands defined in shell.yaml");
+/// Reads the shell config file and executes commands described in it
+/// A failing command block does not stop the rest of blocks
+pub fn handle_shell_command(file_path: &Path, format: Option<ConfigFormat>) -> Result<(), PuntoError> {
+    println!("Running shell commands defined in {}", file_path.display());
     println!("================================================================================");
-    let command_blocks = YamlCommandsParser::parse_file(yaml_file).unwrap();
-    for command in command_blocks {
-        command.execute();
+    let command_blocks = CommandsParser::parse_file(file_path, format)
+        .map_err(|err| PuntoError::config(file_path, err))?;
+
+    let mut failed_blocks = vec![];
+    for command in &command_blocks {
+        if let Err(err) = command.execute() {
+            eprintln!("❌ Command block {} failed: {}", command.description, err);
+            failed_blocks.push(command.description.clone());
+        }
+
+        if Errors::interrupted() {
+            return Err(PuntoError::UserAbort);
+        }
     }
-}
 
+    if failed_blocks.is_empty() {
+        return Ok(());
+    }
+
+    let source = anyhow::anyhow!("Command blocks that failed: {}", failed_blocks.join(", "));
+    if failed_blocks.len() == command_blocks.len() {
+        return Err(PuntoError::total_failure("Running shell commands", source));
+    }
+    return Err(PuntoError::partial_failure("Running shell commands", source));
+}
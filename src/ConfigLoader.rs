@@ -0,0 +1,420 @@
+//! Module where config files are loaded and composed, before being parsed
+//! into the structs of each command
+//!
+//! Any config file (directories, installer or shell) can:
+//!     - Include other config files with `include:`, a path or a list of
+//!       paths relative to the including file. Included blocks come before
+//!       the blocks of the including file, and on conflicts, the including
+//!       file wins
+//!     - Give a `defaults:` section, whose fields are inherited by every block
+//!       that does not set them. Defaults of included files are merged too,
+//!       and they apply to every block of the composed config
+//!
+//! Blocks are the map entries at the top level of the file (installer
+//! sections and shell command blocks), and the entries of the `directories`
+//! list of directories configs
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use serde_json::{Map, Value};
+
+use crate::Diagnostics::{Diagnostic, Location};
+use crate::Migrations;
+
+/// Key to include other config files
+const INCLUDE_KEY: &str = "include";
+
+/// Key of the section with the default values of the blocks
+const DEFAULTS_KEY: &str = "defaults";
+
+/// Key of the package managers declared in installer configs
+pub const BACKENDS_KEY: &str = "backends";
+
+/// Keys that are not blocks, at the top level of any config file
+pub const RESERVED_KEYS: [&str; 3] = [INCLUDE_KEY, DEFAULTS_KEY, BACKENDS_KEY];
+
+/// Key of the list of blocks in directories configs
+const DIRECTORIES_KEY: &str = "directories";
+
+/// Formats that config files can be written in
+/// All of them are loaded into the same tree, so every config kind has the
+/// same semantics in any format
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConfigFormat {
+    Yaml,
+    Toml,
+    Json,
+}
+
+impl ConfigFormat {
+    /// Names of the formats, as given in `--format`
+    pub const NAMES: [&'static str; 3] = ["yaml", "toml", "json"];
+
+    /// Gets the format from its name, or from a file extension
+    pub fn from_name(name: &str) -> Option<Self> {
+        return match name {
+            "yaml" | "yml" => Some(Self::Yaml),
+            "toml" => Some(Self::Toml),
+            "json" => Some(Self::Json),
+            _ => None,
+        };
+    }
+
+    /// Detects the format of the file in `path` by its extension
+    pub fn from_path(path: &Path) -> Option<Self> {
+        return path.extension()
+            .and_then(|extension| extension.to_str())
+            .and_then(Self::from_name);
+    }
+
+    /// Parses `contents` of the file `path` into a tree
+    /// Syntax errors point to where they are in the file
+    fn parse(&self, path: &Path, contents: &str) -> Result<Value, Diagnostic> {
+        return match self {
+            Self::Yaml => serde_yaml::from_str(contents).map_err(|err| {
+                let location = err.location()
+                    .map(|location| Location::from_offset(path, contents, location.index(), 1));
+                Diagnostic::new(without_position(&err.to_string())).at(location)
+            }),
+            Self::Toml => toml::from_str(contents).map_err(|err| {
+                let location = err.span()
+                    .map(|span| Location::from_offset(path, contents, span.start, span.len()));
+                Diagnostic::new(err.message().trim()).at(location)
+            }),
+            Self::Json => serde_json::from_str(contents).map_err(|err| {
+                let location = Location::from_line_column(path, contents, err.line(), err.column(), 1);
+                Diagnostic::new(without_position(&err.to_string())).at(Some(location))
+            }),
+        };
+    }
+
+    /// Writes `value` as a config file in this format
+    pub fn serialize(&self, value: &Value) -> anyhow::Result<String> {
+        let contents = match self {
+            Self::Yaml => serde_yaml::to_string(value)?,
+            Self::Toml => toml::to_string_pretty(value)?,
+            Self::Json => serde_json::to_string_pretty(value)? + "\n",
+        };
+
+        return Ok(contents);
+    }
+}
+
+/// Loads the config file in `path`, resolving its includes and applying its
+/// defaults to every block
+/// `format` overrides the format given by the extension of the file. Included
+/// files use their own extension, or the format of the including file if
+/// they have none
+pub fn load(path: &Path, format: Option<ConfigFormat>) -> anyhow::Result<Value> {
+    return Ok(load_with_sources(path, format)?.0);
+}
+
+/// Same as `load`, also returning the files that the config was loaded from,
+/// so errors found later can be located in them. Each file comes before the
+/// files it includes
+pub fn load_with_sources(path: &Path, format: Option<ConfigFormat>) -> anyhow::Result<(Value, Vec<PathBuf>)> {
+    let mut sources = vec![];
+    let mut composed = load_with_includes(path, format, &mut vec![], &mut sources)?;
+
+    if let Value::Object(root) = &mut composed {
+        if let Some(defaults) = take_key(root, DEFAULTS_KEY) {
+            let defaults = match defaults {
+                Value::Object(defaults) => defaults,
+                _ => anyhow::bail!("The `{}` section must be a map", DEFAULTS_KEY),
+            };
+            apply_defaults(root, &defaults);
+        }
+    }
+
+    return Ok((composed, sources));
+}
+
+/// Removes `key` from `map`, keeping the order of the rest of keys
+pub fn take_key(map: &mut Map<String, Value>, key: &str) -> Option<Value> {
+    let mut taken = None;
+    *map = std::mem::take(map)
+        .into_iter()
+        .filter_map(|(curr_key, value)| {
+            if curr_key == key {
+                taken = Some(value);
+                return None;
+            }
+            return Some((curr_key, value));
+        })
+        .collect();
+
+    return taken;
+}
+
+/// Removes every " at line 3 column 5" of yaml and json errors, as
+/// diagnostics show where the error is by themselves
+fn without_position(message: &str) -> String {
+    let mut message = message.to_string();
+    let mut searched = 0;
+    while let Some(found) = message[searched..].find(" at line ") {
+        let start = searched + found;
+        let rest = &message[start + " at line ".len()..];
+        let line_digits = rest.len() - rest.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+        let column = match rest[line_digits..].strip_prefix(" column ") {
+            Some(column) if line_digits > 0 => column,
+            _ => {
+                searched = start + 1;
+                continue;
+            },
+        };
+        let column_digits = column.len() - column.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+        let end = message.len() - column.len() + column_digits;
+        message.replace_range(start..end, "");
+        searched = start;
+    }
+
+    return message;
+}
+
+/// Format of the config file in `path`: `format` when given, otherwise the
+/// one of its extension
+pub fn format_of(path: &Path, format: Option<ConfigFormat>) -> anyhow::Result<ConfigFormat> {
+    return format.or_else(|| ConfigFormat::from_path(path))
+        .with_context(|| format!(
+            "Could not get the format of {} from its extension, use one of {} or pass --format",
+            path.display(),
+            ConfigFormat::NAMES.join(", "),
+        ));
+}
+
+/// Reads and parses a single config file in `format`, without resolving its
+/// includes or defaults
+pub fn read_file(path: &Path, format: ConfigFormat) -> anyhow::Result<Value> {
+    let contents = fs::read_to_string(path).with_context(|| format!("Could not read {}", path.display()))?;
+    let value = format.parse(path, &contents)?;
+
+    // Empty yaml files are null
+    if value == Value::Null {
+        return Ok(Value::Object(Map::new()));
+    }
+
+    return Ok(value);
+}
+
+/// Loads the config file in `path` with all its includes resolved
+/// `format` is used when given, otherwise the extension of the file picks it
+/// `stack` holds the files that are being included, to detect cycles
+/// `sources` gets every loaded file
+fn load_with_includes(path: &Path, format: Option<ConfigFormat>, stack: &mut Vec<PathBuf>, sources: &mut Vec<PathBuf>) -> anyhow::Result<Value> {
+    let canonical = path.canonicalize().with_context(|| format!("Could not find config file {}", path.display()))?;
+    if stack.contains(&canonical) {
+        let cycle: Vec<String> = stack.iter()
+            .chain(std::iter::once(&canonical))
+            .map(|path| path.display().to_string())
+            .collect();
+        anyhow::bail!("Found an include cycle: {}", cycle.join(" -> "));
+    }
+
+    let format = format_of(path, format)?;
+    let mut value = read_file(path, format)?;
+    sources.push(path.to_path_buf());
+    let root = match &mut value {
+        Value::Object(root) => root,
+        _ => return Ok(value),
+    };
+
+    // Each file can be written for a different version
+    Migrations::upgrade_with_warning(path, root)?;
+
+    let includes = match take_key(root, INCLUDE_KEY) {
+        None => vec![],
+        Some(Value::String(include)) => vec![include],
+        Some(Value::Array(includes)) => includes.into_iter()
+            .map(|include| match include {
+                Value::String(include) => Ok(include),
+                other => Err(anyhow::anyhow!("Included files must be paths, found {}", other)),
+            })
+            .collect::<anyhow::Result<Vec<String>>>()?,
+        Some(other) => anyhow::bail!("`{}` must be a path or a list of paths, found {}", INCLUDE_KEY, other),
+    };
+
+    if includes.is_empty() {
+        return Ok(value);
+    }
+
+    // Paths are relative to the including file
+    let parent = path.parent().unwrap_or_else(|| Path::new(""));
+
+    stack.push(canonical);
+    let mut composed = Map::new();
+    for include in includes {
+        let included_path = parent.join(&include);
+        let included_format = ConfigFormat::from_path(&included_path).unwrap_or(format);
+        let included = load_with_includes(&included_path, Some(included_format), stack, sources)
+            .with_context(|| format!("Could not include {} from {}", include, path.display()))?;
+        match included {
+            Value::Object(included) => merge_configs(&mut composed, included),
+            _ => anyhow::bail!("Included file {} is not a map", include),
+        }
+    }
+    stack.pop();
+
+    merge_configs(&mut composed, std::mem::take(root));
+    return Ok(Value::Object(composed));
+}
+
+/// Merges the config `other` into `base`
+/// Lists of dir blocks are concatenated, defaults and backends are merged
+/// key by key, and any other key of `other` replaces the one in `base`
+fn merge_configs(base: &mut Map<String, Value>, other: Map<String, Value>) {
+    for (key, value) in other {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Array(base_blocks)), Value::Array(blocks)) if key == DIRECTORIES_KEY => {
+                base_blocks.extend(blocks);
+            },
+            (Some(Value::Object(base_entries)), Value::Object(entries)) if key == DEFAULTS_KEY || key == BACKENDS_KEY => {
+                base_entries.extend(entries);
+            },
+            (_, value) => {
+                // Overridden blocks keep their place
+                base.insert(key, value);
+            },
+        }
+    }
+}
+
+/// Sets the `defaults` fields that are missing in every block of `root`
+fn apply_defaults(root: &mut Map<String, Value>, defaults: &Map<String, Value>) {
+    for (key, value) in root.iter_mut() {
+        match value {
+            Value::Array(blocks) if key == DIRECTORIES_KEY => {
+                // Each entry of the list is a map with the block name as its only key
+                for entry in blocks.iter_mut() {
+                    if let Value::Object(entry) = entry {
+                        for block in entry.values_mut() {
+                            inherit_defaults(block, defaults);
+                        }
+                    }
+                }
+            },
+            Value::Object(_) if !RESERVED_KEYS.contains(&key.as_str()) => inherit_defaults(value, defaults),
+            _ => (),
+        }
+    }
+}
+
+/// Sets in `block` the fields of `defaults` that it does not have
+fn inherit_defaults(block: &mut Value, defaults: &Map<String, Value>) {
+    if let Value::Object(block) = block {
+        for (key, value) in defaults {
+            if !block.contains_key(key) {
+                block.insert(key.clone(), value.clone());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use serde_json::json;
+
+    use super::{load, ConfigFormat};
+
+    #[test]
+    fn test_includes_and_defaults() {
+        let base_path = Path::new("test_includes_and_defaults");
+        let _ = fs::remove_dir_all(base_path);
+        fs::create_dir_all(base_path.join("common")).unwrap();
+
+        fs::write(base_path.join("common/shared.yaml"), "
+defaults:
+    ignore_files: ['*.swp', '.DS_Store']
+directories:
+    - nvim:
+        repo_path: nvim
+        system_path: .config/nvim
+").unwrap();
+        fs::write(base_path.join("directories.yaml"), "
+include: common/shared.yaml
+repo_base: /repo
+defaults:
+    sync_type: dir
+directories:
+    - git:
+        sync_type: file
+        ignore_files: []
+        repo_path: gitconfig
+        system_path: .gitconfig
+").unwrap();
+
+        let loaded = load(&base_path.join("directories.yaml"), None).expect("Could not load the config");
+        assert_eq!(loaded, json!({
+            "directories": [
+                {"nvim": {"repo_path": "nvim", "system_path": ".config/nvim", "ignore_files": ["*.swp", ".DS_Store"], "sync_type": "dir"}},
+                {"git": {"sync_type": "file", "ignore_files": [], "repo_path": "gitconfig", "system_path": ".gitconfig"}},
+            ],
+            "repo_base": "/repo",
+        }));
+
+        fs::remove_dir_all(base_path).unwrap();
+    }
+
+    #[test]
+    fn test_include_cycles_are_detected() {
+        let base_path = Path::new("test_include_cycles_are_detected");
+        let _ = fs::remove_dir_all(base_path);
+        fs::create_dir_all(base_path).unwrap();
+
+        fs::write(base_path.join("first.yaml"), "include: second.yaml\nsection: {}\n").unwrap();
+        fs::write(base_path.join("second.yaml"), "include: [first.yaml]\n").unwrap();
+
+        let err = load(&base_path.join("first.yaml"), None).expect_err("Include cycle was not detected");
+        assert!(format!("{:#}", err).contains("include cycle"), "Unexpected error: {:#}", err);
+
+        fs::remove_dir_all(base_path).unwrap();
+    }
+
+    #[test]
+    fn test_formats_load_the_same() {
+        let base_path = Path::new("test_formats_load_the_same");
+        let _ = fs::remove_dir_all(base_path);
+        fs::create_dir_all(base_path).unwrap();
+
+        fs::write(base_path.join("packages.yaml"), "aur:\n    install_command: paru -S\n    sudo: false\n    packages: [bat]\n").unwrap();
+        fs::write(base_path.join("packages.toml"), "[aur]\ninstall_command = 'paru -S'\nsudo = false\npackages = ['bat']\n").unwrap();
+        fs::write(base_path.join("packages.json"), r#"{"aur": {"install_command": "paru -S", "sudo": false, "packages": ["bat"]}}"#).unwrap();
+
+        // Files without extension need the format to be given
+        fs::write(base_path.join("packages"), "aur:\n    install_command: paru -S\n    sudo: false\n    packages: [bat]\n").unwrap();
+        assert!(load(&base_path.join("packages"), None).is_err(), "File without extension was loaded without a format");
+
+        let expected = json!({"aur": {"install_command": "paru -S", "sudo": false, "packages": ["bat"]}});
+        for file in ["packages.yaml", "packages.toml", "packages.json"] {
+            assert_eq!(load(&base_path.join(file), None).unwrap(), expected, "{} was not loaded as the others", file);
+        }
+        assert_eq!(load(&base_path.join("packages"), Some(ConfigFormat::Yaml)).unwrap(), expected);
+
+        fs::remove_dir_all(base_path).unwrap();
+    }
+
+    #[test]
+    fn test_syntax_errors_are_located() {
+        let base_path = Path::new("test_syntax_errors_are_located");
+        let _ = fs::remove_dir_all(base_path);
+        fs::create_dir_all(base_path).unwrap();
+
+        let files = [
+            ("bad.yaml", "aur:\n    packages: [bat\n    sudo: false\n", "bad.yaml:3:"),
+            ("bad.toml", "[aur]\nsudo = flase\npackages = ['bat']\n", "bad.toml:2:"),
+            ("bad.json", "{\"aur\": {\n    \"sudo\": false,\n}}", "bad.json:3:"),
+        ];
+        for (file, contents, location) in files {
+            fs::write(base_path.join(file), contents).unwrap();
+            let err = format!("{:#}", load(&base_path.join(file), None).unwrap_err());
+            assert!(err.contains(location), "{} was not located in: {}", location, err);
+            assert!(!err.contains(" at line "), "Position given twice in: {}", err);
+        }
+
+        fs::remove_dir_all(base_path).unwrap();
+    }
+}
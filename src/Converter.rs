@@ -0,0 +1,121 @@
+//! Converts config files between the supported formats
+//!
+//! Every config kind has the same schema in any format, so a file is
+//! converted by writing its tree in the target format, for the current
+//! config version. Its `include` and `defaults` sections are kept as they
+//! are, not resolved
+
+use std::fs;
+use std::path::Path;
+
+use anyhow::Context;
+use serde_json::Value;
+
+use crate::ConfigLoader::{self, ConfigFormat};
+use crate::Errors::PuntoError;
+use crate::Migrations;
+use crate::Schemas;
+
+/// Callback for --convert cli arg
+/// # Arguments
+/// - `input`: directories, installer or shell config file to convert
+/// - `output`: file to write the converted config to. Its extension picks the
+///   target format, and it must not exist
+/// - `format`: overrides the format given by the extension of `input`
+pub fn handle_convert(input: &Path, output: &Path, format: Option<ConfigFormat>) -> Result<(), PuntoError> {
+    let (kind, contents) = convert(input, output, format)
+        .map_err(|err| PuntoError::config(input, err))?;
+
+    write_new_file(output, &contents)
+        .map_err(|err| PuntoError::total_failure("Converting config file", err))?;
+
+    println!("🔁 Converted {} config {} to {}", kind, input.display(), output.display());
+    return Ok(());
+}
+
+/// Checks that `input` is a valid config, and writes it in the format of
+/// `output`. Returns the kind of the config, along with the converted contents
+fn convert(input: &Path, output: &Path, format: Option<ConfigFormat>) -> anyhow::Result<(&'static str, String)> {
+    let output_format = ConfigFormat::from_path(output)
+        .with_context(|| format!(
+            "Could not get the target format from the extension of {}, use one of {}",
+            output.display(),
+            ConfigFormat::NAMES.join(", "),
+        ))?;
+
+    // Included files and defaults are part of the config being checked
+    let kind = Schemas::config_kind(&ConfigLoader::load(input, format)?)?;
+
+    // Converted files are written for the current version
+    let input_format = ConfigLoader::format_of(input, format)?;
+    let mut config = match ConfigLoader::read_file(input, input_format)? {
+        Value::Object(config) => config,
+        _ => anyhow::bail!("Config files must be maps"),
+    };
+    Migrations::upgrade(&mut config)?;
+    let contents = output_format.serialize(&Value::Object(Migrations::with_current_version(config)))
+        .with_context(|| format!("Could not write it as {:?}", output_format))?;
+
+    return Ok((kind, contents));
+}
+
+/// Writes `contents` to `path`, that must not exist, so no file is lost
+fn write_new_file(path: &Path, contents: &str) -> anyhow::Result<()> {
+    if path.exists() {
+        anyhow::bail!("{} already exists, remove it or pick other file", path.display());
+    }
+
+    return fs::write(path, contents).with_context(|| format!("Could not write {}", path.display()));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use serde_json::Value;
+
+    use super::convert;
+    use crate::ConfigLoader;
+    use crate::Migrations;
+
+    #[test]
+    fn test_conversions_keep_blocks_and_their_order() {
+        let base_path = Path::new("test_conversions_keep_blocks_and_their_order");
+        let _ = fs::remove_dir_all(base_path);
+        fs::create_dir_all(base_path).unwrap();
+
+        fs::write(base_path.join("dirs.yaml"), "
+repo_base: /repo
+defaults:
+    sync_type: dir
+directories:
+    - zsh:
+        repo_path: ./zsh
+        system_path: .config/zsh
+    - alacritty:
+        repo_path: ./alacritty
+        system_path: .config/alacritty
+        mode: '0600'
+").unwrap();
+        // Converted files get the current version
+        let original = ConfigLoader::read_file(&base_path.join("dirs.yaml"), ConfigLoader::ConfigFormat::Yaml).unwrap();
+        let original = Value::Object(Migrations::with_current_version(original.as_object().unwrap().clone()));
+
+        for target in ["dirs.toml", "dirs.json", "dirs.yml"] {
+            let (kind, contents) = convert(&base_path.join("dirs.yaml"), &base_path.join(target), None).unwrap();
+            assert_eq!(kind, "directories");
+            fs::write(base_path.join(target), contents).unwrap();
+
+            // Converting back and forth gives the same config, in the same order
+            let converted = ConfigLoader::read_file(&base_path.join(target), ConfigLoader::ConfigFormat::from_path(Path::new(target)).unwrap()).unwrap();
+            assert_eq!(serde_json::to_string(&converted).unwrap(), serde_json::to_string(&original).unwrap(), "{} is not the same config", target);
+        }
+
+        // Files that are not configs are not converted
+        fs::write(base_path.join("other.yaml"), "name: punto\n").unwrap();
+        assert!(convert(&base_path.join("other.yaml"), &base_path.join("other.toml"), None).is_err());
+
+        fs::remove_dir_all(base_path).unwrap();
+    }
+}
@@ -0,0 +1,287 @@
+//! Errors of config files pointing to where they are in the file
+//!
+//! A `Diagnostic` is rendered with the file, line and column of the error,
+//! the offending line, and a hint when there is one:
+//!
+//! ```text
+//! unknown field `sytem_path` in block `nvim`
+//!   --> directories.yaml:7:9
+//!    |
+//!  7 |         sytem_path: .config/nvim
+//!    |         ^^^^^^^^^^
+//!    = help: did you mean `system_path`?
+//! ```
+//!
+//! Loaded configs do not keep where each value came from, so errors found
+//! after loading are located by searching their block and key in the files
+//! that were loaded
+
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::Schemas::{SchemaError, SchemaErrorKind};
+
+/// An error in a config file, with where it is and how to fix it
+#[derive(Debug)]
+pub struct Diagnostic {
+    message: String,
+    location: Option<Location>,
+    hint: Option<String>,
+}
+
+/// Where an error is in a config file
+#[derive(Debug, PartialEq)]
+pub struct Location {
+    file: PathBuf,
+
+    /// Line and column, starting at 1
+    line: usize,
+    column: usize,
+
+    /// Line of the file where the error is
+    snippet: String,
+
+    /// Number of chars of the snippet that are marked
+    width: usize,
+}
+
+impl Diagnostic {
+    pub fn new(message: impl Into<String>) -> Self {
+        return Self { message: message.into(), location: None, hint: None };
+    }
+
+    pub fn at(mut self, location: Option<Location>) -> Self {
+        self.location = location;
+        return self;
+    }
+
+    pub fn with_hint(mut self, hint: Option<String>) -> Self {
+        self.hint = hint;
+        return self;
+    }
+
+    /// Error of a config that does not follow its schema
+    /// `sources` are the files the config was loaded from
+    pub fn from_schema_error(err: SchemaError, sources: &[PathBuf]) -> Self {
+        let block = err.block.as_deref();
+        let in_block = match block {
+            Some(block) => format!(" in block `{}`", block),
+            None => String::new(),
+        };
+
+        return match err.kind {
+            SchemaErrorKind::UnknownField { field, expected } => {
+                let hint = did_you_mean(&field, &expected);
+                Self::new(format!("unknown field `{}`{}", field, in_block))
+                    .at(locate_key(sources, block, &field))
+                    .with_hint(hint.or_else(|| Some(format!("valid fields are {}", quoted_list(&expected)))))
+            },
+            SchemaErrorKind::MissingField { field } => Self::new(format!("missing field `{}`{}", field, in_block))
+                .at(block.and_then(|block| locate_key(sources, None, block))),
+            SchemaErrorKind::Other(message) => Self::new(format!("{}{}", message, in_block))
+                .at(block.and_then(|block| locate_key(sources, None, block))),
+        };
+    }
+}
+
+impl std::error::Error for Diagnostic {}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+
+        let line_number = self.location.as_ref().map(|location| location.line.to_string()).unwrap_or_default();
+        let gutter = " ".repeat(line_number.len());
+        if let Some(location) = &self.location {
+            let marker_offset: String = location.snippet
+                .chars()
+                .take(location.column - 1)
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect();
+
+            write!(f, "\n{}--> {}:{}:{}", gutter, location.file.display(), location.line, location.column)?;
+            write!(f, "\n{} |", gutter)?;
+            write!(f, "\n{} | {}", line_number, location.snippet)?;
+            write!(f, "\n{} | {}{}", gutter, marker_offset, "^".repeat(location.width.max(1)))?;
+        }
+
+        if let Some(hint) = &self.hint {
+            write!(f, "\n{} = help: {}", gutter, hint)?;
+        }
+
+        return Ok(());
+    }
+}
+
+impl Location {
+    /// Location of the byte `offset` of `contents`, the contents of `file`
+    pub fn from_offset(file: &Path, contents: &str, offset: usize, width: usize) -> Self {
+        let offset = offset.min(contents.len());
+        let line_start = contents[..offset].rfind('\n').map(|index| index + 1).unwrap_or(0);
+        let line = contents[..line_start].matches('\n').count() + 1;
+        let column = contents[line_start..offset].chars().count() + 1;
+
+        return Self::from_line_column(file, contents, line, column, width);
+    }
+
+    /// Location of `line` and `column`, starting at 1, of `contents`
+    pub fn from_line_column(file: &Path, contents: &str, line: usize, column: usize, width: usize) -> Self {
+        let snippet = contents.lines().nth(line.saturating_sub(1)).unwrap_or("").to_string();
+
+        // Marks do not go past the end of the line
+        let width = width.min(snippet.chars().count().saturating_sub(column.saturating_sub(1)));
+
+        return Self {
+            file: file.to_path_buf(),
+            line: line.max(1),
+            column: column.max(1),
+            snippet,
+            width,
+        };
+    }
+}
+
+/// Suggests the candidate closest to `word`, if it is close enough to be a typo
+pub fn did_you_mean(word: &str, candidates: &[String]) -> Option<String> {
+    return candidates
+        .iter()
+        .map(|candidate| (strsim::levenshtein(word, candidate), candidate))
+        .filter(|(distance, candidate)| *distance <= (candidate.len() / 3).max(1))
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| format!("did you mean `{}`?", candidate));
+}
+
+/// Formats `items` as "`a`, `b` or `c`"
+fn quoted_list(items: &[String]) -> String {
+    let quoted: Vec<String> = items.iter().map(|item| format!("`{}`", item)).collect();
+    return match quoted.split_last() {
+        Some((last, rest)) if !rest.is_empty() => format!("{} or {}", rest.join(", "), last),
+        _ => quoted.join(""),
+    };
+}
+
+/// Finds `key` in the files of `sources`, after the line where `block` is
+/// given when there is one
+/// Files are searched in order, and the files that include others come
+/// before their includes in `sources`, as they win on conflicts
+pub fn locate_key(sources: &[PathBuf], block: Option<&str>, key: &str) -> Option<Location> {
+    return locate(sources, block, |line| key_span(line, key));
+}
+
+/// Finds the text `text` in the files of `sources`, after the line where
+/// `block` is given when there is one
+pub fn locate_text(sources: &[PathBuf], block: Option<&str>, text: &str) -> Option<Location> {
+    return locate(sources, block, |line| line.find(text).map(|start| (start, text.chars().count())));
+}
+
+fn locate(sources: &[PathBuf], block: Option<&str>, find: impl Fn(&str) -> Option<(usize, usize)>) -> Option<Location> {
+    for file in sources {
+        let contents = match fs::read_to_string(file) {
+            Ok(contents) => contents,
+            Err(_) => continue,
+        };
+
+        let mut offset = 0;
+        let mut in_block = block.is_none();
+        for line in contents.split_inclusive('\n') {
+            if !in_block {
+                in_block = key_span(line, block.unwrap_or("")).is_some();
+            } else if let Some((start, width)) = find(line) {
+                return Some(Location::from_offset(file, &contents, offset + start, width));
+            }
+
+            offset += line.len();
+        }
+    }
+
+    return None;
+}
+
+/// Byte where `key` starts in `line` and its width in chars, when the line
+/// gives that key, in any config format:
+///     `key: value` or `- key:` (yaml), `"key": value` (json),
+///     `key = value` or `[parent.key]` (toml)
+fn key_span(line: &str, key: &str) -> Option<(usize, usize)> {
+    if key.is_empty() {
+        return None;
+    }
+
+    let trimmed = line.trim_start();
+    let indent = line.len() - trimmed.len();
+
+    // Toml table headers
+    if trimmed.starts_with('[') {
+        let header = trimmed.trim_start_matches('[');
+        let end = header.find(']')?;
+        let last = header[..end].rsplit('.').next()?.trim();
+        let last = last.trim_matches(|c| c == '"' || c == '\'');
+        if last != key {
+            return None;
+        }
+
+        let start = line.rfind(key)?;
+        return Some((start, key.chars().count()));
+    }
+
+    let item = trimmed.strip_prefix("- ").unwrap_or(trimmed);
+    let start = indent + (trimmed.len() - item.len());
+    let (quote_width, after_key) = match item.strip_prefix('"').or_else(|| item.strip_prefix('\'')) {
+        Some(unquoted) => (1, unquoted.strip_prefix(key)?.get(1..)?),
+        None => (0, item.strip_prefix(key)?),
+    };
+
+    let after_key = after_key.trim_start();
+    if !after_key.starts_with(':') && !after_key.starts_with('=') {
+        return None;
+    }
+
+    return Some((start + quote_width, key.chars().count()));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    use super::{did_you_mean, locate_key, Diagnostic};
+    use crate::Schemas::{self, DirectoriesFile};
+
+    #[test]
+    fn test_schema_errors_point_to_the_key() {
+        let base_path = Path::new("test_schema_errors_point_to_the_key");
+        let _ = fs::remove_dir_all(base_path);
+        fs::create_dir_all(base_path).unwrap();
+
+        let yaml = base_path.join("dirs.yaml");
+        fs::write(&yaml, "repo_base: /repo\ndirectories:\n    - nvim:\n        repo_path: ./nvim\n        sytem_path: .config/nvim\n").unwrap();
+        let toml = base_path.join("dirs.toml");
+        fs::write(&toml, "repo_base = '/repo'\n\n[[directories]]\n[directories.nvim]\nrepo_path = './nvim'\nsytem_path = '.config/nvim'\n").unwrap();
+
+        for (file, line, column) in [(&yaml, 5, 9), (&toml, 6, 1)] {
+            let loaded = crate::ConfigLoader::load(file, None).unwrap();
+            let err = Schemas::from_loaded::<DirectoriesFile>(loaded).unwrap_err();
+            let diagnostic = Diagnostic::from_schema_error(err, &[PathBuf::from(file)]);
+
+            let location = diagnostic.location.as_ref().unwrap();
+            assert_eq!((location.line, location.column), (line, column), "Bad location in {}", file.display());
+            let rendered = diagnostic.to_string();
+            assert!(rendered.starts_with("unknown field `sytem_path` in block `nvim`"), "Got: {}", rendered);
+            assert!(rendered.contains("did you mean `system_path`?"), "Got: {}", rendered);
+        }
+
+        // Keys of other blocks are not taken
+        fs::write(&yaml, "directories:\n    - vim:\n        mode: 1\n    - nvim:\n        mode: 2\n").unwrap();
+        assert_eq!(locate_key(std::slice::from_ref(&yaml), Some("nvim"), "mode").unwrap().line, 5);
+
+        fs::remove_dir_all(base_path).unwrap();
+    }
+
+    #[test]
+    fn test_did_you_mean() {
+        let fields = vec!["repo_path".to_string(), "system_path".to_string(), "sync_type".to_string()];
+        assert_eq!(did_you_mean("sytem_path", &fields), Some("did you mean `system_path`?".to_string()));
+        assert_eq!(did_you_mean("sink_type", &fields), Some("did you mean `sync_type`?".to_string()));
+        assert_eq!(did_you_mean("packages", &fields), None);
+    }
+}
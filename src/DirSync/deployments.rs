@@ -0,0 +1,257 @@
+//! Module where we keep track of the files that each `DirBlock` has deployed
+//! into the system, so they can be removed later on
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use lib_fileops::{atomic_copy, atomic_write, remove_region, JournalEntry};
+
+/// Name of the file, inside the deployments dir, that stores the deployments
+const DEPLOYMENTS_FILE: &str = "deployments.json";
+
+/// Name of the dir, inside the deployments dir, that stores pre-punto backups
+const BACKUPS_DIR: &str = "backups";
+
+/// A file that punto has placed in the system
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DeployedFile {
+    /// Absolute path of the file in the system
+    #[serde(with = "lib_fileops::serde_path")]
+    path: PathBuf,
+
+    /// Copy of the file as it was before punto deployed it for the first time
+    /// None if the file did not exist before punto deployed it
+    backup: Option<PathBuf>,
+}
+
+/// Everything a single `DirBlock` has placed in the system
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct DeployedBlock {
+    files: Vec<DeployedFile>,
+
+    /// Dirs that did not exist before punto deployed the block
+    #[serde(with = "lib_fileops::serde_path::vec")]
+    created_dirs: Vec<PathBuf>,
+
+    /// System files where the block has deployed a region named after it
+    #[serde(default, with = "lib_fileops::serde_path::vec")]
+    regions: Vec<PathBuf>,
+}
+
+impl DeployedBlock {
+    pub fn files(&self) -> &Vec<DeployedFile> {
+        return &self.files;
+    }
+
+    pub fn regions(&self) -> &Vec<PathBuf> {
+        return &self.regions;
+    }
+}
+
+impl DeployedFile {
+    pub fn path(&self) -> &PathBuf {
+        return &self.path;
+    }
+}
+
+/// On-disk representation of `Deployments`
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct DeploymentsRepresentation {
+    /// The directories config file whose blocks are tracked
+    #[serde(with = "lib_fileops::serde_path")]
+    config: PathBuf,
+
+    /// Index for the next backup file, so backups never collide
+    next_backup: usize,
+
+    blocks: BTreeMap<String, DeployedBlock>,
+}
+
+/// Tracks, for a single directories config file, what each of its blocks has
+/// deployed into the system
+#[derive(Debug)]
+pub struct Deployments {
+    /// Dir where the deployments are persisted
+    location: PathBuf,
+
+    repr: DeploymentsRepresentation,
+}
+
+impl Deployments {
+
+    /// Loads the deployments persisted in `location`
+    /// If nothing was deployed yet, returns empty deployments for `config`
+    pub fn load(location: &Path, config: &Path) -> anyhow::Result<Self> {
+        let deployments_file = location.join(DEPLOYMENTS_FILE);
+        if !deployments_file.exists() {
+            let repr = DeploymentsRepresentation { config: config.to_path_buf(), ..Default::default() };
+            return Ok(Self { location: location.to_path_buf(), repr });
+        }
+
+        let data = fs::read_to_string(&deployments_file)
+            .with_context(|| format!("Could not read deployments file {}", deployments_file.display()))?;
+        let repr = serde_json::from_str(&data)
+            .with_context(|| format!("Deployments file {} is corrupted", deployments_file.display()))?;
+
+        return Ok(Self { location: location.to_path_buf(), repr });
+    }
+
+    /// Loads the deployments of every config file, stored in subdirs of `location`
+    pub fn load_all(location: &Path) -> anyhow::Result<Vec<Self>> {
+        let mut all_deployments = vec![];
+        if !location.exists() {
+            return Ok(all_deployments);
+        }
+
+        for entry in fs::read_dir(location).with_context(|| format!("Could not read dir {}", location.display()))? {
+            let entry = entry.with_context(|| format!("Could not read an entry of {}", location.display()))?;
+            if !entry.path().join(DEPLOYMENTS_FILE).exists() {
+                continue;
+            }
+
+            let deployments = Self::load(&entry.path(), Path::new(""))?;
+            all_deployments.push(deployments);
+        }
+
+        return Ok(all_deployments);
+    }
+
+    pub fn config(&self) -> &PathBuf {
+        return &self.repr.config;
+    }
+
+    pub fn block(&self, name: &str) -> Option<&DeployedBlock> {
+        return self.repr.blocks.get(name);
+    }
+
+    /// Records the files that the block `name` has just deployed
+    ///
+    /// `journal_entries` are the changes made to the system while deploying
+    /// the block. They are used to find out which files existed before punto
+    /// deployed them for the first time, and to back up their original contents
+    pub fn record_block(&mut self, name: &str, files: Vec<PathBuf>, journal_entries: &[JournalEntry]) -> anyhow::Result<()> {
+        let mut block = self.repr.blocks.get(name).cloned().unwrap_or_default();
+
+        for path in files {
+            if block.files.iter().any(|deployed| deployed.path == path) {
+                continue;
+            }
+
+            // Find out what was in the system before this first deployment
+            let previous = journal_entries.iter().find_map(|entry| match entry {
+                JournalEntry::FileCreated { path: created } if created == &path => Some(None),
+                JournalEntry::FileOverwritten { path: overwritten, backup } if overwritten == &path => Some(Some(backup.clone())),
+                _ => None,
+            });
+
+            let backup = match previous {
+                Some(None) => None,
+                Some(Some(journal_backup)) => Some(self.store_backup(&journal_backup)?),
+
+                // The file was already in the system with the same contents
+                None => Some(self.store_backup(&path)?),
+            };
+
+            block.files.push(DeployedFile { path, backup });
+        }
+
+        for entry in journal_entries {
+            if let JournalEntry::DirCreated { path } = entry {
+                if !block.created_dirs.contains(path) {
+                    block.created_dirs.push(path.clone());
+                }
+            }
+        }
+
+        self.repr.blocks.insert(name.to_string(), block);
+        return self.persist();
+    }
+
+    /// Records that the block `name` has deployed its region into the file
+    /// in `path`
+    /// The region is persisted along with the rest of the block by `record_block`
+    pub fn record_region(&mut self, name: &str, path: PathBuf) {
+        let block = self.repr.blocks.entry(name.to_string()).or_default();
+        if !block.regions.contains(&path) {
+            block.regions.push(path);
+        }
+    }
+
+    /// Blocks that have deployed files but are not in `current_blocks`
+    pub fn orphans(&self, current_blocks: &[&String]) -> Vec<(&String, &DeployedBlock)> {
+        return self.repr.blocks.iter()
+            .filter(|(name, _)| !current_blocks.contains(name))
+            .collect();
+    }
+
+    /// Removes from the system every file deployed by the block `name`,
+    /// restoring the pre-punto version of the files that had one
+    /// Returns false if the block has not deployed anything
+    pub fn undeploy(&mut self, name: &str) -> anyhow::Result<bool> {
+        let block = match self.block(name) {
+            Some(block) => block.clone(),
+            None => return Ok(false),
+        };
+
+        for file in &block.files {
+            match &file.backup {
+                Some(backup) => {
+                    println!("--> Restoring {}", file.path.display());
+                    atomic_copy(backup, &file.path)
+                        .with_context(|| format!("Could not restore the pre-punto version of {}", file.path.display()))?;
+                    fs::remove_file(backup)
+                        .with_context(|| format!("Could not remove backup {}", backup.display()))?;
+                },
+                None => {
+                    println!("--> Removing {}", file.path.display());
+                    if fs::symlink_metadata(&file.path).is_ok() {
+                        fs::remove_file(&file.path)
+                            .with_context(|| format!("Could not remove {}", file.path.display()))?;
+                    }
+                },
+            }
+        }
+
+        // The rest of the file is not ours, so only the region is removed
+        for path in &block.regions {
+            println!("--> Removing region {} of {}", name, path.display());
+            remove_region(path, name)
+                .with_context(|| format!("Could not remove region {} of {}", name, path.display()))?;
+        }
+
+        // Deepest dirs first. Dirs that are not empty contain files that were
+        // not deployed by punto, so they are left in place
+        for dir in block.created_dirs.iter().rev() {
+            let _ = fs::remove_dir(dir);
+        }
+
+        self.repr.blocks.remove(name);
+        self.persist()?;
+        return Ok(true);
+    }
+
+    /// Copies `path` to the backups dir, returning the path of the copy
+    fn store_backup(&mut self, path: &Path) -> anyhow::Result<PathBuf> {
+        let backups_dir = self.location.join(BACKUPS_DIR);
+        fs::create_dir_all(&backups_dir)
+            .with_context(|| format!("Could not create dir {}", backups_dir.display()))?;
+
+        let backup = backups_dir.join(self.repr.next_backup.to_string());
+        self.repr.next_backup += 1;
+
+        atomic_copy(path, &backup).with_context(|| format!("Could not back up {}", path.display()))?;
+        return Ok(backup);
+    }
+
+    /// Writes the deployments to disk
+    fn persist(&self) -> anyhow::Result<()> {
+        fs::create_dir_all(&self.location)
+            .with_context(|| format!("Could not create dir {}", self.location.display()))?;
+
+        let data = serde_json::to_string_pretty(&self.repr).context("Could not serialize the deployments")?;
+        return atomic_write(&self.location.join(DEPLOYMENTS_FILE), data.as_bytes());
+    }
+}
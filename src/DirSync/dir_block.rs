@@ -1,14 +1,36 @@
-use lib_fileops::sanitize_relative_path;
+use std::path::{Path, PathBuf};
+
+use lib_fileops::{escapes_base, normalize_relative_path};
+use serde::Deserialize;
+use thiserror::Error;
 
 /// Indicate if a path refers to a directory or a file
-#[derive(Debug, Clone, PartialEq)]
+/// In config files, it is given in lowercase (`sync_type: dir`)
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum DirFileType {
     File,
     Dir,
+
+    /// The repo file is a region inside a system file that punto does not
+    /// own wholesale. The region is delimited by `# BEGIN punto <block name>`
+    /// and `# END punto <block name>` lines
+    Region,
+
+    /// Both files are structured config files (json, toml, yaml or ini) and
+    /// only the keys present in the repo file are synced
+    Merge,
+}
+
+impl Default for DirFileType {
+    fn default() -> Self {
+        return DirFileType::File;
+    }
 }
 
 /// Represent a dir block inside a `DirectoriesDescr`
 /// A Dir Block represents:
+///     0. The name of the block, that identifies it
 ///     1. The relative path of the file or dir inside the git repo
 ///     2. The relative path of the file or dir inside the system
 ///     3. Wether we are working with a file or with a dir
@@ -16,11 +38,23 @@ pub enum DirFileType {
 ///        exclude
 #[derive(Debug)]
 pub struct DirBlock {
+    /// Name of the block, as given in the config file
+    name: String,
+
     /// Path relative to DirDescr::repo_base
-    repo_path: String,
+    repo_path: PathBuf,
 
     /// Path relative to DirDescr::system_base
-    system_path: String,
+    system_path: PathBuf,
+
+    /// Overrides of DirDescr::repo_base and DirDescr::system_base for this block
+    /// They can be absolute, or relative to the ones of DirDescr
+    repo_base: Option<PathBuf>,
+    system_base: Option<PathBuf>,
+
+    /// Permissions given to the files that the block places in the system
+    /// None keeps the permissions that the files already have
+    mode: Option<u32>,
 
     /// Type of sync mechanism
     /// Wether we are working with files or directories
@@ -32,24 +66,181 @@ pub struct DirBlock {
     ignore_files: Vec<String>,
 }
 
+/// Errors that can happen when building a `DirBlock`
+#[derive(Error, Debug)]
+pub enum DirBlockError {
+    #[error("The {field} of block {block} is empty")]
+    EmptyPath{
+        block: String,
+        field: String,
+    },
+
+    #[error("The mode '{mode}' of block {block} is not valid")]
+    BadMode{
+        block: String,
+        mode: String,
+    },
+
+    #[error("The {field} '{path}' of block {block} points outside of its base path")]
+    PathEscapesBase{
+        block: String,
+        field: String,
+        path: String,
+    },
+}
+
+impl DirBlockError {
+    /// Block and field of the config file where the error is
+    pub fn block_and_field(&self) -> (&str, &str) {
+        return match self {
+            Self::EmptyPath{block, field} => (block, field),
+            Self::BadMode{block, ..} => (block, "mode"),
+            Self::PathEscapesBase{block, field, ..} => (block, field),
+        };
+    }
+
+    /// How to fix the error, if there is an obvious way
+    pub fn hint(&self) -> Option<String> {
+        return match self {
+            Self::EmptyPath{..} => None,
+            Self::BadMode{..} => Some("give it in octal, as a string (\"0600\") or as a number (0o600)".to_string()),
+            Self::PathEscapesBase{..} => Some("set `allow_outside_base: true` in the block if this is intended".to_string()),
+        };
+    }
+}
+
+/// Normalizes the path in `field` of block `block`, checking that it stays
+/// inside its base path unless `allow_outside_base` is set
+fn checked_relative_path(block: &str, field: &str, path: &Path, allow_outside_base: bool) -> Result<PathBuf, DirBlockError> {
+    if path.as_os_str().is_empty() {
+        return Err(DirBlockError::EmptyPath{block: block.to_string(), field: field.to_string()});
+    }
+
+    let normalized = normalize_relative_path(path);
+    if escapes_base(&normalized) && !allow_outside_base {
+        return Err(DirBlockError::PathEscapesBase{
+            block: block.to_string(),
+            field: field.to_string(),
+            path: path.display().to_string(),
+        });
+    }
+
+    return Ok(normalized);
+}
+
+/// Checks the override of the base path in `field` of block `block`
+/// Absolute overrides are kept as they are, relative ones are normalized and
+/// must stay inside the global base unless `allow_outside_base` is set
+fn checked_base_override(block: &str, field: &str, base: Option<PathBuf>, allow_outside_base: bool) -> Result<Option<PathBuf>, DirBlockError> {
+    return match base {
+        Some(base) if base.is_absolute() => Ok(Some(base)),
+        Some(base) => Ok(Some(checked_relative_path(block, field, &base, allow_outside_base)?)),
+        None => Ok(None),
+    };
+}
+
+/// Biggest mode that can be given to a file (setuid, setgid, sticky and rwx bits)
+const MAX_MODE: u32 = 0o7777;
+
+/// Parses the mode of block `block`, given in octal as a string ("0600")
+pub fn mode_from_octal_str(block: &str, mode: &str) -> Result<u32, DirBlockError> {
+    let digits = mode.trim().trim_start_matches("0o");
+    return match u32::from_str_radix(digits, 8) {
+        Ok(parsed) if parsed <= MAX_MODE => Ok(parsed),
+        _ => Err(DirBlockError::BadMode{block: block.to_string(), mode: mode.to_string()}),
+    };
+}
+
+/// Checks the mode of block `block`, given as a number (0o600 in yaml or toml)
+pub fn mode_from_number(block: &str, mode: i64) -> Result<u32, DirBlockError> {
+    if mode < 0 || mode > MAX_MODE as i64 {
+        return Err(DirBlockError::BadMode{block: block.to_string(), mode: mode.to_string()});
+    }
+
+    return Ok(mode as u32);
+}
+
+/// Base path of a block, given the global one and the block override
+/// Absolute overrides replace the global base, relative ones are joined to it
+fn resolve_base(global: &Path, block_override: Option<&Path>) -> PathBuf {
+    return match block_override {
+        Some(block_override) => global.join(block_override),
+        None => global.to_path_buf(),
+    };
+}
+
 impl DirBlock {
-    pub fn new(repo_path: String, system_path: String, sync_type: DirFileType, ignore_files: Vec<String>) -> Self {
-        return DirBlock {
-            repo_path: sanitize_relative_path(&repo_path),
-            system_path: sanitize_relative_path(&system_path),
+    /// Creates a new DirBlock
+    /// Paths are lexically normalized, and paths that escape their base path
+    /// (for example, "../../etc") are rejected unless `allow_outside_base` is true
+    pub fn new(
+        name: String,
+        repo_path: PathBuf,
+        system_path: PathBuf,
+        sync_type: DirFileType,
+        ignore_files: Vec<String>,
+        allow_outside_base: bool,
+    ) -> Result<Self, DirBlockError> {
+        let repo_path = checked_relative_path(&name, "repo_path", &repo_path, allow_outside_base)?;
+        let system_path = checked_relative_path(&name, "system_path", &system_path, allow_outside_base)?;
+
+        return Ok(DirBlock {
+            name,
+            repo_path,
+            system_path,
+            repo_base: None,
+            system_base: None,
+            mode: None,
             sync_type,
             ignore_files,
-        };
+        });
+    }
+
+    /// Overrides the base paths of the block. None keeps the global one
+    /// Relative overrides are checked like the paths of `DirBlock::new`
+    pub fn with_bases(
+        mut self,
+        repo_base: Option<PathBuf>,
+        system_base: Option<PathBuf>,
+        allow_outside_base: bool,
+    ) -> Result<Self, DirBlockError> {
+        self.repo_base = checked_base_override(&self.name, "repo_base", repo_base, allow_outside_base)?;
+        self.system_base = checked_base_override(&self.name, "system_base", system_base, allow_outside_base)?;
+        return Ok(self);
+    }
+
+    pub fn name(&self) -> &String{
+        return &self.name;
     }
 
-    pub fn repo_path(&self) -> &String{
+    pub fn repo_path(&self) -> &PathBuf{
         return &self.repo_path;
     }
 
-    pub fn system_path(&self) -> &String{
+    pub fn system_path(&self) -> &PathBuf{
         return &self.system_path;
     }
 
+    /// Sets the permissions of the files that the block places in the system
+    pub fn with_mode(mut self, mode: Option<u32>) -> Self {
+        self.mode = mode;
+        return self;
+    }
+
+    /// Base path of `repo_path`, given the global `repo_base`
+    pub fn repo_base(&self, global_repo_base: &Path) -> PathBuf{
+        return resolve_base(global_repo_base, self.repo_base.as_deref());
+    }
+
+    /// Base path of `system_path`, given the global `system_base`
+    pub fn system_base(&self, global_system_base: &Path) -> PathBuf{
+        return resolve_base(global_system_base, self.system_base.as_deref());
+    }
+
+    pub fn mode(&self) -> Option<u32>{
+        return self.mode;
+    }
+
     pub fn sync_type(&self) -> &DirFileType{
         return &self.sync_type;
     }
@@ -59,3 +250,85 @@ impl DirBlock {
     }
 }
 
+
+#[cfg(test)]
+mod tests{
+    use std::path::{Path, PathBuf};
+
+    use super::{mode_from_number, mode_from_octal_str, DirBlock, DirBlockError, DirFileType};
+
+    fn build_block(repo_path: &str, system_path: &str, allow_outside_base: bool) -> Result<DirBlock, DirBlockError>{
+        return DirBlock::new(
+            "block".to_string(),
+            PathBuf::from(repo_path),
+            PathBuf::from(system_path),
+            DirFileType::File,
+            vec![],
+            allow_outside_base,
+        );
+    }
+
+    #[test]
+    fn test_paths_are_normalized(){
+        let block = build_block("./src/../config/./file", "/.config/file", false).expect("Valid paths were rejected");
+        assert_eq!(block.repo_path(), Path::new("config/file"));
+        assert_eq!(block.system_path(), Path::new(".config/file"));
+
+        // Single character paths used to panic
+        let block = build_block("a", "b", false).expect("Valid paths were rejected");
+        assert_eq!(block.repo_path(), Path::new("a"));
+    }
+
+    #[test]
+    fn test_paths_escaping_base_are_rejected(){
+        assert!(matches!(build_block("../../etc", "etc", false), Err(DirBlockError::PathEscapesBase{..})));
+        assert!(matches!(build_block("etc", "config/../../..", false), Err(DirBlockError::PathEscapesBase{..})));
+        assert!(matches!(build_block("", "etc", false), Err(DirBlockError::EmptyPath{..})));
+
+        // Unless explicitly allowed
+        let block = build_block("../../etc", "etc", true).expect("Path outside base was rejected when allowed");
+        assert_eq!(block.repo_path(), Path::new("../../etc"));
+    }
+
+    #[test]
+    fn test_block_bases_override_global_ones(){
+        let global = Path::new("/home/user");
+
+        let block = build_block("hosts", "hosts", false).unwrap();
+        assert_eq!(block.system_base(global), global, "Block without override did not use the global base");
+
+        let block = block.with_bases(Some(PathBuf::from("dotfiles/etc")), Some(PathBuf::from("/etc")), false).unwrap();
+        assert_eq!(block.repo_base(global), Path::new("/home/user/dotfiles/etc"), "Relative base was not joined to the global one");
+        assert_eq!(block.system_base(global), Path::new("/etc"), "Absolute base did not replace the global one");
+    }
+
+    #[test]
+    fn test_block_bases_escaping_global_ones_are_rejected(){
+        let global = Path::new("/home/user");
+
+        let block = build_block("hosts", "hosts", false).unwrap();
+        let escaping = block.with_bases(None, Some(PathBuf::from("config/../../../etc")), false);
+        assert!(matches!(escaping, Err(DirBlockError::PathEscapesBase{..})), "Relative base escaping the global one was accepted");
+
+        let block = build_block("hosts", "hosts", false).unwrap()
+            .with_bases(Some(PathBuf::from("./dotfiles/../etc")), None, false)
+            .expect("Valid relative base was rejected");
+        assert_eq!(block.repo_base(global), Path::new("/home/user/etc"), "Relative base was not normalized");
+
+        // Unless explicitly allowed
+        let block = build_block("hosts", "hosts", true).unwrap()
+            .with_bases(None, Some(PathBuf::from("../../etc")), true)
+            .expect("Base outside the global one was rejected when allowed");
+        assert_eq!(block.system_base(global), Path::new("/home/user/../../etc"));
+    }
+
+    #[test]
+    fn test_modes_are_parsed_as_octal(){
+        assert_eq!(mode_from_octal_str("block", "0600").unwrap(), 0o600);
+        assert_eq!(mode_from_octal_str("block", "0o755").unwrap(), 0o755);
+        assert_eq!(mode_from_number("block", 0o644).unwrap(), 0o644);
+
+        assert!(mode_from_octal_str("block", "0699").is_err(), "Non octal mode was accepted");
+        assert!(mode_from_number("block", 0o17777).is_err(), "Too big mode was accepted");
+    }
+}
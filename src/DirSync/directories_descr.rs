@@ -1,18 +1,53 @@
-use std::path::Path;
+use std::fs;
+use std::path::PathBuf;
 
 use crate::DirSync::dir_block::{DirBlock, DirFileType};
-use lib_fileops::{join_two_paths, sync_dir, sync_file, get_dir_diff};
+use crate::DirSync::deployments::{DeployedBlock, Deployments};
+use crate::Errors;
+use lib_fileops::{
+    join_two_paths,
+    sync_dir,
+    sync_file,
+    sync_from_region,
+    sync_into_region,
+    sync_managed_keys,
+    sync_merging_keys,
+    get_dir_diff,
+    IndexStore,
+    Journal,
+};
 use anyhow::Context;
 
+/// Gives the permissions `mode` to `files`, recording the old ones in `journal`
+/// Symlinks are left untouched, as changing them would change their targets
+fn set_mode(files: &[PathBuf], mode: u32, journal: &mut Journal) -> anyhow::Result<()> {
+    #[cfg(unix)]
+    for file in files {
+        use std::os::unix::fs::PermissionsExt;
+
+        let metadata = fs::symlink_metadata(file).context(format!("Could not get metadata of {}", file.display()))?;
+        let old_mode = metadata.permissions().mode() & 0o7777;
+        if metadata.file_type().is_symlink() || old_mode == mode {
+            continue;
+        }
+
+        journal.record_mode_change(file, old_mode)?;
+        fs::set_permissions(file, fs::Permissions::from_mode(mode))
+            .context(format!("Could not set the mode of {}", file.display()))?;
+    }
+
+    return Ok(());
+}
+
 /// Represent the dir structure that we want to manage
 /// This representation is based on a set of dirblocks
 #[derive(Debug)]
 pub struct DirectoriesDescr {
     /// All the dir blocks have the same repo_base path
-    repo_base: String,
+    repo_base: PathBuf,
 
     /// All the dir blocks have the same system_base path
-    system_base: String,
+    system_base: PathBuf,
 
     /// The dir blocks that make up the DirectoriesDescr
     dir_blocks: Vec<DirBlock>,
@@ -21,7 +56,7 @@ pub struct DirectoriesDescr {
 impl DirectoriesDescr {
 
     /// Generates a new struct
-    pub fn new(repo_base: String, system_base: String, dir_blocks: Vec<DirBlock>) -> Self {
+    pub fn new(repo_base: PathBuf, system_base: PathBuf, dir_blocks: Vec<DirBlock>) -> Self {
         return Self{repo_base, system_base, dir_blocks};
     }
 
@@ -30,61 +65,134 @@ impl DirectoriesDescr {
         self.dir_blocks.push(dir_block);
     }
 
+    /// Absolute repo and system paths of `dir_block`, using the base paths
+    /// of the block if it overrides them
+    fn absolute_paths(&self, dir_block: &DirBlock) -> (PathBuf, PathBuf) {
+        let repo_path = join_two_paths(&dir_block.repo_base(&self.repo_base), dir_block.repo_path());
+        let system_path = join_two_paths(&dir_block.system_base(&self.system_base), dir_block.system_path());
+        return (repo_path, system_path);
+    }
+
     /// Downloads files from repo to the system
     /// Download in sync mode: can delete files in system that are not present in repo
-    // TODO -- test -- need to add some tests
-    pub fn download_from_repo_to_system(&self) {
+    ///
+    /// Every change made in the system is recorded in `journal`, so a failed
+    /// (or unwanted) download can be rolled back
+    /// The files that each block places in the system are recorded in `deployments`.
+    /// For that, `journal` must not be disabled, as it tells which files
+    /// existed before the download
+    /// Dirs are scanned using the indexes of `index_store`
+    pub fn download_from_repo_to_system(
+        &self,
+        journal: &mut Journal,
+        deployments: &mut Deployments,
+        index_store: &IndexStore,
+    ) -> anyhow::Result<()> {
         for dir_block in &self.dir_blocks {
 
+            // Ctrl-C stops between blocks, so no file is left half written
+            if Errors::interrupted() {
+                anyhow::bail!("Interrupted before block {}", dir_block.name());
+            }
+
             // Get two absolute paths using base paths
-            let from = &join_two_paths(&self.repo_base, &dir_block.repo_path());
-            let to = &join_two_paths(&self.system_base, &dir_block.system_path());
-            println!("==> Downloading {} to {}", from, to);
+            let (from, to) = &self.absolute_paths(dir_block);
+            println!("==> Downloading {} to {}", from.display(), to.display());
 
             let ignore_files = &dir_block.ignore_files();
 
-            // TODO -- DESIGN -- should this function return an error?
-            match &dir_block.sync_type() {
-                DirFileType::File => sync_file(from, to)
-                    .context(format!("Could not sync file from {} to {}", from, to))
-                    .unwrap(),
-                DirFileType::Dir => sync_dir(from, to, ignore_files, false)
-                    .context(format!("Could not sync dir from {} to {}", from, to))
-                    .unwrap(),
+            // Changes made by this block start at this point of the journal
+            let first_entry = journal.entries().len();
+
+            let deployed_files = match &dir_block.sync_type() {
+                DirFileType::File => {
+                    sync_file(from, to, journal)
+                        .context(format!("Could not sync file from {} to {}", from.display(), to.display()))?;
+                    vec![to.clone()]
+                },
+                DirFileType::Dir => sync_dir(from, to, ignore_files, false, journal, index_store)
+                    .context(format!("Could not sync dir from {} to {}", from.display(), to.display()))?,
+
+                // The system file is not owned by the block, only its region
+                DirFileType::Region => {
+                    sync_into_region(from, to, dir_block.name(), journal)
+                        .context(format!("Could not sync file {} into a region of {}", from.display(), to.display()))?;
+                    deployments.record_region(dir_block.name(), to.clone());
+                    vec![]
+                },
+
+                // The system file is shared with the application that owns it,
+                // so it is not tracked as deployed by the block
+                DirFileType::Merge => {
+                    sync_merging_keys(from, to, journal)
+                        .context(format!("Could not merge the keys of {} into {}", from.display(), to.display()))?;
+                    vec![]
+                },
             };
+
+            if let Some(mode) = dir_block.mode() {
+                set_mode(&deployed_files, mode, journal)
+                    .context(format!("Could not set the mode of the files of block {}", dir_block.name()))?;
+            }
+
+            deployments.record_block(dir_block.name(), deployed_files, &journal.entries()[first_entry..])
+                .context(format!("Could not record the files deployed by block {}", dir_block.name()))?;
         }
+
+        return Ok(());
+    }
+
+    /// Blocks that deployed files in the past but are no longer part of this
+    /// description
+    pub fn orphans<'a>(&self, deployments: &'a Deployments) -> Vec<(&'a String, &'a DeployedBlock)> {
+        let current_blocks: Vec<&String> = self.dir_blocks.iter().map(|block| block.name()).collect();
+        return deployments.orphans(&current_blocks);
     }
 
     /// Uploads files from system to the repo
     /// Upload in sync mode: can delete files in repo that are not present in system
+    /// Dirs are scanned using the indexes of `index_store`
     // TODO -- TEST -- need to add some tests
-    pub fn upload_from_system_to_repo(&self) {
+    pub fn upload_from_system_to_repo(&self, index_store: &IndexStore) -> anyhow::Result<()> {
+
+        // The repo is under version control, so there is no need to journal
+        // the changes made to it
+        let mut journal = Journal::disabled();
+
         for dir_block in &self.dir_blocks {
 
+            // Ctrl-C stops between blocks, so no file is left half written
+            if Errors::interrupted() {
+                anyhow::bail!("Interrupted before block {}", dir_block.name());
+            }
+
             // Get two absolute paths using base paths
-            let to = &join_two_paths(&self.repo_base, &dir_block.repo_path());
-            let from = &join_two_paths(&self.system_base, &dir_block.system_path());
-            println!("==> Uploading {} to {}", from, to);
+            let (to, from) = &self.absolute_paths(dir_block);
+            println!("==> Uploading {} to {}", from.display(), to.display());
 
             let ignore_files = &dir_block.ignore_files();
 
-            // TODO -- DESIGN -- should this function return an error?
             match &dir_block.sync_type() {
-                DirFileType::File => sync_file(from, to)
-                    .context(format!("Could not sync file from {} to {}", from, to))
-                    .unwrap(),
-                DirFileType::Dir => sync_dir(from, to, ignore_files, false)
-                    .context(format!("Could not sync dir from {} to {}", from, to))
-                    .unwrap(),
+                DirFileType::File => sync_file(from, to, &mut journal)
+                    .context(format!("Could not sync file from {} to {}", from.display(), to.display()))?,
+                DirFileType::Dir => sync_dir(from, to, ignore_files, false, &mut journal, index_store)
+                    .context(format!("Could not sync dir from {} to {}", from.display(), to.display()))
+                    .map(|_| ())?,
+                DirFileType::Region => sync_from_region(from, to, dir_block.name(), &mut journal)
+                    .context(format!("Could not sync a region of {} to file {}", from.display(), to.display()))?,
+                DirFileType::Merge => sync_managed_keys(from, to, &mut journal)
+                    .context(format!("Could not sync the managed keys of {} to {}", from.display(), to.display()))?,
             };
         }
+
+        return Ok(());
     }
 
     /// Checks for dir sync problems
     /// That's to say, search for files that are present in repo (or system)
     /// but not in system (or repo)
     /// This happens when we delete a file, because dir sync does not delete files
-    pub fn check(&self) {
+    pub fn check(&self) -> anyhow::Result<()> {
         // Filter entries that are about files, that entries can't be checked
         let only_dirs: Vec<&DirBlock> = self.dir_blocks.iter()
             .filter(|block| block.sync_type() == &DirFileType::Dir)
@@ -94,34 +202,25 @@ impl DirectoriesDescr {
         // but not in the other
         for curr_dir_block in only_dirs{
 
-            let absolute_repo_path = join_two_paths(
-                self.repo_base.as_str(),
-                curr_dir_block.repo_path().as_str()
-            );
-            let absolute_system_path = join_two_paths(
-                self.system_base.as_str(),
-                curr_dir_block.system_path().as_str()
-            );
+            let (absolute_repo_path, absolute_system_path) = self.absolute_paths(curr_dir_block);
 
             // Check for files that are present in the repo but not in the system
             // These are the dangerous files
             let new_files = get_dir_diff(&absolute_system_path, &absolute_repo_path)
-                .context(format!("Could not diff {} and {}", absolute_repo_path, absolute_system_path))
-                .unwrap();
+                .context(format!("Could not diff {} and {}", absolute_repo_path.display(), absolute_system_path.display()))?;
 
             // Warn the user if we found some files
             if new_files.len() > 0 {
                 println!("🚨 Found files that are present in the repo but not in the system!");
                 for file in new_files{
-                    println!("\t- {file}");
+                    println!("\t- {}", file.display());
                 }
                 println!("");
             }
 
             // Check for files that are present in the system but not in the repo
             let new_files = get_dir_diff(&absolute_repo_path, &absolute_system_path)
-                .context(format!("Could not diff {} and {}", absolute_system_path, absolute_repo_path))
-                .unwrap();
+                .context(format!("Could not diff {} and {}", absolute_system_path.display(), absolute_repo_path.display()))?;
 
             // Warn the user if we found some files
             if new_files.len() > 0 {
@@ -130,21 +229,25 @@ impl DirectoriesDescr {
                 println!("😅 Don't worry too much, probably you want to update these files from system to your git repo");
 
                 for file in new_files{
-                    println!("\t- {file}");
+                    println!("\t- {}", file.display());
                 }
                 println!("");
             }
         }
+
+        return Ok(());
     }
 }
 
 #[cfg(test)]
 mod tests{
 
-    use std::{path::Path, fs};
+    use std::{path::{Path, PathBuf}, fs};
 
     use super::DirectoriesDescr;
     use crate::DirSync::dir_block::{DirBlock, DirFileType};
+    use crate::DirSync::deployments::Deployments;
+    use lib_fileops::{IndexStore, Journal};
 
     /// A lot of tests need to work in top a file hierarchy structure
     /// So with this function we can create a basic structure
@@ -174,18 +277,13 @@ mod tests{
     /// NOTE: do not share root folder, because some tests might run in parallel
     fn create_basic_dir_description(base_path: &str) -> DirectoriesDescr{
 
-        let repo_base = base_path;
-
-        // Binding to not mess with the lifetimes
-        let binding = Path::new(repo_base)
-            .join("system");
-        let system_base = binding
-            .to_str()
-            .expect("Could not convert path object to string");
+        let repo_base = Path::new(base_path).to_path_buf();
+        let system_base = repo_base.join("system");
 
         // Create a bunch of DirBlocks
         // Put the parameters of each dir block in vectors, so creating more than one dir block
         // is easier
+        let names = vec!["src_block", "test_file_block"];
         let repo_paths = vec!["src", "test/first_test.rs"];
         let system_paths = vec!["alternative_src", "other_test_place/first_test___.rs"];
         let sync_types = vec![DirFileType::Dir, DirFileType::File];
@@ -195,17 +293,20 @@ mod tests{
         let mut dir_blocks = vec![];
         for i in 0..repo_paths.len(){
 
-            let repo_path = repo_paths[i].to_string();
-            let system_path = system_paths[i].to_string();
+            let name = names[i].to_string();
+            let repo_path = PathBuf::from(repo_paths[i]);
+            let system_path = PathBuf::from(system_paths[i]);
             let sync_type = sync_types[i].clone();
             let curr_ignored_files = ignored_files[i].clone();
 
             // Create the dir block with the current data
-            let new_dir_block = DirBlock::new(repo_path, system_path, sync_type, curr_ignored_files);
+            let allow_outside_base = false;
+            let new_dir_block = DirBlock::new(name, repo_path, system_path, sync_type, curr_ignored_files, allow_outside_base)
+                .expect("Could not create a dir block");
             dir_blocks.push(new_dir_block);
         }
 
-        return DirectoriesDescr::new(repo_base.to_string(), system_base.to_string(), dir_blocks);
+        return DirectoriesDescr::new(repo_base, system_base, dir_blocks);
     }
 
     #[test]
@@ -220,7 +321,8 @@ mod tests{
         let description = create_basic_dir_description(base_path);
 
         // Get the dir description
-        description.download_from_repo_to_system();
+        let mut deployments = Deployments::load(&Path::new(base_path).join("deployments"), Path::new("config.yaml")).unwrap();
+        description.download_from_repo_to_system(&mut Journal::disabled(), &mut deployments, &IndexStore::disabled()).expect("Download failed");
 
         // Make some checks about directories
         assert!(Path::new(base_path).join("system").exists(), "Directories were not properly downloaded");
@@ -233,4 +335,143 @@ mod tests{
         assert!(Path::new(base_path).join("system/alternative_src/third.rs").exists(), "Dir sync failed to copy a file");
         assert!(Path::new(base_path).join("system/other_test_place/first_test___.rs").exists(), "File sync failed to make the copy");
     }
+
+    #[test]
+    fn test_download_with_block_bases(){
+        let base_path = "./test_download_with_block_bases";
+        remove_basic_file_structure(base_path);
+        create_basic_file_structure(base_path).expect("Could not create basic file structure for the test");
+
+        // One block relative to another repo base, and the other one to an
+        // absolute system base
+        let mut description = create_basic_dir_description(base_path);
+        fs::create_dir_all(Path::new(base_path).join("etc")).unwrap();
+        let absolute_system_base = Path::new(base_path).join("etc").canonicalize().unwrap();
+        let block = DirBlock::new(
+            "etc_block".to_string(),
+            PathBuf::from("first_test.rs"),
+            PathBuf::from("hosts"),
+            DirFileType::File,
+            vec![],
+            false,
+        ).unwrap()
+            .with_bases(Some(PathBuf::from("test")), Some(absolute_system_base.clone()), false).unwrap()
+            .with_mode(Some(0o600));
+        description.push(block);
+
+        let mut deployments = Deployments::load(&Path::new(base_path).join("deployments"), Path::new("config.yaml")).unwrap();
+        description.download_from_repo_to_system(&mut Journal::disabled(), &mut deployments, &IndexStore::disabled()).expect("Download failed");
+
+        assert!(absolute_system_base.join("hosts").exists(), "Block did not use its own bases");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(absolute_system_base.join("hosts")).unwrap().permissions().mode();
+            assert_eq!(mode & 0o7777, 0o600, "Mode of the block was not applied");
+        }
+        assert!(Path::new(base_path).join("system/alternative_src/second.rs").exists(), "Blocks without bases did not use the global ones");
+
+        remove_basic_file_structure(base_path);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_mode_change_can_be_rolled_back(){
+        use std::os::unix::fs::PermissionsExt;
+
+        let base_path = "./test_mode_change_can_be_rolled_back";
+        remove_basic_file_structure(base_path);
+        create_basic_file_structure(base_path).expect("Could not create basic file structure for the test");
+
+        // A system file with the same contents as the repo one, so the block
+        // only changes its mode
+        let system_file = Path::new(base_path).join("system/hosts");
+        fs::create_dir_all(system_file.parent().unwrap()).unwrap();
+        fs::write(&system_file, "").unwrap();
+        fs::set_permissions(&system_file, fs::Permissions::from_mode(0o644)).unwrap();
+
+        let mut description = DirectoriesDescr::new(Path::new(base_path).to_path_buf(), Path::new(base_path).join("system"), vec![]);
+        let block = DirBlock::new(
+            "hosts_block".to_string(),
+            PathBuf::from("test/first_test.rs"),
+            PathBuf::from("hosts"),
+            DirFileType::File,
+            vec![],
+            false,
+        ).unwrap().with_mode(Some(0o600));
+        description.push(block);
+
+        let mut journal = Journal::start(&Path::new(base_path).join("journal")).expect("Could not start the journal");
+        let mut deployments = Deployments::load(&Path::new(base_path).join("deployments"), Path::new("config.yaml")).unwrap();
+        description.download_from_repo_to_system(&mut journal, &mut deployments, &IndexStore::disabled()).expect("Download failed");
+
+        let mode = || fs::metadata(&system_file).unwrap().permissions().mode() & 0o7777;
+        assert_eq!(mode(), 0o600, "Mode of the block was not applied");
+
+        journal.rollback().expect("Rollback failed");
+        assert_eq!(mode(), 0o644, "Mode of the file was not restored");
+
+        remove_basic_file_structure(base_path);
+    }
+
+    #[test]
+    fn test_download_can_be_rolled_back(){
+        let base_path = "./test_download_can_be_rolled_back";
+        remove_basic_file_structure(base_path);
+        create_basic_file_structure(base_path).expect("Could not create basic file structure for the test");
+
+        // Put a file in the system that is going to be overwritten by the download
+        let overwritten = Path::new(base_path).join("system/alternative_src/second.rs");
+        fs::create_dir_all(overwritten.parent().unwrap()).unwrap();
+        fs::write(&overwritten, "pre-punto contents").unwrap();
+
+        let description = create_basic_dir_description(base_path);
+        let journal_location = Path::new(base_path).join("journal");
+        let mut journal = Journal::start(&journal_location).expect("Could not start the journal");
+        let mut deployments = Deployments::load(&Path::new(base_path).join("deployments"), Path::new("config.yaml")).unwrap();
+        description.download_from_repo_to_system(&mut journal, &mut deployments, &IndexStore::disabled()).expect("Download failed");
+
+        assert_eq!(fs::read_to_string(&overwritten).unwrap(), "", "File was not downloaded");
+
+        // Rolling back restores the system to its pre-download state
+        journal.rollback().expect("Rollback failed");
+        assert_eq!(fs::read_to_string(&overwritten).unwrap(), "pre-punto contents", "Overwritten file was not restored");
+        assert!(!Path::new(base_path).join("system/alternative_src/third.rs").exists(), "Created file was not removed");
+        assert!(!Path::new(base_path).join("system/other_test_place").exists(), "Created dir was not removed");
+
+        remove_basic_file_structure(base_path);
+    }
+
+    #[test]
+    fn test_undeploy_restores_pre_punto_files(){
+        let base_path = "./test_undeploy_restores_pre_punto_files";
+        remove_basic_file_structure(base_path);
+        create_basic_file_structure(base_path).expect("Could not create basic file structure for the test");
+
+        // A file that was in the system before punto managed it
+        let pre_punto = Path::new(base_path).join("system/alternative_src/second.rs");
+        fs::create_dir_all(pre_punto.parent().unwrap()).unwrap();
+        fs::write(&pre_punto, "pre-punto contents").unwrap();
+
+        let description = create_basic_dir_description(base_path);
+        let mut journal = Journal::start(&Path::new(base_path).join("journal")).unwrap();
+        let mut deployments = Deployments::load(&Path::new(base_path).join("deployments"), Path::new("config.yaml")).unwrap();
+        description.download_from_repo_to_system(&mut journal, &mut deployments, &IndexStore::disabled()).expect("Download failed");
+
+        // Both blocks are in the description, so there are no orphans
+        assert!(description.orphans(&deployments).is_empty(), "Found orphans when all blocks are in the config");
+        let deployed: Vec<_> = deployments.block("src_block").unwrap().files().iter().map(|file| file.path().clone()).collect();
+        assert!(deployed.contains(&pre_punto), "Overwritten file was not tracked");
+
+        // Undeploy the dir block
+        assert!(deployments.undeploy("src_block").unwrap(), "Block was not deployed");
+        assert_eq!(fs::read_to_string(&pre_punto).unwrap(), "pre-punto contents", "Pre-punto file was not restored");
+        assert!(!Path::new(base_path).join("system/alternative_src/third.rs").exists(), "Deployed file was not removed");
+        assert!(deployments.block("src_block").is_none());
+
+        // Undeploying twice does nothing
+        assert!(!deployments.undeploy("src_block").unwrap());
+
+        remove_basic_file_structure(base_path);
+    }
 }
@@ -1,91 +1,233 @@
 pub mod directories_descr;
 pub mod dir_block;
+pub mod deployments;
 mod parsers;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-use parsers::{YamlDirParser, ParseDirectories};
 use anyhow::Context;
+use lib_fileops::{punto_state_dir, IndexStore, Journal};
 
-use crate::DirSync::parsers::TomlDirParser;
+use crate::ConfigLoader::ConfigFormat;
+use crate::DirSync::deployments::Deployments;
+use crate::DirSync::directories_descr::DirectoriesDescr;
+use crate::Errors::{self, PuntoError};
 
-#[derive(Debug)]
-enum SupportedFileFormats {
-    Yaml,
-    Toml,
+/// Where the journal of the last download is stored
+fn download_journal_location() -> anyhow::Result<PathBuf> {
+    return Ok(punto_state_dir()?.join("download_journal"));
 }
 
-fn get_file_format(file_path: &str) -> anyhow::Result<SupportedFileFormats> {
-    let extension = Path::new(file_path)
-        .extension()
-        .context(format!("Could not get the file extension of the file {}", file_path))?
-        .to_str()
-        .context("Could not convert file extension object to &str")?;
-
-    let format: SupportedFileFormats = match extension {
-        "yaml" => SupportedFileFormats::Yaml,
-        "toml" => SupportedFileFormats::Toml,
-        _ => return anyhow::bail!(format!("Extension for {} is not supported in our program", file_path)),
-    };
+/// Store for the indexes of the synced dirs, so only changed files are hashed
+fn index_store() -> anyhow::Result<IndexStore> {
+    return Ok(IndexStore::new(&punto_state_dir()?.join("index")));
+}
 
-    return Ok(format);
+/// Where the deployments of all config files are stored
+fn deployments_root() -> anyhow::Result<PathBuf> {
+    return Ok(punto_state_dir()?.join("deployments"));
 }
 
-// TODO -- DESIGN -- the following three functions should return an error?
+/// Where the deployments of the config file `file_path` are stored
+/// Each config file gets its own dir, named after its canonical path
+fn deployments_location(file_path: &Path) -> anyhow::Result<PathBuf> {
+    let canonical = file_path
+        .canonicalize()
+        .context(format!("Could not canonicalize {}", file_path.display()))?;
+    let dir_name = canonical.to_string_lossy().replace('/', "%");
+
+    return Ok(deployments_root()?.join(dir_name));
+}
+
+/// Parses the directories config file in `file_path`
+/// `format` overrides the format given by the extension of the file
+fn parse_directories_file(file_path: &Path, format: Option<ConfigFormat>) -> Result<DirectoriesDescr, PuntoError> {
+    return parsers::parse_directories_file(file_path, format)
+        .map_err(|err| PuntoError::config(file_path, err));
+}
 
 /// Handle the download command
-pub fn handle_download(file_path: &str) {
+pub fn handle_download(file_path: &Path, format: Option<ConfigFormat>) -> Result<(), PuntoError> {
     println!("📂 Getting files from git repo to your system!");
 
-    // Get the format of the file and parse it depending on the extension
-    let format = get_file_format(file_path)
-        .context("Could not get file extension!")
-        .unwrap();
-    let dir_descr = match format {
-        SupportedFileFormats::Yaml => YamlDirParser::parse_file(file_path),
-        SupportedFileFormats::Toml => TomlDirParser::parse_file(file_path),
+    let dir_descr = parse_directories_file(file_path, format)?;
+
+    // Record every change made to the system, so the download can be rolled back
+    // The journal of a download that did not finish is kept until the user
+    // decides what to do with it, or the state before that download is lost
+    let journal_location = download_journal_location()
+        .context("Could not get the location of the download journal")
+        .map_err(|err| PuntoError::total_failure("Download", err))?;
+    if Journal::is_unfinished(&journal_location).unwrap_or(false) {
+        let source = anyhow::anyhow!(
+            "The last download did not finish. Run `punto --rollback` to restore your system to the state it had before it, \
+            or `punto --discard-journal` to keep it as it is"
+        );
+        return Err(PuntoError::total_failure("Download", source));
     }
-        .context("Could not parse file contents to rust object properly :(")
-        .unwrap();
+    let mut journal = Journal::start(&journal_location)
+        .context("Could not start the download journal")
+        .map_err(|err| PuntoError::total_failure("Download", err))?;
+
+    // Keep track of what each block deploys
+    let mut deployments = deployments_location(file_path)
+        .and_then(|location| Deployments::load(&location, file_path))
+        .context("Could not load the deployments of this config file")
+        .map_err(|err| PuntoError::total_failure("Download", err))?;
+
+    let index_store = index_store()
+        .context("Could not get the location of the indexes")
+        .map_err(|err| PuntoError::total_failure("Download", err))?;
 
     // Download
-    dir_descr.download_from_repo_to_system();
+    if let Err(err) = dir_descr.download_from_repo_to_system(&mut journal, &mut deployments, &index_store) {
+        if Errors::interrupted() {
+            if !journal.is_empty() {
+                eprintln!("Run `punto --rollback` to undo the part of the download that was done");
+            }
+            return Err(PuntoError::UserAbort);
+        }
+
+        // Nothing was changed in the system
+        if journal.is_empty() {
+            return Err(PuntoError::total_failure("Download", err));
+        }
+
+        eprintln!("Your system is partially updated, run `punto --rollback` to restore it");
+        return Err(PuntoError::partial_failure("Download", err));
+    }
+
+    // Warn about files deployed by blocks that are no longer in the config
+    let orphans = dir_descr.orphans(&deployments);
+    if !orphans.is_empty() {
+        println!("🧹 Found files deployed by blocks that are no longer in {}", file_path.display());
+        for (block_name, block) in orphans {
+            println!("==> Block {}", block_name);
+            for file in block.files() {
+                println!("\t- {}", file.path().display());
+            }
+            for region_file in block.regions() {
+                println!("\t- region {} of {}", block_name, region_file.display());
+            }
+        }
+        println!("Run `punto --undeploy <block>` to remove them from your system");
+    }
+
+    return journal.finish()
+        .context("Could not mark the download journal as finished")
+        .map_err(|err| PuntoError::partial_failure("Download", err));
+}
+
+/// Handle the undeploy command
+/// Removes the files deployed by the block `block_name`, restoring the files
+/// that were in the system before punto deployed them
+pub fn handle_undeploy(block_name: &str) -> Result<(), PuntoError> {
+    println!("🧹 Undeploying block {}", block_name);
+
+    let all_deployments = deployments_root()
+        .and_then(|root| Deployments::load_all(&root))
+        .context("Could not load the deployments")
+        .map_err(|err| PuntoError::total_failure("Undeploy", err))?;
+
+    let mut found = false;
+    for mut deployments in all_deployments {
+        let config = deployments.config().clone();
+        let undeployed = deployments.undeploy(block_name)
+            .context(format!("Could not undeploy block {} of {}", block_name, config.display()))
+            .map_err(|err| PuntoError::total_failure("Undeploy", err))?;
+
+        if undeployed {
+            println!("Block {} of {} undeployed", block_name, config.display());
+            found = true;
+        }
+    }
+
+    if !found {
+        let source = anyhow::anyhow!("No block named {} has deployed files", block_name);
+        return Err(PuntoError::total_failure("Undeploy", source));
+    }
+
+    return Ok(());
+}
+
+/// Handle the rollback command
+/// Undoes all the changes made by the last download
+pub fn handle_rollback() -> Result<(), PuntoError> {
+    println!("⏪ Rolling back the last download");
+
+    let journal = download_journal_location()
+        .and_then(|location| Journal::load(&location))
+        .context("Could not load the download journal")
+        .map_err(|err| PuntoError::total_failure("Rollback", err))?;
+
+    let journal = match journal {
+        Some(journal) => journal,
+        None => {
+            println!("There is no download to roll back");
+            return Ok(());
+        }
+    };
+
+    journal.rollback()
+        .context("Could not roll back the last download")
+        .map_err(|err| PuntoError::total_failure("Rollback", err))?;
+    journal.discard()
+        .context("Could not discard the download journal after rolling back")
+        .map_err(|err| PuntoError::partial_failure("Rollback", err))?;
+
+    println!("Your system is back to the state it had before the last download");
+    return Ok(());
+}
+
+/// Handle the discard journal command
+/// Forgets the journal of the last download, keeping the system as it is
+pub fn handle_discard_journal() -> Result<(), PuntoError> {
+    let journal = download_journal_location()
+        .and_then(|location| Journal::load(&location))
+        .context("Could not load the download journal")
+        .map_err(|err| PuntoError::total_failure("Discard journal", err))?;
+
+    let journal = match journal {
+        Some(journal) => journal,
+        None => {
+            println!("There is no download journal to discard");
+            return Ok(());
+        }
+    };
+
+    journal.discard()
+        .context("Could not discard the download journal")
+        .map_err(|err| PuntoError::total_failure("Discard journal", err))?;
+
+    println!("🗑️  Download journal discarded, the last download can no longer be rolled back");
+    return Ok(());
 }
 
 /// Handle the upload command
-pub fn handle_upload(file_path: &str) {
+pub fn handle_upload(file_path: &Path, format: Option<ConfigFormat>) -> Result<(), PuntoError> {
     println!("📂 Uploading files from your system to the repo");
 
-    // Get the format of the file and parse it depending on the extension
-    let format = get_file_format(file_path)
-        .context("Could not get file extension!")
-        .unwrap();
-    let dir_descr = match format {
-        SupportedFileFormats::Yaml => YamlDirParser::parse_file(file_path),
-        SupportedFileFormats::Toml => TomlDirParser::parse_file(file_path),
-    }
-        .context("Could not parse file contents to rust object properly :(")
-        .unwrap();
+    let dir_descr = parse_directories_file(file_path, format)?;
 
+    let index_store = index_store()
+        .context("Could not get the location of the indexes")
+        .map_err(|err| PuntoError::total_failure("Upload", err))?;
 
     // Upload
-    dir_descr.upload_from_system_to_repo();
+    if let Err(err) = dir_descr.upload_from_system_to_repo(&index_store) {
+        if Errors::interrupted() {
+            return Err(PuntoError::UserAbort);
+        }
+        return Err(PuntoError::total_failure("Upload", err));
+    }
+
+    return Ok(());
 }
 
-pub fn handle_check(file_path: &str) {
+pub fn handle_check(file_path: &Path, format: Option<ConfigFormat>) -> Result<(), PuntoError> {
     println!("🔎 Checking for problems in your dir syncs");
 
-    // Get the format of the file and parse it depending on the extension
-    let format = get_file_format(file_path)
-        .context("Could not get file extension!")
-        .unwrap();
-    let dir_descr = match format {
-        SupportedFileFormats::Yaml => YamlDirParser::parse_file(file_path),
-        SupportedFileFormats::Toml => TomlDirParser::parse_file(file_path),
-    }
-        .context("Could not parse file contents to rust object properly :(")
-        .unwrap();
-
+    let dir_descr = parse_directories_file(file_path, format)?;
 
     // Check directories specified in the description
-    dir_descr.check();
+    return dir_descr.check().map_err(|err| PuntoError::total_failure("Check", err));
 }
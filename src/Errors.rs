@@ -0,0 +1,139 @@
+//! Errors that punto commands can end with, and the exit code of each one
+//!
+//! Every handler returns a `PuntoError`, that is propagated up to `main`,
+//! printed with its whole context chain, and turned into the exit code of the
+//! process, so punto can be scripted
+
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use thiserror::Error;
+
+/// Exit code when the command could not do any of its work
+pub const EXIT_TOTAL_FAILURE: i32 = 1;
+
+/// Exit code when a config file could not be read or is not valid
+pub const EXIT_CONFIG_ERROR: i32 = 2;
+
+/// Exit code when part of the work was done, but some of it failed
+pub const EXIT_PARTIAL_FAILURE: i32 = 3;
+
+/// Exit code when the user aborted the command (128 + SIGINT, as shells do)
+pub const EXIT_USER_ABORT: i32 = 130;
+
+#[derive(Debug, Error)]
+pub enum PuntoError {
+    /// The config file could not be read, or its contents are not valid
+    #[error("Config file {} is not valid", file.display())]
+    Config {
+        file: PathBuf,
+        #[source]
+        source: anyhow::Error,
+    },
+
+    /// Some of the work was done, but some parts of it failed
+    #[error("{action} partially failed")]
+    PartialFailure {
+        action: String,
+        #[source]
+        source: anyhow::Error,
+    },
+
+    /// The command could not do any of its work
+    #[error("{action} failed")]
+    TotalFailure {
+        action: String,
+        #[source]
+        source: anyhow::Error,
+    },
+
+    /// The user stopped the command (for example, with Ctrl-C)
+    #[error("Aborted by the user")]
+    UserAbort,
+}
+
+impl PuntoError {
+    pub fn config(file: impl Into<PathBuf>, source: impl Into<anyhow::Error>) -> Self {
+        return Self::Config { file: file.into(), source: source.into() };
+    }
+
+    pub fn partial_failure(action: &str, source: impl Into<anyhow::Error>) -> Self {
+        return Self::PartialFailure { action: action.to_string(), source: source.into() };
+    }
+
+    pub fn total_failure(action: &str, source: impl Into<anyhow::Error>) -> Self {
+        return Self::TotalFailure { action: action.to_string(), source: source.into() };
+    }
+
+    /// Exit code of the process when punto ends with this error
+    pub fn exit_code(&self) -> i32 {
+        return match self {
+            Self::Config { .. } => EXIT_CONFIG_ERROR,
+            Self::PartialFailure { .. } => EXIT_PARTIAL_FAILURE,
+            Self::TotalFailure { .. } => EXIT_TOTAL_FAILURE,
+            Self::UserAbort => EXIT_USER_ABORT,
+        };
+    }
+
+    /// Prints the error to stderr, followed by every error that caused it
+    pub fn print(&self) {
+        eprintln!("❌ {}", self);
+
+        let mut cause = std::error::Error::source(self);
+        if cause.is_some() {
+            eprintln!("\nCaused by:");
+        }
+
+        let mut depth = 0;
+        while let Some(err) = cause {
+            // Multi-line messages are kept aligned with their first line
+            let message = err.to_string().replace('\n', "\n       ");
+            eprintln!("    {}: {}", depth, message);
+            cause = err.source();
+            depth += 1;
+        }
+    }
+}
+
+/// Set when the user presses Ctrl-C
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Makes Ctrl-C stop punto between two steps, instead of killing it in the
+/// middle of one (for example, while a file is being written)
+/// The running child process, if any, still gets the signal
+pub fn install_interrupt_handler() -> anyhow::Result<()> {
+    ctrlc::set_handler(|| INTERRUPTED.store(true, Ordering::SeqCst))?;
+    return Ok(());
+}
+
+/// Checks if the user has pressed Ctrl-C
+pub fn interrupted() -> bool {
+    return INTERRUPTED.load(Ordering::SeqCst);
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Context;
+
+    use super::{PuntoError, EXIT_CONFIG_ERROR, EXIT_PARTIAL_FAILURE};
+
+    #[test]
+    fn test_context_chain_is_kept() {
+        let source = Err::<(), _>(anyhow::anyhow!("Permission denied"))
+            .context("Could not sync file a to b")
+            .unwrap_err();
+        let err = PuntoError::partial_failure("Download", source);
+        assert_eq!(err.exit_code(), EXIT_PARTIAL_FAILURE);
+
+        // Walk the chain as `print` does
+        let mut chain = vec![err.to_string()];
+        let mut cause = std::error::Error::source(&err);
+        while let Some(err) = cause {
+            chain.push(err.to_string());
+            cause = err.source();
+        }
+
+        assert_eq!(chain, vec!["Download partially failed", "Could not sync file a to b", "Permission denied"]);
+        assert_eq!(PuntoError::config("punto.yaml", anyhow::anyhow!("bad")).exit_code(), EXIT_CONFIG_ERROR);
+    }
+}
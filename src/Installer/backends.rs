@@ -0,0 +1,438 @@
+//! Package managers that installer sections use to install their packages
+//!
+//! Backends run a command template for each action, where `{package}` is
+//! replaced by the name of the package (or the name is appended, if the
+//! template does not have the placeholder). punto has built-in backends for
+//! common package managers, and installer files can declare their own ones
+//! under `backends`
+
+use anyhow::Context;
+use lib_commands::{SingleCommand, SingleCommandError};
+
+use crate::Schemas::BackendSchema;
+
+/// What punto can do with a package manager
+pub trait PackageBackend {
+    /// Name of the backend, as given in installer files
+    fn name(&self) -> &str;
+
+    /// Program that the backend runs to install packages
+    fn program(&self) -> &str;
+
+    fn install(&self, package: &str) -> anyhow::Result<()>;
+
+    /// Installs all the `packages` in a single invocation of the package manager
+    fn install_all(&self, packages: &[&str]) -> anyhow::Result<()>;
+
+    fn is_installed(&self, package: &str) -> anyhow::Result<bool>;
+
+    /// Version of `package` that is installed, or None if it is not installed
+    fn installed_version(&self, package: &str) -> anyhow::Result<Option<String>>;
+
+    fn remove(&self, package: &str) -> anyhow::Result<()>;
+
+    /// Packages that the user installed with this backend
+    fn list_installed(&self) -> anyhow::Result<Vec<String>>;
+}
+
+/// Lines of stderr kept from failed commands, to report why they failed
+const STDERR_TAIL_LINES: usize = 10;
+
+/// Placeholder of the command templates, replaced by the name of the package
+const PACKAGE_PLACEHOLDER: &str = "{package}";
+
+/// Backend that runs a command template for each action
+#[derive(Debug, Clone)]
+pub struct CommandBackend {
+    name: String,
+    install: String,
+    is_installed: Option<String>,
+    version: Option<String>,
+    remove: Option<String>,
+    list_installed: Option<String>,
+
+    /// Gets the package names from the output of `list_installed`
+    parse_list: fn(&str) -> Vec<String>,
+
+    /// Wether install and remove commands are run with sudo. Queries never are
+    sudo: bool,
+}
+
+/// A built-in backend. Its commands are the same as `CommandBackend` ones
+struct Builtin {
+    name: &'static str,
+    install: &'static str,
+    is_installed: Option<&'static str>,
+    version: Option<&'static str>,
+    remove: &'static str,
+    list_installed: &'static str,
+    parse_list: fn(&str) -> Vec<String>,
+    sudo: bool,
+}
+
+/// Backends that can be used without declaring them
+const BUILTINS: [Builtin; 11] = [
+    Builtin {
+        name: "pacman",
+        install: "pacman -S --noconfirm --needed",
+        is_installed: Some("pacman -Q"),
+        version: Some("pacman -Q"),
+        remove: "pacman -Rns --noconfirm",
+        list_installed: "pacman -Qqe",
+        parse_list: first_words,
+        sudo: true,
+    },
+    Builtin {
+        name: "paru",
+        install: "paru -S --noconfirm --needed",
+        is_installed: Some("paru -Q"),
+        version: Some("paru -Q"),
+        remove: "paru -Rns --noconfirm",
+        list_installed: "paru -Qqem",
+        parse_list: first_words,
+        sudo: false,
+    },
+    Builtin {
+        name: "yay",
+        install: "yay -S --noconfirm --needed",
+        is_installed: Some("yay -Q"),
+        version: Some("yay -Q"),
+        remove: "yay -Rns --noconfirm",
+        list_installed: "yay -Qqem",
+        parse_list: first_words,
+        sudo: false,
+    },
+    Builtin {
+        name: "apt",
+        install: "apt-get install -y",
+        is_installed: Some("dpkg -s"),
+        version: Some("dpkg-query -W -f=${Version}"),
+        remove: "apt-get remove -y",
+        list_installed: "apt-mark showmanual",
+        parse_list: first_words,
+        sudo: true,
+    },
+    Builtin {
+        name: "dnf",
+        install: "dnf install -y",
+        is_installed: Some("rpm -q"),
+        version: Some("rpm -q --qf %{VERSION}-%{RELEASE}"),
+        remove: "dnf remove -y",
+        list_installed: "dnf repoquery --userinstalled --qf %{name}\\n",
+        parse_list: first_words,
+        sudo: true,
+    },
+    Builtin {
+        name: "zypper",
+        install: "zypper --non-interactive install",
+        is_installed: Some("rpm -q"),
+        version: Some("rpm -q --qf %{VERSION}-%{RELEASE}"),
+        remove: "zypper --non-interactive remove",
+        list_installed: "rpm -qa --qf %{NAME}\\n",
+        parse_list: first_words,
+        sudo: true,
+    },
+    Builtin {
+        name: "nix-env",
+        install: "nix-env -i",
+        is_installed: Some("nix-env -q"),
+        version: Some("nix-env -q"),
+        remove: "nix-env -e",
+        list_installed: "nix-env -q",
+        parse_list: nix_names,
+        sudo: false,
+    },
+    Builtin {
+        name: "flatpak",
+        install: "flatpak install -y --noninteractive",
+        is_installed: Some("flatpak info"),
+        version: None,
+        remove: "flatpak uninstall -y --noninteractive",
+        list_installed: "flatpak list --app --columns=application",
+        parse_list: first_words,
+        sudo: false,
+    },
+    Builtin {
+        name: "cargo",
+        install: "cargo install",
+        is_installed: None,
+        version: None,
+        remove: "cargo uninstall",
+        list_installed: "cargo install --list",
+        parse_list: first_words,
+        sudo: false,
+    },
+    Builtin {
+        name: "pipx",
+        install: "pipx install",
+        is_installed: None,
+        version: None,
+        remove: "pipx uninstall",
+        list_installed: "pipx list --short",
+        parse_list: first_words,
+        sudo: false,
+    },
+    Builtin {
+        name: "npm",
+        install: "npm install -g",
+        is_installed: None,
+        version: Some("npm ls -g --depth=0"),
+        remove: "npm uninstall -g",
+        list_installed: "npm ls -g --depth=0 --parseable",
+        parse_list: npm_names,
+        sudo: false,
+    },
+];
+
+/// Names of the built-in backends
+pub fn builtin_names() -> Vec<String> {
+    return BUILTINS.iter().map(|builtin| builtin.name.to_string()).collect();
+}
+
+/// Finds the backend `name`, among the ones `declared` in the installer file
+/// and the built-in ones. Declared backends take precedence
+pub fn find_backend(name: &str, declared: &[(String, BackendSchema)]) -> Option<anyhow::Result<CommandBackend>> {
+    if let Some((_, schema)) = declared.iter().find(|(declared_name, _)| declared_name == name) {
+        return Some(CommandBackend::from_schema(name, schema));
+    }
+
+    let builtin = BUILTINS.iter().find(|builtin| builtin.name == name)?;
+    return Some(Ok(CommandBackend {
+        name: builtin.name.to_string(),
+        install: builtin.install.to_string(),
+        is_installed: builtin.is_installed.map(str::to_string),
+        version: builtin.version.map(str::to_string),
+        remove: Some(builtin.remove.to_string()),
+        list_installed: Some(builtin.list_installed.to_string()),
+        parse_list: builtin.parse_list,
+        sudo: builtin.sudo,
+    }));
+}
+
+impl CommandBackend {
+    /// Backend declared in an installer file
+    pub fn from_schema(name: &str, schema: &BackendSchema) -> anyhow::Result<Self> {
+        let templates = [Some(&schema.install), schema.is_installed.as_ref(), schema.version.as_ref(), schema.remove.as_ref(), schema.list_installed.as_ref()];
+        for template in templates.iter().flatten() {
+            check_template(template).with_context(|| format!("Backend {} is not valid", name))?;
+        }
+
+        return Ok(Self {
+            name: name.to_string(),
+            install: schema.install.clone(),
+            is_installed: schema.is_installed.clone(),
+            version: schema.version.clone(),
+            remove: schema.remove.clone(),
+            list_installed: schema.list_installed.clone(),
+            parse_list: first_words,
+            sudo: schema.sudo,
+        });
+    }
+
+    /// Backend of the sections that only give an `install_command`, that can
+    /// only install packages
+    pub fn from_install_command(install_command: &str, sudo: bool) -> anyhow::Result<Self> {
+        check_template(install_command)?;
+
+        return Ok(Self {
+            name: install_command.to_string(),
+            install: install_command.to_string(),
+            is_installed: None,
+            version: None,
+            remove: None,
+            list_installed: None,
+            parse_list: first_words,
+            sudo,
+        });
+    }
+
+    /// Overrides if the backend uses sudo. None keeps the one of the backend
+    pub fn with_sudo(mut self, sudo: Option<bool>) -> Self {
+        self.sudo = sudo.unwrap_or(self.sudo);
+        return self;
+    }
+
+    /// The template of an action, failing if the backend does not have it
+    fn template<'a>(&self, template: &'a Option<String>, field: &str) -> anyhow::Result<&'a str> {
+        return template.as_deref().with_context(|| format!(
+            "Backend {} can not do this, give it a `{}` command in `backends`", self.name, field
+        ));
+    }
+}
+
+impl PackageBackend for CommandBackend {
+    fn name(&self) -> &str {
+        return &self.name;
+    }
+
+    fn program(&self) -> &str {
+        return self.install.split_whitespace().next().unwrap_or_default();
+    }
+
+    fn install(&self, package: &str) -> anyhow::Result<()> {
+        return run(&self.install, Some(package), self.sudo);
+    }
+
+    fn install_all(&self, packages: &[&str]) -> anyhow::Result<()> {
+        // Commands are split by spaces, so each package is an argument
+        return run(&self.install, Some(&packages.join(" ")), self.sudo);
+    }
+
+    fn is_installed(&self, package: &str) -> anyhow::Result<bool> {
+        // Without a query command, the package is searched in the installed ones
+        let template = match &self.is_installed {
+            Some(template) => template,
+            None => return Ok(self.list_installed()?.iter().any(|installed| installed == package)),
+        };
+
+        // Query commands tell that the package is not installed by failing
+        return match command(template, Some(package), false)?.output() {
+            Ok(_) => Ok(true),
+            Err(SingleCommandError::RuntimeFailure(_)) => Ok(false),
+            Err(err) => Err(err).with_context(|| format!("Could not check if {} is installed", package)),
+        };
+    }
+
+    fn installed_version(&self, package: &str) -> anyhow::Result<Option<String>> {
+        let template = self.template(&self.version, "version")?;
+
+        // As queries, version commands fail if the package is not installed
+        return match command(template, Some(package), false)?.output() {
+            Ok(output) => Ok(parse_version(&output, package)),
+            Err(SingleCommandError::RuntimeFailure(_)) => Ok(None),
+            Err(err) => Err(err).with_context(|| format!("Could not get the version of {}", package)),
+        };
+    }
+
+    fn remove(&self, package: &str) -> anyhow::Result<()> {
+        return run(self.template(&self.remove, "remove")?, Some(package), self.sudo);
+    }
+
+    fn list_installed(&self) -> anyhow::Result<Vec<String>> {
+        let template = self.template(&self.list_installed, "list_installed")?;
+        let output = command(template, None, false)?
+            .output()
+            .with_context(|| format!("Could not list the packages installed with {}", self.name))?;
+
+        return Ok((self.parse_list)(&output));
+    }
+}
+
+/// Checks that a command template can be run
+fn check_template(template: &str) -> anyhow::Result<()> {
+    SingleCommand::new(template.to_string(), false, false)
+        .with_context(|| format!("Command '{}' is not valid, set `sudo: true` to run it with sudo", template))?;
+    return Ok(());
+}
+
+/// Builds the command of `template` for `package`
+fn command(template: &str, package: Option<&str>, sudo: bool) -> anyhow::Result<SingleCommand> {
+    let command_string = match package {
+        Some(package) if template.contains(PACKAGE_PLACEHOLDER) => template.replace(PACKAGE_PLACEHOLDER, package),
+        Some(package) => format!("{} {}", template, package),
+        None => template.to_string(),
+    };
+
+    return Ok(SingleCommand::new(command_string, false, sudo)?);
+}
+
+/// Runs the command of `template` for `package`, showing its output
+/// If it fails, the error keeps the last lines it wrote to stderr
+fn run(template: &str, package: Option<&str>, sudo: bool) -> anyhow::Result<()> {
+    return Ok(command(template, package, sudo)?.run_keeping_stderr(STDERR_TAIL_LINES)?);
+}
+
+/// Version in the output of a version command: the last word of its last
+/// line, without the package name before it, as in `git 2.40.1-1`,
+/// `git-2.40.1` or `└── typescript@5.0.4`
+fn parse_version(output: &str, package: &str) -> Option<String> {
+    let word = output.lines().rev().find(|line| !line.trim().is_empty())?.split_whitespace().last()?;
+    let version = [format!("{}-", package), format!("{}@", package)]
+        .iter()
+        .find_map(|prefix| word.strip_prefix(prefix.as_str()))
+        .unwrap_or(word);
+    return Some(version.to_string());
+}
+
+/// Package names given as the first word of each line
+/// Indented lines give details of the package above them, as binaries in
+/// `cargo install --list`, so they are skipped
+fn first_words(output: &str) -> Vec<String> {
+    return output
+        .lines()
+        .filter(|line| !line.starts_with(char::is_whitespace))
+        .filter_map(|line| line.split_whitespace().next())
+        .map(|name| name.to_string())
+        .collect();
+}
+
+/// Package names given as `name-version` by `nix-env -q`
+fn nix_names(output: &str) -> Vec<String> {
+    return first_words(output)
+        .into_iter()
+        .map(|name_version| {
+            // The version starts at the first dash followed by a digit
+            let version_start = name_version
+                .char_indices()
+                .zip(name_version.chars().skip(1))
+                .find(|((_, current), next)| *current == '-' && next.is_ascii_digit())
+                .map(|((index, _), _)| index);
+
+            return match version_start {
+                Some(index) => name_version[..index].to_string(),
+                None => name_version,
+            };
+        })
+        .collect();
+}
+
+/// Package names given as paths inside `node_modules` by `npm ls --parseable`
+fn npm_names(output: &str) -> Vec<String> {
+    return output
+        .lines()
+        .filter_map(|line| line.rsplit_once("node_modules/"))
+        .map(|(_, name)| name.to_string())
+        .collect();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{find_backend, first_words, nix_names, npm_names, PackageBackend};
+    use crate::Schemas::BackendSchema;
+
+    #[test]
+    fn test_installed_packages_are_parsed() {
+        let cargo_list = "bat v0.23.0:\n    bat\nripgrep v13.0.0:\n    rg\n";
+        assert_eq!(first_words(cargo_list), vec!["bat", "ripgrep"]);
+
+        let nix_list = "git-2.40.1\npython3-3.11.4\nnix-index-0.1.5\nhello\n";
+        assert_eq!(nix_names(nix_list), vec!["git", "python3", "nix-index", "hello"]);
+
+        let npm_list = "/usr/lib\n/usr/lib/node_modules/typescript\n/usr/lib/node_modules/@angular/cli\n";
+        assert_eq!(npm_names(npm_list), vec!["typescript", "@angular/cli"]);
+    }
+
+    #[test]
+    fn test_declared_backends_use_their_templates() {
+        let declared = vec![("fake".to_string(), BackendSchema {
+            install: "true {package}".to_string(),
+            is_installed: Some("test {package} = git".to_string()),
+            version: Some("echo {package}-1.2.0".to_string()),
+            remove: None,
+            list_installed: Some("echo git\nhtop".to_string()),
+            sudo: false,
+        })];
+
+        let backend = find_backend("fake", &declared).unwrap().unwrap();
+        assert!(backend.install("git").is_ok());
+        assert!(backend.is_installed("git").unwrap());
+        assert!(!backend.is_installed("htop").unwrap());
+        assert_eq!(backend.installed_version("git").unwrap(), Some("1.2.0".to_string()));
+        assert_eq!(backend.list_installed().unwrap(), vec!["git", "htop"]);
+        assert!(backend.remove("git").is_err(), "Backend without remove command removed a package");
+
+        // Built-in backends are found without declaring them
+        assert_eq!(find_backend("pacman", &[]).unwrap().unwrap().name(), "pacman");
+        assert!(find_backend("pacmna", &declared).is_none());
+    }
+}
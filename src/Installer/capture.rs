@@ -0,0 +1,402 @@
+//! Captures the packages installed with a backend as an installer section
+//!
+//! The section is printed, or merged into an existing installer file. Yaml
+//! and toml files are edited as text, so their comments and layout are kept.
+//! Json files have no comments, so they are written again
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+
+use anyhow::Context;
+use lib_fileops::atomic_write;
+use serde_json::Value;
+
+use crate::ConfigLoader::{self, ConfigFormat};
+use crate::Errors::PuntoError;
+use crate::Installer::backends::{self, PackageBackend};
+use crate::Migrations;
+use crate::Schemas::{self, InstallerFile, PackageSchema};
+
+/// Callback for --capture-packages cli arg
+/// # Arguments
+/// - `backend_name`: backend whose explicitly installed packages are captured
+/// - `section`: name of the captured section. Defaults to the name of the backend
+/// - `into`: installer file to merge the section into. If it is None, the
+///   section is printed as a new installer file
+/// - `format`: overrides the format given by the extension of `into`
+pub fn handle_capture(backend_name: &str, section: Option<&str>, into: Option<&Path>, format: Option<ConfigFormat>) -> Result<(), PuntoError> {
+    let section = section.unwrap_or(backend_name);
+
+    let into = match into {
+        Some(into) if into.exists() => into,
+
+        // Without a file to merge into, the section is a file on its own
+        _ => {
+            let captured = capture_new_file(into, format, backend_name, section)
+                .map_err(|err| PuntoError::total_failure("Capturing packages", err))?;
+            eprintln!("📦 Captured {} packages installed with {}", captured, backend_name);
+            return Ok(());
+        },
+    };
+
+    let added = merge_into_file(into, format, backend_name, section)
+        .map_err(|err| PuntoError::config(into, err))?;
+    eprintln!("📦 Added {} packages installed with {} to section {} of {}", added, backend_name, section, into.display());
+    return Ok(());
+}
+
+/// Writes the packages of `backend_name` as a new installer file in `path`,
+/// or prints it as yaml if `path` is None. Returns how many packages were captured
+fn capture_new_file(path: Option<&Path>, format: Option<ConfigFormat>, backend_name: &str, section: &str) -> anyhow::Result<usize> {
+    let packages = capture(backend_name, &InstallerFile { backends: vec![], sections: vec![] })?;
+
+    match path {
+        Some(path) => {
+            let contents = new_file(ConfigLoader::format_of(path, format)?, section, backend_name, &packages)?;
+            atomic_write(path, contents.as_bytes()).with_context(|| format!("Could not write {}", path.display()))?;
+        },
+        None => print!("{}", new_file(ConfigFormat::Yaml, section, backend_name, &packages)?),
+    }
+
+    return Ok(packages.len());
+}
+
+/// Adds the packages of `backend_name` that the installer file `path` does
+/// not declare yet to its `section`. Returns how many packages were added
+fn merge_into_file(path: &Path, format: Option<ConfigFormat>, backend_name: &str, section: &str) -> anyhow::Result<usize> {
+    let installer: InstallerFile = Schemas::from_loaded(ConfigLoader::load(path, format)?)?;
+
+    // Packages go to a section that installs them with the same backend
+    let existing_backend = installer.sections
+        .iter()
+        .find(|(name, _)| name == section)
+        .map(|(_, section)| section.backend.as_deref());
+    if let Some(existing_backend) = existing_backend {
+        if existing_backend != Some(backend_name) {
+            anyhow::bail!("Section {} does not use the backend {}, pick other section with --section", section, backend_name);
+        }
+    }
+
+    let packages = capture(backend_name, &installer)?;
+    if packages.is_empty() {
+        return Ok(0);
+    }
+
+    let format = ConfigLoader::format_of(path, format)?;
+    let contents = fs::read_to_string(path).with_context(|| format!("Could not read {}", path.display()))?;
+    let merged = match format {
+        ConfigFormat::Json => merge_json(path, section, backend_name, &packages)?,
+        _ => match merge_text(format, &contents, section, &packages) {
+            Some(merged) => merged,
+
+            // Sections given by included files would be overridden by a new one
+            None if existing_backend.is_some() => anyhow::bail!(
+                "Section {} comes from an included file, pick other section with --section", section,
+            ),
+            None => format!("{}\n{}", with_newline(&contents), new_section(format, section, backend_name, &packages)),
+        },
+    };
+
+    atomic_write(path, merged.as_bytes()).with_context(|| format!("Could not write {}", path.display()))?;
+    return Ok(packages.len());
+}
+
+/// Explicitly installed packages of `backend_name` that no section of
+/// `installer` declares or ignores
+fn capture(backend_name: &str, installer: &InstallerFile) -> anyhow::Result<Vec<String>> {
+    let backend = backends::find_backend(backend_name, &installer.backends)
+        .with_context(|| format!(
+            "Unknown backend {}, declare it in `{}` or use one of {}",
+            backend_name, ConfigLoader::BACKENDS_KEY, backends::builtin_names().join(", "),
+        ))??;
+
+    let mut known: HashSet<String> = installer.sections
+        .iter()
+        .filter(|(_, section)| section.backend.as_deref() == Some(backend_name))
+        .flat_map(|(_, section)| section.packages.iter().flat_map(PackageSchema::names).chain(&section.ignore_unmanaged).cloned())
+        .collect();
+
+    let installed = backend.list_installed()?;
+    return Ok(installed.into_iter().filter(|package| known.insert(package.clone())).collect());
+}
+
+/// Contents of a new installer file with a single section
+fn new_file(format: ConfigFormat, section: &str, backend_name: &str, packages: &[String]) -> anyhow::Result<String> {
+    return match format {
+        ConfigFormat::Json => {
+            let config = serde_json::json!({section: {"backend": backend_name, "packages": packages}});
+            format.serialize(&Value::Object(Migrations::with_current_version(config.as_object().cloned().unwrap_or_default())))
+        },
+        ConfigFormat::Toml => Ok(format!("version = {}\n\n{}", Migrations::CURRENT_VERSION, new_section(format, section, backend_name, packages))),
+        ConfigFormat::Yaml => Ok(format!("version: {}\n\n{}", Migrations::CURRENT_VERSION, new_section(format, section, backend_name, packages))),
+    };
+}
+
+/// Text of a new installer section, in yaml or toml
+fn new_section(format: ConfigFormat, section: &str, backend_name: &str, packages: &[String]) -> String {
+    if packages.is_empty() {
+        return match format {
+            ConfigFormat::Toml => format!("[{}]\nbackend = {}\npackages = []\n", section, toml_string(backend_name)),
+            _ => format!("{}:\n    backend: {}\n    packages: []\n", section, yaml_string(backend_name)),
+        };
+    }
+
+    if format == ConfigFormat::Toml {
+        let items: String = packages.iter().map(|package| format!("    {},\n", toml_string(package))).collect();
+        return format!("[{}]\nbackend = {}\npackages = [\n{}]\n", section, toml_string(backend_name), items);
+    }
+
+    let items: String = packages.iter().map(|package| format!("        - {}\n", yaml_string(package))).collect();
+    return format!("{}:\n    backend: {}\n    packages:\n{}", section, yaml_string(backend_name), items);
+}
+
+/// Adds `packages` to the list of packages of `section`, editing the yaml or
+/// toml `contents` line by line. Returns None if the file has no such section
+fn merge_text(format: ConfigFormat, contents: &str, section: &str, packages: &[String]) -> Option<String> {
+    let mut lines: Vec<String> = contents.lines().map(str::to_string).collect();
+
+    let (header, is_body_end): (String, fn(&str) -> bool) = match format {
+        ConfigFormat::Toml => (format!("[{}]", section), |line| code(line).starts_with('[') && !line.starts_with(char::is_whitespace)),
+        _ => (format!("{}:", section), |line| !code(line).is_empty() && !line.starts_with(char::is_whitespace)),
+    };
+    let start = lines.iter().position(|line| code(line) == header && !line.starts_with(char::is_whitespace))?;
+    let end = (start + 1..lines.len()).find(|&index| is_body_end(&lines[index])).unwrap_or(lines.len());
+
+    let packages_line = (start + 1..end).find(|&index| {
+        let line = code(&lines[index]);
+        return line.starts_with("packages") && line["packages".len()..].trim_start().starts_with(&[':', '='][..]);
+    });
+
+    match packages_line {
+        Some(index) => {
+            let inserted = match format {
+                ConfigFormat::Toml => merge_toml_array(&mut lines, index, end, packages),
+                _ => merge_yaml_list(&mut lines, index, end, packages),
+            };
+            lines.splice(inserted.0..inserted.0, inserted.1);
+        },
+
+        // The section has no packages yet
+        None => {
+            let last = (start..end).rev().find(|&index| !code(&lines[index]).is_empty()).unwrap_or(start);
+            let added: Vec<String> = match format {
+                ConfigFormat::Toml => std::iter::once("packages = [".to_string())
+                    .chain(packages.iter().map(|package| format!("    {},", toml_string(package))))
+                    .chain(std::iter::once("]".to_string()))
+                    .collect(),
+                _ => {
+                    let indent = lines[start + 1..end].iter().find(|line| !code(line).is_empty()).map(|line| indent_of(line)).unwrap_or_else(|| "    ".to_string());
+                    std::iter::once(format!("{}packages:", indent))
+                        .chain(packages.iter().map(|package| format!("{}    - {}", indent, yaml_string(package))))
+                        .collect()
+                },
+            };
+            lines.splice(last + 1..last + 1, added);
+        },
+    }
+
+    return Some(with_newline(&lines.join("\n")));
+}
+
+/// Lines to insert into the yaml list of packages that starts in `lines[index]`,
+/// and where to insert them. Inline lists are rewritten in place
+fn merge_yaml_list(lines: &mut [String], index: usize, end: usize, packages: &[String]) -> (usize, Vec<String>) {
+    let key_indent = indent_of(&lines[index]);
+    let value = code(&lines[index])["packages".len()..].trim_start()[1..].trim().to_string();
+
+    // Flow lists, as `packages: [git, vim]`
+    if value.starts_with('[') {
+        let mut items: Vec<String> = value.trim_start_matches('[').trim_end_matches(']')
+            .split(',')
+            .map(|item| item.trim().to_string())
+            .filter(|item| !item.is_empty())
+            .collect();
+        items.extend(packages.iter().map(|package| yaml_string(package)));
+        lines[index] = format!("{}packages: [{}]", key_indent, items.join(", "));
+        return (index + 1, vec![]);
+    }
+
+    // Block lists go on until a line less indented than their items
+    let mut last_item = None;
+    for (current, line) in lines.iter().enumerate().take(end).skip(index + 1) {
+        if code(line).is_empty() {
+            continue;
+        }
+        if code(line).starts_with("- ") && indent_of(line).len() >= key_indent.len() {
+            last_item = Some(current);
+            continue;
+        }
+        if indent_of(line).len() <= key_indent.len() {
+            break;
+        }
+    }
+
+    let (position, item_indent) = match last_item {
+        Some(last_item) => (last_item + 1, indent_of(&lines[last_item])),
+        None => (index + 1, format!("{}    ", key_indent)),
+    };
+    return (position, packages.iter().map(|package| format!("{}- {}", item_indent, yaml_string(package))).collect());
+}
+
+/// Lines to insert into the toml array of packages that starts in `lines[index]`,
+/// and where to insert them. Arrays in a single line are rewritten in place
+fn merge_toml_array(lines: &mut [String], index: usize, end: usize, packages: &[String]) -> (usize, Vec<String>) {
+    let new_items: Vec<String> = packages.iter().map(|package| toml_string(package)).collect();
+    let line = code(&lines[index]).to_string();
+
+    if line.ends_with(']') {
+        let key_indent = indent_of(&lines[index]);
+        let open = line.find('[').unwrap_or(line.len() - 1);
+        let mut items: Vec<String> = line[open + 1..line.len() - 1]
+            .split(',')
+            .map(|item| item.trim().to_string())
+            .filter(|item| !item.is_empty())
+            .collect();
+        items.extend(new_items);
+        lines[index] = format!("{}packages = [{}]", key_indent, items.join(", "));
+        return (index + 1, vec![]);
+    }
+
+    let close = (index + 1..end).find(|&current| code(&lines[current]).starts_with(']')).unwrap_or(end);
+    let last_item = (index + 1..close).rev().find(|&current| !code(&lines[current]).is_empty());
+    let item_indent = match last_item {
+        Some(last_item) => {
+            // The last item needs a comma before the new ones
+            if !code(&lines[last_item]).ends_with(',') {
+                let item = code(&lines[last_item]).to_string();
+                lines[last_item] = lines[last_item].replacen(&item, &format!("{},", item), 1);
+            }
+            indent_of(&lines[last_item])
+        },
+        None => "    ".to_string(),
+    };
+    return (close, new_items.iter().map(|item| format!("{}{},", item_indent, item)).collect());
+}
+
+/// Adds `packages` to `section` of the json file in `path`, creating the section if needed
+fn merge_json(path: &Path, section: &str, backend_name: &str, packages: &[String]) -> anyhow::Result<String> {
+    let mut config = match ConfigLoader::read_file(path, ConfigFormat::Json)? {
+        Value::Object(config) => config,
+        _ => anyhow::bail!("Config files must be maps"),
+    };
+
+    let body = config.entry(section.to_string())
+        .or_insert_with(|| serde_json::json!({"backend": backend_name, "packages": []}));
+    let list = body.as_object_mut()
+        .with_context(|| format!("Section {} is not a map", section))?
+        .entry("packages")
+        .or_insert_with(|| Value::Array(vec![]));
+    match list {
+        Value::Array(list) => list.extend(packages.iter().cloned().map(Value::String)),
+        _ => anyhow::bail!("Packages of section {} are not a list", section),
+    }
+
+    return ConfigFormat::Json.serialize(&Value::Object(config));
+}
+
+/// A line without its comment and surrounding whitespace
+/// Comments are taken to start at a `#` after a space, as in `- git  # vcs`
+fn code(line: &str) -> &str {
+    let line = line.trim();
+    if line.starts_with('#') {
+        return "";
+    }
+    return line.find(" #").map_or(line, |comment| &line[..comment]).trim_end();
+}
+
+/// Leading whitespace of a line
+fn indent_of(line: &str) -> String {
+    return line.chars().take_while(|char| char.is_whitespace()).collect();
+}
+
+fn with_newline(contents: &str) -> String {
+    if contents.ends_with('\n') || contents.is_empty() {
+        return contents.to_string();
+    }
+    return format!("{}\n", contents);
+}
+
+/// A yaml scalar for `text`, quoted only when it needs to
+fn yaml_string(text: &str) -> String {
+    return serde_yaml::to_string(text).map(|yaml| yaml.trim_end().to_string()).unwrap_or_else(|_| text.to_string());
+}
+
+/// A toml basic string for `text`
+fn toml_string(text: &str) -> String {
+    return serde_json::to_string(text).unwrap_or_else(|_| format!("\"{}\"", text));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::merge_text;
+    use crate::ConfigLoader::ConfigFormat;
+
+    #[test]
+    fn test_packages_are_merged_keeping_comments() {
+        let packages = vec!["htop".to_string(), "@angular/cli".to_string()];
+
+        let yaml = "\
+version: 2
+# Terminal tools
+cli:
+    backend: npm
+    packages:
+        - git    # vcs
+        # Others
+        - vim
+
+    sudo: false
+other:
+    backend: npm
+    packages: [bat]
+";
+        assert_eq!(merge_text(ConfigFormat::Yaml, yaml, "cli", &packages).unwrap(), "\
+version: 2
+# Terminal tools
+cli:
+    backend: npm
+    packages:
+        - git    # vcs
+        # Others
+        - vim
+        - htop
+        - '@angular/cli'
+
+    sudo: false
+other:
+    backend: npm
+    packages: [bat]
+");
+        assert!(merge_text(ConfigFormat::Yaml, yaml, "other", &packages).unwrap().contains("    packages: [bat, htop, '@angular/cli']\n"));
+        assert!(merge_text(ConfigFormat::Yaml, yaml, "missing", &packages).is_none());
+
+        let toml = "\
+version = 2
+
+[cli]
+backend = \"npm\"
+packages = [
+    \"git\" # vcs
+]
+
+[other]
+backend = \"npm\"
+";
+        assert_eq!(merge_text(ConfigFormat::Toml, toml, "cli", &packages).unwrap(), "\
+version = 2
+
+[cli]
+backend = \"npm\"
+packages = [
+    \"git\", # vcs
+    \"htop\",
+    \"@angular/cli\",
+]
+
+[other]
+backend = \"npm\"
+");
+        assert!(merge_text(ConfigFormat::Toml, toml, "other", &packages).unwrap().ends_with("backend = \"npm\"\npackages = [\n    \"htop\",\n    \"@angular/cli\",\n]\n"));
+    }
+}
@@ -0,0 +1,173 @@
+//! Conditions that installer sections give in `when`, and package names
+//! that depend on the machine
+//!
+//! A section is only installed on the machines where all its conditions
+//! hold. Sections that do not apply to a machine are skipped, not failed
+
+use std::env;
+use std::fs;
+use std::path::Path;
+
+use crate::Installer::backends::PackageBackend;
+use crate::Schemas::{OneOrMany, PackageSchema, WhenSchema};
+
+/// Key of the name of a package used when no backend nor distro of the machine has one
+const DEFAULT_ALIAS: &str = "default";
+
+/// Facts about the machine that conditions are checked against
+pub struct SystemFacts {
+    /// `ID` and `ID_LIKE` of /etc/os-release, as `manjaro` and `arch`
+    distros: Vec<String>,
+
+    hostname: Option<String>,
+
+    /// Profiles given with --profile
+    profiles: Vec<String>,
+}
+
+impl SystemFacts {
+    /// Facts of this machine, with the active `profiles`
+    pub fn detect(profiles: &[&str]) -> Self {
+        let hostname = ["/proc/sys/kernel/hostname", "/etc/hostname"]
+            .iter()
+            .find_map(|path| fs::read_to_string(path).ok())
+            .map(|hostname| hostname.trim().to_string())
+            .filter(|hostname| !hostname.is_empty());
+
+        return Self {
+            distros: detect_distros(),
+            hostname,
+            profiles: profiles.iter().map(|profile| profile.to_string()).collect(),
+        };
+    }
+}
+
+/// Checks the conditions of a section for a machine
+/// Returns why they do not hold, or None if the section must be installed
+pub fn unmet_condition(when: &WhenSchema, facts: &SystemFacts, backend: &dyn PackageBackend) -> Option<String> {
+    if let Some(distro) = &when.distro {
+        if !distro.values().iter().any(|distro| facts.distros.contains(distro)) {
+            let found = facts.distros.first().map_or("unknown", String::as_str);
+            return Some(format!("distro is {}, not {}", found, one_of(distro)));
+        }
+    }
+
+    if let Some(hostname) = &when.hostname {
+        let found = facts.hostname.as_deref().unwrap_or("unknown");
+        if !hostname.values().iter().any(|hostname| hostname == found) {
+            return Some(format!("hostname is {}, not {}", found, one_of(hostname)));
+        }
+    }
+
+    if let Some(profile) = &when.profile {
+        if !profile.values().iter().any(|profile| facts.profiles.contains(profile)) {
+            return Some(format!("profile {} is not active, pass it with --profile", profile.values().join(" or ")));
+        }
+    }
+
+    if when.program_on_path && !is_on_path(backend.program()) {
+        return Some(format!("{} is not on PATH", backend.program()));
+    }
+
+    return None;
+}
+
+/// Name of `package` in this machine, for sections installed with `backend_name`
+/// Aliases of the backend come first, then the ones of the distros, most
+/// specific first, and then the default one
+/// Returns None if the package has no name for this machine
+pub fn resolve_package(package: &PackageSchema, backend_name: &str, distros: &[String]) -> Option<String> {
+    let aliases = match package {
+        PackageSchema::Name(name) => return Some(name.clone()),
+        PackageSchema::Aliases(aliases) => aliases,
+    };
+
+    return std::iter::once(backend_name)
+        .chain(distros.iter().map(String::as_str))
+        .chain(std::iter::once(DEFAULT_ALIAS))
+        .find_map(|key| aliases.get(key))
+        .cloned();
+}
+
+/// Distros of this machine, as given by /etc/os-release
+pub fn detect_distros() -> Vec<String> {
+    return distros_of(&fs::read_to_string("/etc/os-release").unwrap_or_default());
+}
+
+/// Distros in the contents of /etc/os-release: its `ID`, followed by the ones
+/// in `ID_LIKE`
+fn distros_of(os_release: &str) -> Vec<String> {
+    let value = |key: &str| -> Vec<String> {
+        return os_release
+            .lines()
+            .filter_map(|line| line.strip_prefix(key)?.strip_prefix('='))
+            .flat_map(|value| value.trim_matches('"').split_whitespace().map(str::to_string).collect::<Vec<String>>())
+            .collect();
+    };
+
+    let mut distros = value("ID");
+    distros.extend(value("ID_LIKE"));
+    return distros;
+}
+
+/// Whether `program` can be run, as a path or by its name
+fn is_on_path(program: &str) -> bool {
+    if program.contains('/') {
+        return Path::new(program).is_file();
+    }
+
+    let path = env::var_os("PATH").unwrap_or_default();
+    return env::split_paths(&path).any(|dir| dir.join(program).is_file());
+}
+
+fn one_of(values: &OneOrMany) -> String {
+    return values.values().join(" nor ");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{distros_of, resolve_package, unmet_condition, SystemFacts};
+    use crate::Installer::backends::CommandBackend;
+    use crate::Schemas::{OneOrMany, PackageSchema, WhenSchema};
+
+    #[test]
+    fn test_conditions_are_checked_against_the_machine() {
+        let os_release = "NAME=\"Manjaro Linux\"\nID=manjaro\nID_LIKE=\"arch\"\nPRETTY_NAME=\"Manjaro\"\n";
+        let facts = SystemFacts {
+            distros: distros_of(os_release),
+            hostname: Some("laptop".to_string()),
+            profiles: vec!["work".to_string()],
+        };
+        assert_eq!(facts.distros, vec!["manjaro", "arch"]);
+
+        let backend = CommandBackend::from_install_command("thisprogramdoesnotexist -S", false).unwrap();
+        let when = |distro: &str, hostname: &str, program_on_path: bool| WhenSchema {
+            distro: Some(OneOrMany::One(distro.to_string())),
+            hostname: Some(OneOrMany::Many(vec!["desktop".to_string(), hostname.to_string()])),
+            profile: Some(OneOrMany::One("work".to_string())),
+            program_on_path,
+        };
+
+        // Distros match their ID_LIKE too
+        assert_eq!(unmet_condition(&when("arch", "laptop", false), &facts, &backend), None);
+        assert_eq!(unmet_condition(&when("debian", "laptop", false), &facts, &backend).unwrap(), "distro is manjaro, not debian");
+        assert!(unmet_condition(&when("arch", "server", false), &facts, &backend).unwrap().starts_with("hostname is laptop"));
+        assert_eq!(unmet_condition(&when("arch", "laptop", true), &facts, &backend).unwrap(), "thisprogramdoesnotexist is not on PATH");
+    }
+
+    #[test]
+    fn test_package_names_are_resolved_per_machine() {
+        let package: PackageSchema = serde_json::from_value(serde_json::json!({
+            "default": "fd", "debian": "fd-find", "nix-env": "nixpkgs.fd",
+        })).unwrap();
+        let distros = |distros: &[&str]| -> Vec<String> { distros.iter().map(|distro| distro.to_string()).collect() };
+
+        // Backends come before distros, and ID_LIKE distros apply too
+        assert_eq!(resolve_package(&package, "nix-env", &distros(&["debian"])).unwrap(), "nixpkgs.fd");
+        assert_eq!(resolve_package(&package, "apt", &distros(&["ubuntu", "debian"])).unwrap(), "fd-find");
+        assert_eq!(resolve_package(&package, "pacman", &distros(&["arch"])).unwrap(), "fd");
+
+        let package: PackageSchema = serde_json::from_value(serde_json::json!({"debian": "batcat"})).unwrap();
+        assert_eq!(resolve_package(&package, "pacman", &distros(&["arch"])), None);
+    }
+}
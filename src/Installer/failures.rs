@@ -0,0 +1,133 @@
+//! Report of the packages that failed to install
+//!
+//! Failures of the last install of each installer file are kept in the punto
+//! state dir, so they can be read after the terminal scrolls, and installed
+//! again with `--retry-failed`
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use lib_commands::SingleCommandError;
+use lib_fileops::{atomic_write, punto_state_dir};
+use serde::{Deserialize, Serialize};
+
+/// Name of the report, in the punto state dir
+const REPORT_FILE_NAME: &str = "failed_packages.json";
+
+/// A package that failed to install, and why
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FailedPackage {
+    pub section: String,
+    pub package: String,
+
+    /// Exit code of the install command. None if it did not run, or was killed by a signal
+    pub exit_code: Option<i32>,
+
+    /// Last lines that the install command wrote to stderr
+    pub stderr_tail: Vec<String>,
+}
+
+impl FailedPackage {
+    /// Failure of `package` of `section`, from the error of its install command
+    pub fn new(section: &str, package: &str, error: &anyhow::Error) -> Self {
+        let (exit_code, stderr_tail) = match error.downcast_ref::<SingleCommandError>() {
+            Some(SingleCommandError::FailureWithStderr { code, stderr_tail }) => (*code, stderr_tail.clone()),
+            _ => (None, vec![format!("{:#}", error)]),
+        };
+
+        return Self {
+            section: section.to_string(),
+            package: package.to_string(),
+            exit_code,
+            stderr_tail,
+        };
+    }
+}
+
+/// Failed packages of each installer file, by its path
+type Report = BTreeMap<String, Vec<FailedPackage>>;
+
+/// Where the report is kept
+pub fn report_path() -> anyhow::Result<PathBuf> {
+    return Ok(punto_state_dir()?.join(REPORT_FILE_NAME));
+}
+
+/// Records the `failures` of installing the `sections` of the installer file
+/// in `config_path`, replacing the previous failures of those sections
+pub fn record_failures(report_path: &Path, config_path: &Path, sections: &[&str], failures: Vec<FailedPackage>) -> anyhow::Result<()> {
+    let mut report = read_report(report_path)?;
+
+    let key = report_key(config_path);
+    let mut file_failures: Vec<FailedPackage> = report.remove(&key).unwrap_or_default()
+        .into_iter()
+        .filter(|failure| !sections.contains(&failure.section.as_str()))
+        .collect();
+    file_failures.extend(failures);
+    if !file_failures.is_empty() {
+        report.insert(key, file_failures);
+    }
+
+    if let Some(parent) = report_path.parent() {
+        fs::create_dir_all(parent).with_context(|| format!("Could not create {}", parent.display()))?;
+    }
+    let contents = serde_json::to_string_pretty(&report)?;
+    return atomic_write(report_path, contents.as_bytes()).with_context(|| format!("Could not write {}", report_path.display()));
+}
+
+/// Packages of the installer file in `config_path` that failed to install the last time
+pub fn previous_failures(report_path: &Path, config_path: &Path) -> anyhow::Result<Vec<FailedPackage>> {
+    return Ok(read_report(report_path)?.remove(&report_key(config_path)).unwrap_or_default());
+}
+
+fn read_report(report_path: &Path) -> anyhow::Result<Report> {
+    if !report_path.exists() {
+        return Ok(Report::new());
+    }
+
+    let contents = fs::read_to_string(report_path).with_context(|| format!("Could not read {}", report_path.display()))?;
+    return serde_json::from_str(&contents).with_context(|| format!("{} is not a valid report, remove it", report_path.display()));
+}
+
+/// The same installer file has the same key, from any working dir
+fn report_key(config_path: &Path) -> String {
+    return fs::canonicalize(config_path)
+        .unwrap_or_else(|_| config_path.to_path_buf())
+        .display()
+        .to_string();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use super::{previous_failures, record_failures, FailedPackage};
+
+    fn failure(section: &str, package: &str) -> FailedPackage {
+        return FailedPackage::new(section, package, &anyhow::anyhow!("target not found"));
+    }
+
+    #[test]
+    fn test_failures_are_replaced_per_section() {
+        let base_path = Path::new("test_failures_are_replaced_per_section");
+        let _ = fs::remove_dir_all(base_path);
+        fs::create_dir_all(base_path).unwrap();
+        let report_path = base_path.join("state/failed_packages.json");
+        let config_path = base_path.join("packages.yaml");
+        fs::write(&config_path, "").unwrap();
+
+        record_failures(&report_path, &config_path, &["cli", "aur"], vec![failure("cli", "htop"), failure("aur", "spotify")]).unwrap();
+        let failures = previous_failures(&report_path, &config_path).unwrap();
+        assert_eq!(failures.len(), 2);
+        assert_eq!(failures[0].exit_code, None);
+        assert_eq!(failures[0].stderr_tail, vec!["target not found"]);
+
+        // Installing a section again forgets its old failures, and keeps the other ones
+        record_failures(&report_path, &config_path, &["cli"], vec![]).unwrap();
+        assert_eq!(previous_failures(&report_path, &config_path).unwrap(), vec![failure("aur", "spotify")]);
+
+        fs::remove_dir_all(base_path).unwrap();
+    }
+}
@@ -0,0 +1,225 @@
+//! Lock file with the versions of the installed packages
+//!
+//! After installing, the version that the backend reports for each package is
+//! recorded, per section, in a `packages.lock` next to the installer file.
+//! Other machines can check that they have the same versions with `--locked`
+
+use std::collections::btree_map::Entry;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use lib_fileops::atomic_write;
+
+use crate::Errors;
+use crate::Installer::InstallerSection;
+
+/// Name of the lock file, placed next to the installer file
+const LOCK_FILE_NAME: &str = "packages.lock";
+
+/// What an installation does with the lock file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockMode {
+    /// Records the packages that are not locked yet, warning about the ones
+    /// whose installed version differs from the lock
+    Record,
+
+    /// Fails if an installed version differs from the lock, or is not locked.
+    /// The lock file is not changed
+    Locked,
+
+    /// Records the installed versions, replacing the locked ones
+    Update,
+}
+
+/// Locked versions, per section and package
+type Versions = BTreeMap<String, BTreeMap<String, String>>;
+
+/// Lock file of the installer file in `config_path`
+pub fn lock_path(config_path: &Path) -> PathBuf {
+    return config_path.with_file_name(LOCK_FILE_NAME);
+}
+
+/// Checks, before installing anything, that the packages of `sections` that
+/// are already installed have the versions of the lock file of `config_path`
+/// Used with `LockMode::Locked`, so a mismatch fails before the system is
+/// changed. Packages that are not installed yet are checked by `sync_lock`
+pub fn check_locked(config_path: &Path, sections: &[&InstallerSection]) -> anyhow::Result<()> {
+    let path = lock_path(config_path);
+    let locked = read_lock(&path)?;
+
+    let no_versions = BTreeMap::new();
+    let mut mismatches = vec![];
+    for section in sections {
+        let installed = installed_versions(section);
+        if Errors::interrupted() {
+            return Ok(());
+        }
+
+        let section_lock = locked.get(&section.name).unwrap_or(&no_versions);
+        mismatches.extend(lock_mismatches(&section.name, section_lock, &installed, true));
+    }
+
+    return report_mismatches(&path, &mismatches, true);
+}
+
+/// Compares the installed versions of the packages of `sections` with the
+/// lock file of `config_path`, and updates it as `mode` tells
+/// Packages whose version the backend can not tell are not locked
+pub fn sync_lock(config_path: &Path, sections: &[&InstallerSection], mode: LockMode) -> anyhow::Result<()> {
+    let path = lock_path(config_path);
+    let mut locked = read_lock(&path)?;
+
+    let mut changed = false;
+    let mut mismatches = vec![];
+    for section in sections {
+        let installed = installed_versions(section);
+        if Errors::interrupted() {
+            return Ok(());
+        }
+
+        let section_lock = locked.entry(section.name.clone()).or_default();
+        if mode == LockMode::Update {
+            changed |= *section_lock != installed;
+            *section_lock = installed;
+            continue;
+        }
+
+        mismatches.extend(lock_mismatches(&section.name, section_lock, &installed, mode == LockMode::Locked));
+        if mode == LockMode::Record {
+            for (package, version) in installed {
+                if let Entry::Vacant(entry) = section_lock.entry(package) {
+                    entry.insert(version);
+                    changed = true;
+                }
+            }
+        }
+    }
+
+    report_mismatches(&path, &mismatches, mode == LockMode::Locked)?;
+
+    if changed {
+        locked.retain(|_, packages| !packages.is_empty());
+        let contents = toml::to_string_pretty(&locked).context("Could not write the locked versions")?;
+        atomic_write(&path, contents.as_bytes()).with_context(|| format!("Could not write {}", path.display()))?;
+        println!("🔒 Installed versions written to {}", path.display());
+    }
+
+    return Ok(());
+}
+
+/// Differences between the `installed` versions of the packages of the
+/// section `section_name` and its `section_lock`
+/// Installed packages that are not locked are a difference if `require_locked`
+fn lock_mismatches(
+    section_name: &str,
+    section_lock: &BTreeMap<String, String>,
+    installed: &BTreeMap<String, String>,
+    require_locked: bool,
+) -> Vec<String> {
+    let mut mismatches = vec![];
+    for (package, version) in installed {
+        match section_lock.get(package) {
+            Some(locked_version) if locked_version != version => mismatches.push(format!(
+                "{} of section {} is locked to {}, but {} is installed", package, section_name, locked_version, version,
+            )),
+            None if require_locked => mismatches.push(format!(
+                "{} of section {} is not locked, {} is installed", package, section_name, version,
+            )),
+            _ => {},
+        }
+    }
+
+    return mismatches;
+}
+
+/// Warns about every mismatch with the lock file in `path`, failing if `fatal`
+fn report_mismatches(path: &Path, mismatches: &[String], fatal: bool) -> anyhow::Result<()> {
+    for mismatch in mismatches {
+        eprintln!("⚠️  {}", mismatch);
+    }
+    if fatal && !mismatches.is_empty() {
+        anyhow::bail!("{} packages differ from {}, run with --update-lock to lock them", mismatches.len(), path.display());
+    }
+
+    return Ok(());
+}
+
+/// Versions of the packages of `section` that are installed
+fn installed_versions(section: &InstallerSection) -> BTreeMap<String, String> {
+    let mut versions = BTreeMap::new();
+    for package in &section.packages {
+        if Errors::interrupted() {
+            break;
+        }
+
+        if let Ok(Some(version)) = section.backend.installed_version(package) {
+            versions.insert(package.to_string(), version);
+        }
+    }
+
+    return versions;
+}
+
+/// Reads the lock file in `path`. A missing lock file has no versions
+fn read_lock(path: &Path) -> anyhow::Result<Versions> {
+    if !path.exists() {
+        return Ok(Versions::new());
+    }
+
+    let contents = fs::read_to_string(path).with_context(|| format!("Could not read {}", path.display()))?;
+    return toml::from_str(&contents).with_context(|| format!("{} is not a valid lock file", path.display()));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use super::{check_locked, lock_path, read_lock, sync_lock, LockMode};
+    use crate::Installer::backends::CommandBackend;
+    use crate::Installer::InstallerSection;
+    use crate::Schemas::BackendSchema;
+
+    /// Section whose packages are installed with version `version`
+    fn section_with_version(version: &str) -> InstallerSection {
+        let backend = CommandBackend::from_schema("fake", &BackendSchema {
+            install: "true".to_string(),
+            is_installed: None,
+            version: Some(format!("echo {{package}} {}", version)),
+            remove: None,
+            list_installed: None,
+            sudo: false,
+        }).unwrap();
+        return InstallerSection::new("cli".to_string(), Box::new(backend), vec!["git".to_string(), "vim".to_string()]);
+    }
+
+    #[test]
+    fn test_locked_versions_are_checked_and_updated() {
+        let base_path = Path::new("test_locked_versions_are_checked_and_updated");
+        let _ = fs::remove_dir_all(base_path);
+        fs::create_dir_all(base_path).unwrap();
+        let config_path = base_path.join("packages.yaml");
+
+        // The first install locks the versions
+        sync_lock(&config_path, &[&section_with_version("1.0")], LockMode::Record).unwrap();
+        let locked = read_lock(&lock_path(&config_path)).unwrap();
+        assert_eq!(locked["cli"]["git"], "1.0");
+        assert_eq!(locked["cli"]["vim"], "1.0");
+
+        // Other versions are reported, failing with --locked before and after
+        // installing, but not recorded
+        assert!(check_locked(&config_path, &[&section_with_version("1.0")]).is_ok());
+        assert!(check_locked(&config_path, &[&section_with_version("2.0")]).is_err());
+        sync_lock(&config_path, &[&section_with_version("2.0")], LockMode::Record).unwrap();
+        assert!(sync_lock(&config_path, &[&section_with_version("2.0")], LockMode::Locked).is_err());
+        assert_eq!(read_lock(&lock_path(&config_path)).unwrap(), locked);
+
+        sync_lock(&config_path, &[&section_with_version("2.0")], LockMode::Update).unwrap();
+        assert_eq!(read_lock(&lock_path(&config_path)).unwrap()["cli"]["git"], "2.0");
+        assert!(sync_lock(&config_path, &[&section_with_version("2.0")], LockMode::Locked).is_ok());
+
+        fs::remove_dir_all(base_path).unwrap();
+    }
+}
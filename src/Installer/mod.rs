@@ -0,0 +1,734 @@
+mod backends;
+mod capture;
+mod conditions;
+mod failures;
+mod lock;
+mod prune;
+
+pub use capture::handle_capture;
+pub use lock::LockMode;
+pub use prune::handle_prune;
+
+use crate::ConfigLoader::{self, ConfigFormat};
+use crate::Diagnostics::{self, Diagnostic};
+use crate::Errors::{self, PuntoError};
+use crate::Installer::backends::{CommandBackend, PackageBackend};
+use crate::Installer::conditions::SystemFacts;
+use crate::Installer::failures::FailedPackage;
+use crate::Schemas::{self, BackendSchema, InstallerFile, InstallerSectionSchema, WhenSchema};
+use std::path::{Path, PathBuf};
+use anyhow::Context;
+
+/// Represents a section of a installer .yaml specification
+struct InstallerSection {
+
+    /// Name of the installer section
+    name: String,
+
+    /// Package manager used to install all packages in this section
+    backend: Box<dyn PackageBackend>,
+
+    /// List of packages to install
+    packages: Vec<String>,
+
+    /// Packages of the backend that are not installed by punto, but must not be pruned
+    ignore_unmanaged: Vec<String>,
+
+    /// Conditions for the section to be installed
+    when: Option<WhenSchema>,
+
+    /// Sections that must be installed before this one
+    after: Vec<String>,
+}
+
+impl InstallerSection {
+    /// Creates a new InstallerSection struct
+    pub fn new(name: String, backend: Box<dyn PackageBackend>, packages: Vec<String>) -> Self {
+        return InstallerSection {
+            name,
+            backend,
+            packages,
+            ignore_unmanaged: vec![],
+            when: None,
+            after: vec![],
+        };
+    }
+
+    /// Sets the sections that must be installed before this one
+    pub fn with_after(mut self, after: Vec<String>) -> Self {
+        self.after = after;
+        return self;
+    }
+
+    /// First section of `after` that is in `skipped`, so this one can not be installed
+    fn skipped_dependency(&self, skipped: &[&str]) -> Option<&String> {
+        return self.after.iter().find(|after| skipped.contains(&after.as_str()));
+    }
+
+    /// Sets the conditions for the section to be installed
+    pub fn with_when(mut self, when: Option<WhenSchema>) -> Self {
+        self.when = when;
+        return self;
+    }
+
+    /// Why the section must not be installed in this machine, or None if it must
+    fn skip_reason(&self, facts: &SystemFacts) -> Option<String> {
+        return conditions::unmet_condition(self.when.as_ref()?, facts, self.backend.as_ref());
+    }
+
+    /// Sets the packages that are installed by hand
+    pub fn with_ignore_unmanaged(mut self, ignore_unmanaged: Vec<String>) -> Self {
+        self.ignore_unmanaged = ignore_unmanaged;
+        return self;
+    }
+
+    /// Installs the packages described in the InstallerSection that are not installed yet
+    /// All of them are installed at once, and only if that fails they are installed one by one,
+    /// to know which ones failed
+    /// Returns how many packages it tried to install, along with the packages that failed to
+    /// install in this section or None if no package failed to install
+    pub fn install_all_packages(&self) -> (usize, Option<FailedPackages>){
+
+        let mut missing_packages = vec![];
+        for (package, status) in self.package_status() {
+            if status == PackageStatus::Installed {
+                println!("--> {} is already installed", package);
+                continue;
+            }
+            missing_packages.push(package.as_str());
+
+            if Errors::interrupted() {
+                return (0, None);
+            }
+        }
+
+        if missing_packages.is_empty() {
+            return (0, None);
+        }
+        let batch_error = match self.backend.install_all(&missing_packages) {
+            Ok(()) => return (missing_packages.len(), None),
+            Err(err) => err,
+        };
+
+        // Packages that failed to install
+        let mut failed_packages = FailedPackages::new(self.name.clone());
+
+        // A failed install of a single package needs no retry
+        if missing_packages.len() == 1 {
+            failed_packages.push(missing_packages[0], &batch_error);
+            return (1, Some(failed_packages));
+        }
+
+        // The batch can fail for a single package, so they are installed one by one
+        println!("--> Installing all packages at once failed, installing them one by one");
+        for package in &missing_packages {
+            if Errors::interrupted() {
+                break;
+            }
+
+            // The batch may have installed some packages before failing
+            if let Ok(true) = self.backend.is_installed(package) {
+                continue;
+            }
+
+            // Run the command. If it fails, add to the list of failed commands
+            if let Err(err) = self.backend.install(package) {
+                failed_packages.push(package, &err);
+            }
+        }
+
+        // No failed packages generated, return None
+        if failed_packages.is_empty(){
+            return (missing_packages.len(), None);
+        }
+
+        return (missing_packages.len(), Some(failed_packages));
+    }
+
+    /// Whether each package of the section is installed, asking its backend
+    /// Packages are queried lazily, so an interrupted installation stops querying too
+    fn package_status(&self) -> impl Iterator<Item = (&String, PackageStatus)> {
+        return self.packages.iter().map(move |package| {
+            let status = match self.backend.is_installed(package) {
+                Ok(true) => PackageStatus::Installed,
+                Ok(false) => PackageStatus::Missing,
+
+                // Sections with only an `install_command` can not be queried
+                Err(_) => PackageStatus::Unknown,
+            };
+            return (package, status);
+        });
+    }
+}
+
+/// Whether a package is installed in the system
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PackageStatus {
+    Installed,
+    Missing,
+
+    /// The backend could not tell it
+    Unknown,
+}
+
+impl PackageStatus {
+    fn label(&self) -> &'static str {
+        return match self {
+            PackageStatus::Installed => "✅ installed",
+            PackageStatus::Missing => "❌ missing",
+            PackageStatus::Unknown => "❔ unknown",
+        };
+    }
+}
+
+#[cfg(test)]
+mod test_installer_section{
+    use std::fs;
+    use std::path::Path;
+
+    use super::{pick_sections, skip_reasons, InstallerSection, PackageStatus, SectionSelection};
+    use super::backends::CommandBackend;
+    use super::conditions::SystemFacts;
+    use crate::Schemas::{BackendSchema, OneOrMany, WhenSchema};
+
+    #[test]
+    pub fn test_installed_packages_are_skipped() {
+        let base_path = Path::new("test_installed_packages_are_skipped");
+        let _ = fs::remove_dir_all(base_path);
+        fs::create_dir_all(base_path).unwrap();
+        fs::write(base_path.join("installed"), "").unwrap();
+
+        // Packages are files, installed by creating them
+        let backend = CommandBackend::from_schema("files", &BackendSchema {
+            install: "touch {package}".to_string(),
+            is_installed: Some("test -e {package}".to_string()),
+            version: None,
+            remove: None,
+            list_installed: None,
+            sudo: false,
+        }).unwrap();
+        let packages = ["installed", "missing", "no_dir/broken"]
+            .iter()
+            .map(|package| base_path.join(package).display().to_string())
+            .collect();
+        let section_backend = backend.clone();
+        let section = InstallerSection::new("files".to_string(), Box::new(backend), packages);
+
+        let status: Vec<PackageStatus> = section.package_status().map(|(_, status)| status).collect();
+        assert_eq!(status, vec![PackageStatus::Installed, PackageStatus::Missing, PackageStatus::Missing]);
+
+        // Only missing packages are installed, and only those are counted
+        // The batch fails, and installing one by one finds the broken package
+        let (attempted, failed_packages) = section.install_all_packages();
+        assert_eq!(attempted, 2);
+        let failed_packages = failed_packages.unwrap().failed_packages;
+        assert_eq!(failed_packages.len(), 1);
+        assert_eq!(failed_packages[0].package, base_path.join("no_dir/broken").display().to_string());
+        assert_eq!(failed_packages[0].exit_code, Some(1));
+        assert!(failed_packages[0].stderr_tail[0].contains("no_dir"));
+        assert!(base_path.join("missing").exists());
+
+        // A batch that works installs all the packages
+        let packages: Vec<String> = ["first", "second"].iter().map(|package| base_path.join(package).display().to_string()).collect();
+        let section = InstallerSection::new("files".to_string(), Box::new(section_backend.clone()), packages);
+        assert_eq!(section.install_all_packages().0, 2);
+        assert!(base_path.join("first").exists() && base_path.join("second").exists());
+
+        fs::remove_dir_all(base_path).unwrap();
+    }
+
+    #[test]
+    pub fn test_sections_are_picked_by_glob() {
+        let sections: Vec<InstallerSection> = ["base", "desktop-kde", "desktop-fonts", "work"]
+            .iter()
+            .map(|name| InstallerSection::new(name.to_string(), Box::new(CommandBackend::from_install_command("true", false).unwrap()), vec![]))
+            .collect();
+        let picked = |selection: SectionSelection| -> Vec<String> {
+            return pick_sections(&sections, &selection).unwrap().iter().map(|section| section.name.clone()).collect();
+        };
+
+        assert_eq!(picked(SectionSelection::default()).len(), 4);
+        assert_eq!(picked(SectionSelection { sections: vec!["work", "desktop-*"], ..Default::default() }), vec!["desktop-kde", "desktop-fonts", "work"]);
+        assert_eq!(picked(SectionSelection { skip_sections: vec!["desktop-k?e", "work"], ..Default::default() }), vec!["base", "desktop-fonts"]);
+
+        // Unknown sections are an error, with a suggestion
+        let err = pick_sections(&sections, &SectionSelection { sections: vec!["wrk"], ..Default::default() }).err().unwrap();
+        assert!(format!("{}", err).contains("did you mean `work`?"), "Got: {}", err);
+    }
+
+    #[test]
+    pub fn test_sections_after_skipped_ones_are_skipped() {
+        let section = |name: &str, after: &[&str]| {
+            return InstallerSection::new(name.to_string(), Box::new(CommandBackend::from_install_command("true", false).unwrap()), vec![])
+                .with_after(after.iter().map(|after| after.to_string()).collect());
+        };
+        let work_only = WhenSchema {
+            distro: None,
+            hostname: None,
+            profile: Some(OneOrMany::One("work".to_string())),
+            program_on_path: false,
+        };
+        let sections = vec![
+            section("base", &[]),
+            section("desktop", &["base"]),
+            section("fonts", &["desktop"]),
+            section("work", &[]).with_when(Some(work_only)),
+            section("work-tools", &["work"]),
+        ];
+        let reasons = |selection: SectionSelection| -> Vec<(String, Option<String>)> {
+            let picked = pick_sections(&sections, &selection).unwrap();
+            return skip_reasons(&sections, picked, &SystemFacts::detect(&selection.profiles))
+                .into_iter()
+                .map(|(section, reason)| (section.name.clone(), reason))
+                .collect();
+        };
+
+        // Skipped by --skip-section, and by `when`, along with the sections after them
+        let skipped = reasons(SectionSelection { skip_sections: vec!["base"], ..Default::default() });
+        assert_eq!(skipped, vec![
+            ("desktop".to_string(), Some("depends on base, that is skipped".to_string())),
+            ("fonts".to_string(), Some("depends on desktop, that is skipped".to_string())),
+            ("work".to_string(), Some("profile work is not active, pass it with --profile".to_string())),
+            ("work-tools".to_string(), Some("depends on work, that is skipped".to_string())),
+        ]);
+
+        // Not picked by --section
+        let skipped = reasons(SectionSelection { sections: vec!["fonts", "work*"], profiles: vec!["work"], ..Default::default() });
+        assert_eq!(skipped, vec![
+            ("fonts".to_string(), Some("depends on desktop, that is skipped".to_string())),
+            ("work".to_string(), None),
+            ("work-tools".to_string(), None),
+        ]);
+    }
+}
+
+/// List of packages that failed to install
+/// This packages belong to the same InstallerSection
+struct FailedPackages{
+    section_name: String,
+    failed_packages: Vec<FailedPackage>,
+}
+
+impl FailedPackages{
+    /// Creates a new FailedPackages instance
+    pub fn new(section_name: String) -> Self{
+        return Self{
+            section_name,
+            failed_packages: vec![],
+        };
+    }
+
+    /// Displays to the user all packages that failed to install in a section
+    // TODO -- use termion crate for colored output
+    pub fn show_failed_packages(&self) {
+        eprintln!("==> Some packages in section {} failed to install", self.section_name);
+        for failed in &self.failed_packages {
+            let exit_code = failed.exit_code.map(|code| format!(" (exit code {})", code)).unwrap_or_default();
+            let reason = failed.stderr_tail.last().map(|line| format!(": {}", line)).unwrap_or_default();
+            eprintln!("--> {}{}{}", failed.package, exit_code, reason);
+        }
+    }
+
+    pub fn len(&self) -> usize{
+        return self.failed_packages.len();
+    }
+
+    pub fn is_empty(&self) -> bool{
+        return self.failed_packages.is_empty();
+    }
+
+    /// Adds `package`, that failed to install with `error`
+    pub fn push(&mut self, package: &str, error: &anyhow::Error){
+        self.failed_packages.push(FailedPackage::new(&self.section_name, package, error));
+    }
+
+}
+
+/// Sections that the user picks from the cli
+#[derive(Debug, Default)]
+pub struct SectionSelection<'a> {
+    /// Glob patterns of the sections to work with. If empty, all sections are picked
+    pub sections: Vec<&'a str>,
+
+    /// Glob patterns of the sections to leave out
+    pub skip_sections: Vec<&'a str>,
+
+    /// Active profiles, that sections can require in their `when`
+    pub profiles: Vec<&'a str>,
+}
+
+/// Callback for --install cli arg
+/// # Arguments
+/// - `file_path`: file path of the config file containing install specification
+/// - `format`: overrides the format given by the extension of the file
+/// - `selection`: sections of the specification to install
+/// - `lock_mode`: how the installed versions are checked against the lock file
+/// - `retry_failed`: installs only the packages that failed to install the last time
+pub fn handle_install_command(
+    file_path: &Path,
+    format: Option<ConfigFormat>,
+    selection: &SectionSelection,
+    lock_mode: LockMode,
+    retry_failed: bool,
+) -> Result<(), PuntoError> {
+    let mut installer_sections = parse_installer(file_path, format)
+        .map_err(|err| PuntoError::config(file_path, err))?;
+
+    if retry_failed {
+        let previous = failures::report_path()
+            .and_then(|report_path| failures::previous_failures(&report_path, file_path))
+            .map_err(|err| PuntoError::total_failure("Reading failed packages", err))?;
+        if previous.is_empty() {
+            println!("✅ No packages of {} failed to install the last time", file_path.display());
+            return Ok(());
+        }
+
+        for section in &mut installer_sections {
+            let name = &section.name;
+            section.packages.retain(|package| previous.iter().any(|failed| &failed.section == name && &failed.package == package));
+        }
+    }
+
+    if selection.sections.is_empty() {
+        println!("📦 Installing packages -- all sections");
+    } else {
+        println!("📦 Installing packages -- sections {}", selection.sections.join(", "));
+    }
+    let mut picked_sections = pick_sections(&installer_sections, selection)
+        .map_err(|err| PuntoError::config(file_path, err))?;
+
+    // Sections without failed packages have nothing to retry
+    if retry_failed {
+        picked_sections.retain(|section| !section.packages.is_empty());
+    }
+
+    // Sections that do not apply to this machine are reported, not failed
+    let facts = SystemFacts::detect(&selection.profiles);
+    let mut sections_to_install = vec![];
+    for (section, skip_reason) in skip_reasons(&installer_sections, picked_sections, &facts) {
+        match skip_reason {
+            Some(reason) => println!("⏭️  Skipping section {}: {}", section.name, reason),
+            None => sections_to_install.push(section),
+        }
+    }
+
+    // With --locked, installed packages are checked before changing anything
+    if lock_mode == LockMode::Locked {
+        lock::check_locked(file_path, &sections_to_install)
+            .map_err(|err| PuntoError::total_failure("Checking locked versions", err))?;
+        if Errors::interrupted() {
+            return Err(PuntoError::UserAbort);
+        }
+    }
+
+    let (installed_sections, attempted, failed_packages_per_section) = install_sections(&sections_to_install);
+
+    if Errors::interrupted() {
+        return Err(PuntoError::UserAbort);
+    }
+
+    // Failures are kept for --retry-failed, replacing the old ones of these sections
+    let installed_names: Vec<&str> = installed_sections.iter().map(|section| section.name.as_str()).collect();
+    let all_failures: Vec<FailedPackage> = failed_packages_per_section.iter().flat_map(|failed| failed.failed_packages.clone()).collect();
+    let report = failures::report_path()
+        .and_then(|report_path| failures::record_failures(&report_path, file_path, &installed_names, all_failures).map(|_| report_path));
+
+    // Versions of the packages that were installed are locked, even if others failed
+    let lock_result = lock::sync_lock(file_path, &installed_sections, lock_mode);
+    if Errors::interrupted() {
+        return Err(PuntoError::UserAbort);
+    }
+
+    // All went good
+    if failed_packages_per_section.is_empty(){
+        if attempted == 0 {
+            println!("✅ All packages are already installed");
+        }
+        return lock_result.map_err(|err| PuntoError::partial_failure("Checking locked versions", err));
+    }
+
+    if let Err(err) = lock_result {
+        eprintln!("⚠️  {:#}", err);
+    }
+
+    // Some pacakges failed to install, show them with their section
+    println!("Some packages failed to install. Showing them per section");
+    for failed_packages in &failed_packages_per_section{
+        failed_packages.show_failed_packages();
+        println!();
+    }
+    match report {
+        Ok(report_path) => println!(
+            "The report is kept in {}. Run `punto --install {} --retry-failed` to install them again",
+            report_path.display(), file_path.display(),
+        ),
+        Err(err) => eprintln!("⚠️  Could not keep the report of failed packages: {:#}", err),
+    }
+
+    // Packages that were already installed are not part of the failure
+    let failed: usize = failed_packages_per_section.iter().map(|failed_packages| failed_packages.len()).sum();
+    let source = anyhow::anyhow!("{} of {} packages failed to install", failed, attempted);
+    if failed == attempted {
+        return Err(PuntoError::total_failure("Installing packages", source));
+    }
+    return Err(PuntoError::partial_failure("Installing packages", source));
+}
+
+/// Callback for --install cli arg along with --status
+/// Shows which packages of each section are installed, without installing anything
+/// Takes the same arguments as `handle_install_command`, but the lock mode
+pub fn handle_status(file_path: &Path, format: Option<ConfigFormat>, selection: &SectionSelection) -> Result<(), PuntoError> {
+    let installer_sections = parse_installer(file_path, format)
+        .map_err(|err| PuntoError::config(file_path, err))?;
+    let sections_to_show = pick_sections(&installer_sections, selection)
+        .map_err(|err| PuntoError::config(file_path, err))?;
+
+    let facts = SystemFacts::detect(&selection.profiles);
+    for (section, skip_reason) in skip_reasons(&installer_sections, sections_to_show, &facts) {
+        if let Some(reason) = skip_reason {
+            println!("⏭️  Section {} ({}) is skipped: {}", section.name, section.backend.name(), reason);
+            continue;
+        }
+
+        println!("📦 Section {} ({})", section.name, section.backend.name());
+        for (package, status) in section.package_status() {
+            println!("    {:<12} {}", status.label(), package);
+
+            if Errors::interrupted() {
+                return Err(PuntoError::UserAbort);
+            }
+        }
+    }
+
+    return Ok(());
+}
+
+/// Callback for --install cli arg along with --list-sections
+/// Shows the sections in the order they are installed, with their backend and conditions
+/// Takes the same arguments as `handle_status`
+pub fn handle_list_sections(file_path: &Path, format: Option<ConfigFormat>, selection: &SectionSelection) -> Result<(), PuntoError> {
+    let installer_sections = parse_installer(file_path, format)
+        .map_err(|err| PuntoError::config(file_path, err))?;
+    let sections_to_show = pick_sections(&installer_sections, selection)
+        .map_err(|err| PuntoError::config(file_path, err))?;
+
+    let facts = SystemFacts::detect(&selection.profiles);
+    for (section, skip_reason) in skip_reasons(&installer_sections, sections_to_show, &facts) {
+        let skipped = skip_reason.map(|reason| format!(" -- skipped: {}", reason)).unwrap_or_default();
+        println!("{} ({}, {} packages){}", section.name, section.backend.name(), section.packages.len(), skipped);
+    }
+
+    return Ok(());
+}
+
+/// Sections to work with: the ones matching the patterns in `selection.sections`,
+/// or all of them if there are none, without the ones matching `selection.skip_sections`
+/// Patterns that match no section are an error, as they are most likely a typo
+fn pick_sections<'a>(installer_sections: &'a [InstallerSection], selection: &SectionSelection) -> anyhow::Result<Vec<&'a InstallerSection>> {
+    let names: Vec<String> = installer_sections.iter().map(|section| section.name.clone()).collect();
+    for pattern in selection.sections.iter().chain(&selection.skip_sections) {
+        if !names.iter().any(|name| matches_section(pattern, name)) {
+            let hint = Diagnostics::did_you_mean(pattern, &names)
+                .unwrap_or_else(|| format!("sections are {}", names.join(", ")));
+            return Err(Diagnostic::new(format!("there is no section named `{}`", pattern)).with_hint(Some(hint)).into());
+        }
+    }
+
+    let is_picked = |section: &&InstallerSection| {
+        let selected = selection.sections.is_empty() || selection.sections.iter().any(|pattern| matches_section(pattern, &section.name));
+        return selected && !selection.skip_sections.iter().any(|pattern| matches_section(pattern, &section.name));
+    };
+    return Ok(installer_sections.iter().filter(is_picked).collect());
+}
+
+/// Why each of the `picked` sections must not be installed in this machine, or
+/// None if it must. Sections that are not picked or whose conditions do not
+/// hold are skipped, and so are the sections that are after them
+fn skip_reasons<'a>(
+    installer_sections: &'a [InstallerSection],
+    picked: Vec<&'a InstallerSection>,
+    facts: &SystemFacts,
+) -> Vec<(&'a InstallerSection, Option<String>)> {
+    let mut skipped: Vec<&str> = installer_sections.iter()
+        .filter(|section| !picked.iter().any(|picked| picked.name == section.name))
+        .map(|section| section.name.as_str())
+        .collect();
+
+    // Sections are ordered, so the ones in `after` are always decided first
+    let mut reasons = vec![];
+    for section in picked {
+        let reason = match section.skipped_dependency(&skipped) {
+            Some(dependency) => Some(format!("depends on {}, that is skipped", dependency)),
+            None => section.skip_reason(facts),
+        };
+        if reason.is_some() {
+            skipped.push(&section.name);
+        }
+        reasons.push((section, reason));
+    }
+
+    return reasons;
+}
+
+/// Whether the section `name` matches the glob `pattern`, as "desktop-*"
+fn matches_section(pattern: &str, name: &str) -> bool {
+    return match glob::Pattern::new(pattern) {
+        Ok(glob) => glob.matches(name),
+
+        // Not a valid glob, so use it as a literal name
+        Err(_) => pattern == name,
+    };
+}
+
+/// Installs all the given sections, but the ones after a section where some package failed
+/// Returns the sections it installed and how many packages it tried to install, along with
+/// failed packages per section, only for sections where some package failed
+fn install_sections<'a>(installer_sections: &[&'a InstallerSection]) -> (Vec<&'a InstallerSection>, usize, Vec<FailedPackages>) {
+
+    // Failed packages to install at each installer section
+    let mut failed_packages_per_section = vec![];
+    let mut attempted = 0;
+    let mut installed_sections = vec![];
+
+    // Sections that failed or were not installed because of that
+    let mut skipped: Vec<&str> = vec![];
+
+    for section in installer_sections {
+        if let Some(dependency) = section.skipped_dependency(&skipped) {
+            println!("⏭️  Skipping section {}: depends on {}, that failed", section.name, dependency);
+            skipped.push(&section.name);
+            continue;
+        }
+
+        println!("Installing {} section with {}", section.name, section.backend.name());
+        println!(
+            "================================================================================"
+        );
+
+        // Install all packages, storing failed ones if some failed
+        let (section_attempted, failed_packages) = section.install_all_packages();
+        attempted += section_attempted;
+        installed_sections.push(*section);
+        if let Some(failed_packages) = failed_packages {
+            skipped.push(&section.name);
+            failed_packages_per_section.push(failed_packages);
+        }
+
+        if Errors::interrupted() {
+            break;
+        }
+    }
+
+    return (installed_sections, attempted, failed_packages_per_section);
+}
+
+/// Given a installer config file, returns a vector with its InstallerSection
+fn parse_installer(file_path: &Path, format: Option<ConfigFormat>) -> anyhow::Result<Vec<InstallerSection>> {
+    // Includes and defaults are resolved while loading
+    let (loaded, sources) = ConfigLoader::load_with_sources(file_path, format).context("Could not load the installer file")?;
+    let parsed: InstallerFile = Schemas::from_loaded(loaded)
+        .map_err(|err| Diagnostic::from_schema_error(err, &sources))?;
+
+    let distros = conditions::detect_distros();
+    let mut installer_blocks = vec![];
+    for (name, section) in order_sections(parsed.sections, &sources)? {
+        let backend = section_backend(&name, &section, &parsed.backends, &sources)?;
+
+        // Packages can have other names in other machines
+        let mut packages = vec![];
+        for package in &section.packages {
+            match conditions::resolve_package(package, backend.name(), &distros) {
+                Some(resolved) => packages.push(resolved),
+                None => eprintln!(
+                    "⚠️  Package {} of section {} has no name for this machine, give it a `default` one. Skipping it",
+                    package.names().iter().map(|name| name.as_str()).collect::<Vec<&str>>().join("/"), name,
+                ),
+            }
+        }
+
+        installer_blocks.push(
+            InstallerSection::new(name, backend, packages)
+                .with_ignore_unmanaged(section.ignore_unmanaged)
+                .with_when(section.when)
+                .with_after(section.after)
+        );
+    }
+
+    return Ok(installer_blocks);
+}
+
+/// Orders the sections so each one comes after the sections in its `after`,
+/// keeping the order of the file otherwise
+fn order_sections(
+    sections: Vec<(String, InstallerSectionSchema)>,
+    sources: &[PathBuf],
+) -> Result<Vec<(String, InstallerSectionSchema)>, Diagnostic> {
+    let names: Vec<String> = sections.iter().map(|(name, _)| name.clone()).collect();
+    for (name, section) in &sections {
+        if let Some(unknown) = section.after.iter().find(|after| !names.contains(after)) {
+            return Err(Diagnostic::new(format!("section `{}` is after `{}`, that is not a section", name, unknown))
+                .at(Diagnostics::locate_key(sources, Some(name), "after"))
+                .with_hint(Diagnostics::did_you_mean(unknown, &names)));
+        }
+    }
+
+    let mut pending = sections;
+    let mut ordered: Vec<(String, InstallerSectionSchema)> = vec![];
+    while !pending.is_empty() {
+        let is_placed = |after: &String| ordered.iter().any(|(name, _)| name == after);
+        match pending.iter().position(|(_, section)| section.after.iter().all(is_placed)) {
+            Some(ready) => ordered.push(pending.remove(ready)),
+            None => {
+                let cycle: Vec<String> = pending.iter().map(|(name, _)| format!("`{}`", name)).collect();
+                return Err(Diagnostic::new(format!("sections {} are after each other", cycle.join(", ")))
+                    .at(Diagnostics::locate_key(sources, Some(&pending[0].0), "after"))
+                    .with_hint(Some("remove one of them from the `after` of the others".to_string())));
+            },
+        }
+    }
+
+    return Ok(ordered);
+}
+
+/// Backend that installs the packages of the section `name`: the one it
+/// references with `backend`, or one running its `install_command`
+fn section_backend(
+    name: &str,
+    section: &InstallerSectionSchema,
+    declared: &[(String, BackendSchema)],
+    sources: &[PathBuf],
+) -> anyhow::Result<Box<dyn PackageBackend>> {
+    let backend = match (&section.backend, &section.install_command) {
+        (Some(backend), None) => backends::find_backend(backend, declared)
+            .ok_or_else(|| {
+                let mut known: Vec<String> = declared.iter().map(|(declared_name, _)| declared_name.clone()).collect();
+                known.extend(backends::builtin_names());
+                let hint = Diagnostics::did_you_mean(backend, &known)
+                    .unwrap_or_else(|| format!("declare it in `{}`, or use one of {}", ConfigLoader::BACKENDS_KEY, known.join(", ")));
+                Diagnostic::new(format!("unknown backend `{}` in block `{}`", backend, name))
+                    .at(Diagnostics::locate_key(sources, Some(name), "backend"))
+                    .with_hint(Some(hint))
+            })??
+            .with_sudo(section.sudo),
+
+        (None, Some(install_command)) => {
+            let sudo = section.sudo.ok_or_else(|| Diagnostic::new(format!("missing field `sudo` in block `{}`", name))
+                .at(Diagnostics::locate_key(sources, None, name))
+                .with_hint(Some("sections with `install_command` must tell if they use sudo".to_string())))?;
+            CommandBackend::from_install_command(install_command, sudo)
+                .with_context(|| format!("Install command of section {} is not valid", name))?
+        },
+
+        (Some(_), Some(_)) => return Err(Diagnostic::new(format!("block `{}` gives both `backend` and `install_command`", name))
+            .at(Diagnostics::locate_key(sources, Some(name), "install_command"))
+            .with_hint(Some("give only one of them".to_string()))
+            .into()),
+
+        (None, None) => return Err(Diagnostic::new(format!("block `{}` has no `backend` nor `install_command`", name))
+            .at(Diagnostics::locate_key(sources, None, name))
+            .with_hint(Some(format!("use one of the backends {}", backends::builtin_names().join(", "))))
+            .into()),
+    };
+
+    return Ok(Box::new(backend));
+}
@@ -0,0 +1,159 @@
+//! Removes the packages that are installed but not declared in the installer file
+//!
+//! The packages of the installer file are the source of truth: any package
+//! that a backend lists as explicitly installed, and that no section with that
+//! backend declares or ignores in `ignore_unmanaged`, is unmanaged
+
+use std::collections::HashSet;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+
+use crate::ConfigLoader::ConfigFormat;
+use crate::Errors::{self, PuntoError};
+use crate::Installer::backends::PackageBackend;
+use crate::Installer::{parse_installer, InstallerSection};
+
+/// Packages of a backend that no section declares
+struct Unmanaged<'a> {
+    backend: &'a dyn PackageBackend,
+    packages: Vec<String>,
+}
+
+/// Callback for --install cli arg along with --prune
+/// Shows the unmanaged packages of every backend, and removes them once the
+/// user confirms it
+/// `format` overrides the format given by the extension of the file
+pub fn handle_prune(file_path: &Path, format: Option<ConfigFormat>) -> Result<(), PuntoError> {
+    let installer_sections = parse_installer(file_path, format)
+        .map_err(|err| PuntoError::config(file_path, err))?;
+
+    let unmanaged = unmanaged_packages(&installer_sections);
+    let total: usize = unmanaged.iter().map(|unmanaged| unmanaged.packages.len()).sum();
+    if total == 0 {
+        println!("✅ There are no unmanaged packages");
+        return Ok(());
+    }
+
+    println!("🧹 Packages installed but not declared in {}", file_path.display());
+    for Unmanaged { backend, packages } in &unmanaged {
+        println!("==> {}", backend.name());
+        for package in packages {
+            println!("--> {}", package);
+        }
+    }
+
+    if !confirm(&format!("Remove these {} packages?", total)) {
+        println!("Nothing was removed");
+        return Ok(());
+    }
+
+    let mut failed = vec![];
+    for Unmanaged { backend, packages } in &unmanaged {
+        for package in packages {
+            if backend.remove(package).is_err() {
+                failed.push(package.as_str());
+            }
+
+            if Errors::interrupted() {
+                return Err(PuntoError::UserAbort);
+            }
+        }
+    }
+
+    if failed.is_empty() {
+        return Ok(());
+    }
+
+    eprintln!("==> Some packages failed to be removed");
+    for package in &failed {
+        eprintln!("--> {}", package);
+    }
+    let source = anyhow::anyhow!("{} of {} packages failed to be removed", failed.len(), total);
+    if failed.len() == total {
+        return Err(PuntoError::total_failure("Pruning packages", source));
+    }
+    return Err(PuntoError::partial_failure("Pruning packages", source));
+}
+
+/// Unmanaged packages of each backend used by the sections
+/// Backends that can not list their installed packages are skipped with a warning
+fn unmanaged_packages(sections: &[InstallerSection]) -> Vec<Unmanaged<'_>> {
+    let mut backend_names: Vec<&str> = vec![];
+    for section in sections {
+        if !backend_names.contains(&section.backend.name()) {
+            backend_names.push(section.backend.name());
+        }
+    }
+
+    let mut unmanaged = vec![];
+    for name in backend_names {
+        let backend_sections: Vec<&InstallerSection> = sections.iter().filter(|section| section.backend.name() == name).collect();
+        let backend = backend_sections[0].backend.as_ref();
+
+        let installed = match backend.list_installed() {
+            Ok(installed) => installed,
+            Err(err) => {
+                eprintln!("⚠️  Skipping {}: {:#}", name, err);
+                continue;
+            },
+        };
+
+        let declared: HashSet<&String> = backend_sections
+            .iter()
+            .flat_map(|section| section.packages.iter().chain(&section.ignore_unmanaged))
+            .collect();
+        let packages: Vec<String> = installed.into_iter().filter(|package| !declared.contains(package)).collect();
+        if !packages.is_empty() {
+            unmanaged.push(Unmanaged { backend, packages });
+        }
+    }
+
+    return unmanaged;
+}
+
+/// Asks the user a yes or no `question`. Anything but yes is a no
+fn confirm(question: &str) -> bool {
+    print!("{} [y/N] ", question);
+    let _ = io::stdout().flush();
+
+    let mut answer = String::new();
+    if io::stdin().lock().read_line(&mut answer).is_err() {
+        return false;
+    }
+
+    return matches!(answer.trim().to_lowercase().as_str(), "y" | "yes");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::unmanaged_packages;
+    use crate::Installer::backends::CommandBackend;
+    use crate::Installer::InstallerSection;
+    use crate::Schemas::BackendSchema;
+
+    #[test]
+    fn test_only_undeclared_packages_are_unmanaged() {
+        let backend = CommandBackend::from_schema("fake", &BackendSchema {
+            install: "true".to_string(),
+            is_installed: None,
+            version: None,
+            remove: Some("true".to_string()),
+            list_installed: Some("echo git\nhtop\nspotify\nvim".to_string()),
+            sudo: false,
+        }).unwrap();
+
+        // Sections with the same backend share its installed packages
+        let sections = vec![
+            InstallerSection::new("cli".to_string(), Box::new(backend.clone()), vec!["git".to_string()]),
+            InstallerSection::new("desktop".to_string(), Box::new(backend), vec!["vim".to_string()])
+                .with_ignore_unmanaged(vec!["spotify".to_string()]),
+            InstallerSection::new("raw".to_string(), Box::new(CommandBackend::from_install_command("true", false).unwrap()), vec![]),
+        ];
+
+        // The section without a backend can not list its packages, so it is skipped
+        let unmanaged = unmanaged_packages(&sections);
+        assert_eq!(unmanaged.len(), 1);
+        assert_eq!(unmanaged[0].backend.name(), "fake");
+        assert_eq!(unmanaged[0].packages, vec!["htop"]);
+    }
+}
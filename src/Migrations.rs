@@ -0,0 +1,284 @@
+//! Versions of the config files, and migrations from old versions
+//!
+//! Every config file gives its schema version in a top-level `version` key.
+//! Files written for an old version are migrated while they are loaded, with
+//! a deprecation warning, and `punto --migrate` rewrites them for good
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use lib_fileops::{atomic_copy, atomic_write};
+use serde_json::{Map, Value};
+
+use crate::ConfigLoader::{self, ConfigFormat};
+use crate::Errors::PuntoError;
+
+/// Version of the configs that this punto reads and writes
+pub const CURRENT_VERSION: u64 = 2;
+
+/// Version of the files that do not give one, written before versions existed
+const UNVERSIONED: u64 = 1;
+
+/// Key with the version of the config file
+const VERSION_KEY: &str = "version";
+
+/// Migrates the raw contents of a config file to the next version, returning
+/// a description of every change it made
+type Migrate = fn(&mut Map<String, Value>) -> Vec<String>;
+
+/// Migrations from each version to the next one. The migration at index `i`
+/// takes a file from version `i + 1` to version `i + 2`
+/// They return a description of every change they made
+const MIGRATIONS: [Migrate; 1] = [migrate_v1_to_v2];
+
+/// Changes made to a config file written for an old version
+#[derive(Debug)]
+pub struct Migration {
+    pub from: u64,
+    pub changes: Vec<String>,
+}
+
+/// Takes the `version` key out of the raw contents of a config file, and
+/// migrates them to the current version
+/// Returns None if the file already is in the current version
+pub fn upgrade(config: &mut Map<String, Value>) -> anyhow::Result<Option<Migration>> {
+    let version = match ConfigLoader::take_key(config, VERSION_KEY) {
+        None => UNVERSIONED,
+        Some(version) => version.as_u64()
+            .filter(|version| *version >= UNVERSIONED)
+            .with_context(|| format!("`{}` must be a positive integer, found {}", VERSION_KEY, version))?,
+    };
+
+    if version > CURRENT_VERSION {
+        anyhow::bail!(
+            "The file is written for config version {}, but this punto only knows up to version {}. Update punto",
+            version, CURRENT_VERSION,
+        );
+    }
+
+    if version == CURRENT_VERSION {
+        return Ok(None);
+    }
+
+    let mut changes = vec![];
+    for migration in &MIGRATIONS[(version - UNVERSIONED) as usize..] {
+        changes.extend(migration(config));
+    }
+
+    return Ok(Some(Migration { from: version, changes }));
+}
+
+/// Same as `upgrade`, warning the user when the file in `path` is migrated
+/// Files that the migrations do not change (as installer and shell configs
+/// without a version) are read as they are, without a warning
+pub fn upgrade_with_warning(path: &Path, config: &mut Map<String, Value>) -> anyhow::Result<()> {
+    let migration = match upgrade(config)? {
+        Some(migration) if !migration.changes.is_empty() => migration,
+        _ => return Ok(()),
+    };
+
+    eprintln!(
+        "⚠️  {} is written for config version {}, that is deprecated. Run `punto --migrate {}` to update it to version {}",
+        path.display(), migration.from, path.display(), CURRENT_VERSION,
+    );
+    for change in &migration.changes {
+        eprintln!("    - {}", change);
+    }
+
+    return Ok(());
+}
+
+/// Gives `config` the current version, as its first key
+pub fn with_current_version(config: Map<String, Value>) -> Map<String, Value> {
+    let mut versioned = Map::new();
+    versioned.insert(VERSION_KEY.to_string(), Value::from(CURRENT_VERSION));
+    versioned.extend(config);
+    return versioned;
+}
+
+/// Version 2 gave every config kind the same schema in every format:
+///     - Dir blocks of directories configs are listed under `directories`,
+///       instead of being tables at the top level of the file
+///     - `ignore_paths` of dir blocks is now `ignore_files`
+fn migrate_v1_to_v2(config: &mut Map<String, Value>) -> Vec<String> {
+    let mut changes = vec![];
+
+    // Only directories configs changed
+    let is_directories = ["repo_base", "system_base", "directories"].iter().any(|key| config.contains_key(*key));
+    if !is_directories {
+        return changes;
+    }
+
+    if !config.contains_key("directories") {
+        let mut blocks = vec![];
+        *config = std::mem::take(config)
+            .into_iter()
+            .filter_map(|(key, value)| {
+                let is_block = value.is_object() && !ConfigLoader::RESERVED_KEYS.contains(&key.as_str());
+                if is_block {
+                    let mut block = Map::new();
+                    block.insert(key, value);
+                    blocks.push(Value::Object(block));
+                    return None;
+                }
+                return Some((key, value));
+            })
+            .collect();
+
+        if !blocks.is_empty() {
+            changes.push(format!("moved {} dir blocks under `directories`", blocks.len()));
+            config.insert("directories".to_string(), Value::Array(blocks));
+        }
+    }
+
+    // Dir blocks and defaults can both give `ignore_paths`
+    if let Some(Value::Array(blocks)) = config.get_mut("directories") {
+        for block in blocks.iter_mut().filter_map(Value::as_object_mut) {
+            for (name, body) in block.iter_mut().filter_map(|(name, body)| Some((name, body.as_object_mut()?))) {
+                rename_ignore_paths(&format!("block `{}`", name), body, &mut changes);
+            }
+        }
+    }
+    if let Some(Value::Object(defaults)) = config.get_mut("defaults") {
+        rename_ignore_paths("`defaults`", defaults, &mut changes);
+    }
+
+    return changes;
+}
+
+/// Renames `ignore_paths` of the dir block `body` to `ignore_files`
+fn rename_ignore_paths(name: &str, body: &mut Map<String, Value>, changes: &mut Vec<String>) {
+    if let Some(ignore_paths) = ConfigLoader::take_key(body, "ignore_paths") {
+        body.insert("ignore_files".to_string(), ignore_paths);
+        changes.push(format!("renamed `ignore_paths` of {} to `ignore_files`", name));
+    }
+}
+
+/// Callback for --migrate cli arg
+/// Rewrites the config file in `file_path` for the current version, keeping
+/// a copy of the old one next to it
+/// `format` overrides the format given by the extension of the file
+pub fn handle_migrate(file_path: &Path, format: Option<ConfigFormat>) -> Result<(), PuntoError> {
+    let (migration, contents) = migrate_file(file_path, format)
+        .map_err(|err| PuntoError::config(file_path, err))?;
+
+    let migration = match migration {
+        Some(migration) => migration,
+        None => {
+            println!("✅ {} is already written for config version {}", file_path.display(), CURRENT_VERSION);
+            return Ok(());
+        },
+    };
+
+    let backup = backup_path(file_path);
+    atomic_copy(file_path, &backup)
+        .with_context(|| format!("Could not copy {} to {}", file_path.display(), backup.display()))
+        .and_then(|_| atomic_write(file_path, contents.as_bytes()))
+        .with_context(|| format!("Could not rewrite {}", file_path.display()))
+        .map_err(|err| PuntoError::total_failure("Migrating config file", err))?;
+
+    println!("🔁 Migrated {} from config version {} to {}", file_path.display(), migration.from, CURRENT_VERSION);
+    for change in &migration.changes {
+        println!("    - {}", change);
+    }
+    println!("The old file is kept in {}. Comments are not kept in the new one", backup.display());
+
+    return Ok(());
+}
+
+/// Migrates the config file in `path`, returning the migration along with
+/// the new contents of the file. The migration is None if there is nothing
+/// to migrate
+fn migrate_file(path: &Path, format: Option<ConfigFormat>) -> anyhow::Result<(Option<Migration>, String)> {
+    let format = ConfigLoader::format_of(path, format)?;
+    let mut config = match ConfigLoader::read_file(path, format)? {
+        Value::Object(config) => config,
+        _ => anyhow::bail!("Config files must be maps"),
+    };
+
+    let migration = upgrade(&mut config)?;
+    let contents = format.serialize(&Value::Object(with_current_version(config)))?;
+    return Ok((migration, contents));
+}
+
+/// Where the old version of a migrated file is kept: `<path>.bak`, or the
+/// first free `<path>.bak.<n>` if it already exists
+fn backup_path(path: &Path) -> PathBuf {
+    let with_suffix = |suffix: &str| {
+        let mut backup = path.as_os_str().to_owned();
+        backup.push(suffix);
+        return PathBuf::from(backup);
+    };
+
+    // Backups of previous migrations are never overwritten
+    let mut backup = with_suffix(".bak");
+    let mut copies = 0;
+    while fs::symlink_metadata(&backup).is_ok() {
+        copies += 1;
+        backup = with_suffix(&format!(".bak.{}", copies));
+    }
+
+    return backup;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use serde_json::json;
+
+    use super::{backup_path, upgrade, with_current_version, CURRENT_VERSION};
+
+    #[test]
+    fn test_old_directories_configs_are_migrated() {
+        // Layout of toml directories configs before version 2
+        let mut config = json!({
+            "repo_base": "/repo",
+            "defaults": {"ignore_paths": ["*.swp"]},
+            "nvim": {"repo_path": "nvim", "system_path": ".config/nvim", "ignore_paths": ["plugin"]},
+            "bashrc": {"repo_path": "bashrc", "system_path": ".bashrc"},
+        });
+        let config = config.as_object_mut().unwrap();
+
+        let migration = upgrade(config).unwrap().unwrap();
+        assert_eq!(migration.from, 1);
+        assert_eq!(migration.changes.len(), 3);
+        assert_eq!(serde_json::to_string(&config).unwrap(), serde_json::to_string(&json!({
+            "repo_base": "/repo",
+            "defaults": {"ignore_files": ["*.swp"]},
+            "directories": [
+                {"nvim": {"repo_path": "nvim", "system_path": ".config/nvim", "ignore_files": ["plugin"]}},
+                {"bashrc": {"repo_path": "bashrc", "system_path": ".bashrc"}},
+            ],
+        })).unwrap());
+
+        // Migrated files are not migrated again
+        let mut migrated = with_current_version(config.clone());
+        assert!(upgrade(&mut migrated).unwrap().is_none());
+        assert_eq!(&migrated, config);
+
+        // Files of newer versions are not guessed
+        let mut newer = json!({"version": CURRENT_VERSION + 1});
+        assert!(upgrade(newer.as_object_mut().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_backups_are_not_overwritten() {
+        let base_path = Path::new("test_backups_are_not_overwritten");
+        let _ = fs::remove_dir_all(base_path);
+        fs::create_dir_all(base_path).unwrap();
+        let config = base_path.join("config.yaml");
+
+        assert_eq!(backup_path(&config), base_path.join("config.yaml.bak"));
+
+        // A previous migration already left its backup
+        fs::write(base_path.join("config.yaml.bak"), "version 1").unwrap();
+        assert_eq!(backup_path(&config), base_path.join("config.yaml.bak.1"));
+        fs::write(base_path.join("config.yaml.bak.1"), "version 2").unwrap();
+        assert_eq!(backup_path(&config), base_path.join("config.yaml.bak.2"));
+
+        fs::remove_dir_all(base_path).unwrap();
+    }
+}
@@ -0,0 +1,546 @@
+//! Schemas of the config files, shared by every config format
+//!
+//! Each config kind (directories, installer and shell) has one schema, so its
+//! fields have the same names, defaults and types in yaml, toml and json.
+//! Unknown fields are rejected, so a typo is reported instead of ignored
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::marker::PhantomData;
+use std::path::PathBuf;
+
+use serde::de::{self, DeserializeOwned, Deserializer, MapAccess, Visitor};
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::ConfigLoader;
+use crate::DirSync::dir_block::DirFileType;
+
+/// A directories config file, used by `--download`, `--upload` and `--check`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DirectoriesFile {
+    /// Where the dotfiles repo is located
+    pub repo_base: PathBuf,
+
+    /// Where the dotfiles are placed in the system. Defaults to the home dir
+    #[serde(default)]
+    pub system_base: Option<PathBuf>,
+
+    /// Dir blocks, in order. Each item is a map with the name of the block as
+    /// its only key
+    #[serde(default)]
+    pub directories: Vec<Named<DirBlockSchema>>,
+}
+
+/// A dir block of a `DirectoriesFile`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DirBlockSchema {
+    pub repo_path: PathBuf,
+    pub system_path: PathBuf,
+
+    #[serde(default)]
+    pub sync_type: DirFileType,
+
+    /// Paths to skip, relative to `repo_path`
+    #[serde(default)]
+    pub ignore_files: Vec<String>,
+
+    /// Lets the paths of the block escape their base path
+    #[serde(default)]
+    pub allow_outside_base: bool,
+
+    /// Overrides of the global base paths, absolute or relative to them
+    #[serde(default)]
+    pub repo_base: Option<PathBuf>,
+    #[serde(default)]
+    pub system_base: Option<PathBuf>,
+
+    #[serde(default)]
+    pub mode: Option<ModeSchema>,
+}
+
+/// Mode of the files of a block, as an octal string ("0600") or number (0o600)
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum ModeSchema {
+    Number(i64),
+    Text(String),
+}
+
+/// An installer config file, used by `--install`
+#[derive(Debug)]
+pub struct InstallerFile {
+    /// Package managers declared in the file, given under `backends`
+    pub backends: Vec<(String, BackendSchema)>,
+
+    pub sections: Vec<(String, InstallerSectionSchema)>,
+}
+
+/// A section of an `InstallerFile`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InstallerSectionSchema {
+    /// Package manager of the section, built-in or declared in `backends`
+    #[serde(default)]
+    pub backend: Option<String>,
+
+    /// Command that installs a package, given as its last argument, for
+    /// sections without `backend`
+    #[serde(default)]
+    pub install_command: Option<String>,
+
+    /// Defaults to what the backend needs. Some package managers refuse to
+    /// run with sudo, so sections with `install_command` must give it
+    #[serde(default)]
+    pub sudo: Option<bool>,
+
+    #[serde(default)]
+    pub packages: Vec<PackageSchema>,
+
+    /// Packages installed by hand with the backend of the section, that
+    /// `--prune` must not remove
+    #[serde(default)]
+    pub ignore_unmanaged: Vec<String>,
+
+    /// Conditions for the section to be installed. Without them, it always is
+    #[serde(default)]
+    pub when: Option<WhenSchema>,
+
+    /// Sections that are installed before this one
+    #[serde(default)]
+    pub after: Vec<String>,
+}
+
+/// A package of an installer section: its name, or its names per backend or
+/// distro, as `{default: fd, debian: fd-find}`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum PackageSchema {
+    Name(String),
+    Aliases(BTreeMap<String, String>),
+}
+
+impl PackageSchema {
+    /// Every name the package can have
+    pub fn names(&self) -> Vec<&String> {
+        return match self {
+            PackageSchema::Name(name) => vec![name],
+            PackageSchema::Aliases(aliases) => aliases.values().collect(),
+        };
+    }
+}
+
+/// Conditions of an installer section. All the given ones must hold
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WhenSchema {
+    /// Distro, as the `ID` or `ID_LIKE` of /etc/os-release
+    #[serde(default)]
+    pub distro: Option<OneOrMany>,
+
+    #[serde(default)]
+    pub hostname: Option<OneOrMany>,
+
+    /// Profile given with --profile
+    #[serde(default)]
+    pub profile: Option<OneOrMany>,
+
+    /// Requires the program that installs the packages to be on PATH
+    #[serde(default)]
+    pub program_on_path: bool,
+}
+
+/// A value, or a list of values where any of them is valid
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl OneOrMany {
+    pub fn values(&self) -> &[String] {
+        return match self {
+            OneOrMany::One(value) => std::slice::from_ref(value),
+            OneOrMany::Many(values) => values,
+        };
+    }
+}
+
+/// A package manager declared in an `InstallerFile`
+/// Commands are templates where `{package}` is replaced by the package name
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BackendSchema {
+    pub install: String,
+
+    /// Command that succeeds only if the package is installed
+    #[serde(default)]
+    pub is_installed: Option<String>,
+
+    /// Command that prints the installed version of the package
+    #[serde(default)]
+    pub version: Option<String>,
+
+    #[serde(default)]
+    pub remove: Option<String>,
+
+    /// Command that prints the installed packages, one per line
+    #[serde(default)]
+    pub list_installed: Option<String>,
+
+    #[serde(default)]
+    pub sudo: bool,
+}
+
+/// A shell config file, used by `--shell`
+pub type CommandsFile = Sections<CommandBlockSchema>;
+
+/// A block of a `CommandsFile`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CommandBlockSchema {
+    #[serde(default = "default_description")]
+    pub description: String,
+
+    #[serde(default)]
+    pub sudo: bool,
+
+    #[serde(default)]
+    pub quiet: bool,
+
+    pub commands: Vec<String>,
+}
+
+fn default_description() -> String {
+    return "No description provided".to_string();
+}
+
+/// Parses a loaded config into the schema `T`
+pub fn from_loaded<T: DeserializeOwned>(loaded: Value) -> Result<T, SchemaError> {
+    return serde_json::from_value(loaded).map_err(|err| SchemaError::from_message(&err.to_string()));
+}
+
+/// Error of a config that does not follow its schema
+#[derive(Debug)]
+pub struct SchemaError {
+    /// Block where the error is, None if it is at the top level of the file
+    pub block: Option<String>,
+    pub kind: SchemaErrorKind,
+    message: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SchemaErrorKind {
+    UnknownField {
+        field: String,
+        expected: Vec<String>,
+    },
+    MissingField {
+        field: String,
+    },
+    Other(String),
+}
+
+/// Start of the errors found inside a block, followed by its name
+const BLOCK_ERROR_PREFIX: &str = "in block `";
+
+impl SchemaError {
+    /// Builds the error from the message of serde, that is the only thing
+    /// that serde errors tell
+    fn from_message(message: &str) -> Self {
+        let (block, field_message) = match message.strip_prefix(BLOCK_ERROR_PREFIX).and_then(|rest| rest.split_once("`: ")) {
+            Some((block, field_message)) => (Some(block.to_string()), field_message),
+            None => (None, message),
+        };
+
+        // Names are given between backticks, as in "unknown field `a`, expected `b` or `c`"
+        let quoted = |text: &str| -> Vec<String> {
+            return text.split('`').skip(1).step_by(2).map(|name| name.to_string()).collect();
+        };
+
+        let kind = if let Some(rest) = field_message.strip_prefix("unknown field ") {
+            let (field, expected) = rest.split_once(", expected ").unwrap_or((rest, ""));
+            SchemaErrorKind::UnknownField {
+                field: quoted(field).into_iter().next().unwrap_or_default(),
+                expected: quoted(expected),
+            }
+        } else if let Some(field) = field_message.strip_prefix("missing field ") {
+            SchemaErrorKind::MissingField { field: quoted(field).into_iter().next().unwrap_or_default() }
+        } else {
+            SchemaErrorKind::Other(field_message.to_string())
+        };
+
+        return Self { block, kind, message: message.to_string() };
+    }
+}
+
+impl std::error::Error for SchemaError {}
+
+impl fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "{}", self.message);
+    }
+}
+
+/// Kind of config (directories, installer or shell) that `loaded` is
+/// Fails if it does not follow any of the schemas, telling why for each one
+pub fn config_kind(loaded: &Value) -> anyhow::Result<&'static str> {
+    let errors = match (
+        from_loaded::<DirectoriesFile>(loaded.clone()),
+        from_loaded::<InstallerFile>(loaded.clone()),
+        from_loaded::<CommandsFile>(loaded.clone()),
+    ) {
+        (Ok(_), _, _) => return Ok("directories"),
+        (_, Ok(_), _) => return Ok("installer"),
+        (_, _, Ok(_)) => return Ok("shell"),
+        (Err(directories), Err(installer), Err(shell)) => [directories, installer, shell],
+    };
+
+    anyhow::bail!(
+        "It is not a directories, installer or shell config\nAs directories config: {}\nAs installer config: {}\nAs shell config: {}",
+        errors[0], errors[1], errors[2],
+    );
+}
+
+/// Blocks of a config file given as a map from their names to their bodies,
+/// keeping the order of the file
+#[derive(Debug)]
+pub struct Sections<T>(pub Vec<(String, T)>);
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Sections<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        return deserializer.deserialize_map(SectionsVisitor(PhantomData));
+    }
+}
+
+struct SectionsVisitor<T>(PhantomData<T>);
+
+impl<'de, T: Deserialize<'de>> Visitor<'de> for SectionsVisitor<T> {
+    type Value = Sections<T>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        return formatter.write_str("a map of named blocks");
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut sections = vec![];
+        while let Some(name) = map.next_key::<String>()? {
+            let body = next_block(&mut map, &name)?;
+            sections.push((name, body));
+        }
+
+        return Ok(Sections(sections));
+    }
+}
+
+impl<'de> Deserialize<'de> for InstallerFile {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        return deserializer.deserialize_map(InstallerFileVisitor);
+    }
+}
+
+struct InstallerFileVisitor;
+
+impl<'de> Visitor<'de> for InstallerFileVisitor {
+    type Value = InstallerFile;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        return formatter.write_str("a map of installer sections");
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut file = InstallerFile { backends: vec![], sections: vec![] };
+        while let Some(name) = map.next_key::<String>()? {
+            if name == ConfigLoader::BACKENDS_KEY {
+                file.backends = map.next_value::<Sections<BackendSchema>>()?.0;
+                continue;
+            }
+
+            let section = next_block(&mut map, &name)?;
+            file.sections.push((name, section));
+        }
+
+        return Ok(file);
+    }
+}
+
+/// A block given as a map with its name as the only key
+#[derive(Debug)]
+pub struct Named<T> {
+    pub name: String,
+    pub body: T,
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Named<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        return deserializer.deserialize_map(NamedVisitor(PhantomData));
+    }
+}
+
+struct NamedVisitor<T>(PhantomData<T>);
+
+impl<'de, T: Deserialize<'de>> Visitor<'de> for NamedVisitor<T> {
+    type Value = Named<T>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        return formatter.write_str("a map with the name of the block as its only key");
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let name = map.next_key::<String>()?
+            .ok_or_else(|| de::Error::custom("found a block without name"))?;
+        let body = next_block(&mut map, &name)?;
+
+        if let Some(other) = map.next_key::<String>()? {
+            return Err(de::Error::custom(format!(
+                "block `{}` is not indented under `{}`, or a `-` is missing before it", other, name
+            )));
+        }
+
+        return Ok(Named { name, body });
+    }
+}
+
+/// Parses the body of the block `name`, telling the block in the error
+fn next_block<'de, A: MapAccess<'de>, T: Deserialize<'de>>(map: &mut A, name: &str) -> Result<T, A::Error> {
+    return map.next_value()
+        .map_err(|err| de::Error::custom(format!("{}{}`: {}", BLOCK_ERROR_PREFIX, name, err)));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use serde::Deserialize;
+
+    use super::{from_loaded, CommandsFile, DirectoriesFile, SchemaErrorKind};
+    use crate::ConfigLoader;
+    use crate::DirSync::dir_block::DirFileType;
+
+    #[test]
+    fn test_directories_schema_is_the_same_in_every_format() {
+        let base_path = Path::new("test_directories_schema_is_the_same_in_every_format");
+        let _ = fs::remove_dir_all(base_path);
+        fs::create_dir_all(base_path).unwrap();
+
+        fs::write(base_path.join("dirs.yaml"), "
+repo_base: /repo
+directories:
+    - nvim:
+        sync_type: dir
+        repo_path: ./nvim
+        system_path: .config/nvim
+        ignore_files: [plugin]
+    - bashrc:
+        repo_path: ./bashrc
+        system_path: .bashrc
+").unwrap();
+        fs::write(base_path.join("dirs.toml"), "
+repo_base = '/repo'
+
+[[directories]]
+[directories.nvim]
+sync_type = 'dir'
+repo_path = './nvim'
+system_path = '.config/nvim'
+ignore_files = ['plugin']
+
+[[directories]]
+[directories.bashrc]
+repo_path = './bashrc'
+system_path = '.bashrc'
+").unwrap();
+
+        for file in ["dirs.yaml", "dirs.toml"] {
+            let loaded = ConfigLoader::load(&base_path.join(file), None).unwrap();
+            let parsed: DirectoriesFile = from_loaded(loaded).unwrap();
+
+            let names: Vec<&str> = parsed.directories.iter().map(|block| block.name.as_str()).collect();
+            assert_eq!(names, vec!["nvim", "bashrc"], "Blocks of {} were not kept in order", file);
+            assert_eq!(parsed.directories[0].body.sync_type, DirFileType::Dir);
+            assert_eq!(parsed.directories[0].body.ignore_files, vec!["plugin".to_string()]);
+            assert_eq!(parsed.directories[1].body.sync_type, DirFileType::File);
+            assert_eq!(parsed.system_base, None);
+        }
+
+        fs::remove_dir_all(base_path).unwrap();
+    }
+
+    #[test]
+    fn test_unknown_fields_are_rejected() {
+        let loaded = serde_json::json!({
+            "repo_base": "/repo",
+            "directories": [{"nvim": {"repo_path": "nvim", "system_path": "nvim", "ignore_paths": []}}],
+        });
+        let err = from_loaded::<DirectoriesFile>(loaded).unwrap_err().to_string();
+        assert!(err.contains("in block `nvim`") && err.contains("unknown field `ignore_paths`"), "Got: {}", err);
+
+        let loaded = serde_json::json!({"list": {"commands": ["ls"], "sudo": false, "quite": true}});
+        let err = from_loaded::<CommandsFile>(loaded).unwrap_err().to_string();
+        assert!(err.contains("in block `list`") && err.contains("unknown field `quite`"), "Got: {}", err);
+
+        let loaded = serde_json::json!({"repo_base": "/repo", "directories": [{"nvim": {"repo_path": "nvim"}}]});
+        let err = from_loaded::<DirectoriesFile>(loaded).unwrap_err();
+        assert_eq!(err.block.as_deref(), Some("nvim"));
+        assert_eq!(err.kind, SchemaErrorKind::MissingField { field: "system_path".to_string() });
+    }
+
+    /// `SchemaError` reads the field and its kind from the messages of serde,
+    /// so every message shape it understands is checked against real serde errors
+    #[test]
+    fn test_serde_messages_are_understood() {
+        #[derive(Deserialize, Debug)]
+        #[serde(deny_unknown_fields)]
+        #[allow(dead_code)]
+        struct One { first: u8 }
+
+        #[derive(Deserialize, Debug)]
+        #[serde(deny_unknown_fields)]
+        #[allow(dead_code)]
+        struct Two { first: u8, second: u8 }
+
+        #[derive(Deserialize, Debug)]
+        #[serde(deny_unknown_fields)]
+        struct Empty {}
+
+        let unknown = |field: &str, expected: &[&str]| SchemaErrorKind::UnknownField {
+            field: field.to_string(),
+            expected: expected.iter().map(|name| name.to_string()).collect(),
+        };
+
+        // "unknown field `x`, expected `a`"
+        let err = from_loaded::<One>(serde_json::json!({"first": 1, "x": 1})).unwrap_err();
+        assert_eq!(err.kind, unknown("x", &["first"]));
+
+        // "unknown field `x`, expected `a` or `b`"
+        let err = from_loaded::<Two>(serde_json::json!({"first": 1, "second": 2, "x": 1})).unwrap_err();
+        assert_eq!(err.kind, unknown("x", &["first", "second"]));
+
+        // "unknown field `x`, expected one of `a`, `b`, `c`", at the top level
+        let err = from_loaded::<DirectoriesFile>(serde_json::json!({"directories": [], "x": 1})).unwrap_err();
+        assert_eq!(err.block, None);
+        match err.kind {
+            SchemaErrorKind::UnknownField { field, expected } => {
+                assert_eq!(field, "x");
+                assert!(expected.contains(&"repo_base".to_string()) && expected.contains(&"directories".to_string()), "Got: {:?}", expected);
+            },
+            other => panic!("Expected an unknown field, got {:?}", other),
+        }
+
+        // "unknown field `x`, there are no fields"
+        let err = from_loaded::<Empty>(serde_json::json!({"x": 1})).unwrap_err();
+        assert_eq!(err.kind, unknown("x", &[]));
+
+        // "missing field `x`"
+        let err = from_loaded::<Two>(serde_json::json!({"first": 1})).unwrap_err();
+        assert_eq!(err.kind, SchemaErrorKind::MissingField { field: "second".to_string() });
+
+        // Any other message is kept as it is
+        let err = from_loaded::<One>(serde_json::json!({"first": "text"})).unwrap_err();
+        assert!(matches!(&err.kind, SchemaErrorKind::Other(message) if message.starts_with("invalid type")), "Got: {:?}", err.kind);
+    }
+}
@@ -1,9 +1,21 @@
 mod Commands;
 mod ArgParser;
-mod YamlProcessor;
+mod ConfigLoader;
+mod Converter;
+mod Diagnostics;
 mod Installer;
 mod DirSync;
+mod Errors;
+mod Migrations;
+mod Schemas;
 
 fn main() {
-    ArgParser::parse_args_and_launch_commands();
+    if let Err(err) = Errors::install_interrupt_handler() {
+        eprintln!("Could not handle Ctrl-C, it will kill punto right away: {}", err);
+    }
+
+    if let Err(err) = ArgParser::parse_args_and_launch_commands() {
+        err.print();
+        std::process::exit(err.exit_code());
+    }
 }